@@ -182,7 +182,13 @@ $(document).ready(function() {
 			timeout: null,
 			image: null
 		},
-		createImage: function(name, views, favorited, type) {
+		// The icon is stored in the configured thumbnail format. Older uploads only have PNG icons.
+		iconType: function(image) {
+			let icon = (image.thumbnails || []).find(thumbnail => thumbnail.size === 'icon');
+
+			return icon ? icon.file_type : 'png';
+		},
+		createImage: function(name, views, favorited, type, originalType, iconType) {
 			let image = document.createElement('div');
 			image.classList.add('img-info', 'large-2');
 
@@ -197,7 +203,7 @@ $(document).ready(function() {
 
 			let img = document.createElement('img');
 			img.classList.add('img');
-			img.setAttribute('data-src', `//${window.ICON_HOST}/i` + name + '.png');
+			img.setAttribute('data-src', `//${window.ICON_HOST}/i` + name + '.' + iconType);
 			img.setAttribute('align', 'center');
 			img.setAttribute('alt', 'Loading.');
 
@@ -247,6 +253,20 @@ $(document).ready(function() {
 			iBotRight.setAttribute('aria-hidden', 'true');
 			spanBotRight.appendChild(iBotRight);
 
+			// Only set if the original was kept alongside the compressed image.
+			if (originalType) {
+				let download = document.createElement('a');
+				download.setAttribute('href', `/image/${name}.${originalType}/original`);
+				download.setAttribute('download', `${name}.${originalType}`);
+
+				let iDownload = document.createElement('i');
+				iDownload.classList.add('fa', 'fa-download');
+				iDownload.setAttribute('aria-hidden', 'true');
+				download.appendChild(iDownload);
+
+				spanBotRight.appendChild(download);
+			}
+
 			clickable.appendChild(spanBotRight);
 
 
@@ -292,7 +312,7 @@ $(document).ready(function() {
 			var imageContainer = document.createElement('div');
 			imageContainer.className = 'row large-12';
 
-			images.forEach(image => imageContainer.appendChild(uploader.createImage(image.name, image.view_count, image.is_favorite, image.file_type)));
+			images.forEach(image => imageContainer.appendChild(uploader.createImage(image.name, image.view_count, image.is_favorite, image.file_type, image.original_file_type, uploader.iconType(image))));
 
 			container.appendChild(imageContainer);
 
@@ -336,6 +356,25 @@ $(document).ready(function() {
 			$.post('user/settings', data);
 			return false;
 		});
+
+		var compressionForm = $('#compressionForm');
+
+		compressionForm.find('input[name="compress"]').prop('checked', data.compress);
+		compressionForm.find('input[name="quality"]').val(data.quality);
+		compressionForm.find('input[name="lossless_only"]').prop('checked', data.lossless_only);
+		compressionForm.find('input[name="keep_original"]').prop('checked', data.keep_original);
+
+		compressionForm.submit(function() {
+			// Unchecked boxes aren't serialized so they're always sent.
+			var data = $(this).serializeArray().filter(function(v) { return v.name == 'quality' && v.value !== ''; });
+
+			$(this).find('input[type="checkbox"]').each(function() {
+				data.push({ name: this.name, value: this.checked });
+			});
+
+			$.post('user/settings', $.param(data));
+			return false;
+		});
 	});
 
 	// Show Settings
@@ -0,0 +1,413 @@
+use actix_identity::Identity;
+use actix_web::cookie::{time::Duration, Cookie, SameSite};
+use actix_web::{http::header, HttpRequest, HttpResponse};
+use actix_web::{web, Scope};
+use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+use mongodb::bson::{doc, DateTime};
+use url::Url;
+
+use crate::config::{Config, ConfigAuthGoogle};
+use crate::db::model::{create_auth_verify, find_and_remove_auth_verify, NewUser, UserGoogle};
+use crate::db::{get_auth_collection, get_collection, get_users_collection, CollectionType};
+use crate::error::{Error, InternalError};
+use crate::upload::image::UploadImageType;
+use crate::web::{ConfigDataService, remember_identity};
+use crate::words::gen_uuid;
+use crate::Result;
+
+/// Ties the state to the browser which started the login so others can't finish it.
+const STATE_COOKIE: &str = "google_state";
+/// How long the login can take.
+const STATE_COOKIE_MINUTES: i64 = 10;
+
+pub fn register(scope: Scope, config: &Config) -> Scope {
+	if config.auth.google.enabled {
+		scope
+			.route(
+				&config.auth.google.auth_path,
+				web::get().to(get_google_oauth),
+			)
+			.route(
+				&config.auth.google.callback_path,
+				web::get().to(get_google_oauth_callback),
+			)
+	} else {
+		scope
+	}
+}
+
+/// Redirects to Google. The state and PKCE verifier are stored until the callback.
+///
+/// The state is also put in a signed cookie which the callback compares it to.
+pub async fn get_google_oauth(
+	identity: Identity,
+	config: ConfigDataService,
+) -> Result<HttpResponse> {
+	if identity.identity().is_some() {
+		return Ok(HttpResponse::Found()
+			.append_header((header::LOCATION, "/"))
+			.finish());
+	}
+
+	let state = gen_uuid();
+	let code_verifier = format!("{}{}", gen_uuid(), gen_uuid());
+
+	let url = authorization_url(&config.auth.google, &redirect_uri(&config), &state, &code_verifier)?;
+
+	let expires_at = DateTime::now().timestamp_millis() + STATE_COOKIE_MINUTES * 60 * 1000;
+	let cookie = state_cookie(&config, sign_state(&state, expires_at, &config))
+		.max_age(Duration::minutes(STATE_COOKIE_MINUTES))
+		.finish();
+
+	create_auth_verify(state, code_verifier, &get_auth_collection()).await?;
+
+	Ok(HttpResponse::Found()
+		.append_header((header::LOCATION, url.as_str()))
+		.cookie(cookie)
+		.finish())
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct QueryCallback {
+	pub state: String,
+	/// Unset if the user declined.
+	pub code: Option<String>,
+	pub error: Option<String>,
+}
+
+pub async fn get_google_oauth_callback(
+	req: HttpRequest,
+	query: web::Query<QueryCallback>,
+	identity: Identity,
+	config: ConfigDataService,
+) -> Result<HttpResponse> {
+	if identity.identity().is_some() {
+		return Ok(HttpResponse::Found()
+			.append_header((header::LOCATION, "/"))
+			.finish());
+	}
+
+	let QueryCallback { state, code, error } = query.into_inner();
+
+	let mut removal = state_cookie(&config, String::new()).finish();
+	removal.make_removal();
+
+	let is_same_browser = req.cookie(STATE_COOKIE)
+		.is_some_and(|v| is_signed_state(v.value(), &state, DateTime::now().timestamp_millis(), &config));
+
+	if !is_same_browser {
+		return Ok(HttpResponse::Found()
+			.append_header((header::LOCATION, "/"))
+			.cookie(removal)
+			.finish());
+	}
+
+	let auth_verify = find_and_remove_auth_verify(&state, &get_auth_collection()).await?;
+
+	if let (Some(auth_verify), Some(code), None) = (auth_verify, code, error) {
+		let token = exchange_code(&config.auth.google, &redirect_uri(&config), &code, &auth_verify.oauth_token_secret).await?;
+
+		let profile = get_user_info(&config.auth.google, &token.access_token).await?;
+
+		// Create or Update User.
+
+		let google = UserGoogle {
+			id: profile.sub,
+			email: profile.email_verified.then_some(profile.email).flatten(),
+			display_name: profile.name,
+		};
+
+		let user_collection = get_users_collection();
+
+		let mut user = if let Some(mut user) = user_collection
+			.find_one(doc! { "google.id": &google.id }, None)
+			.await?
+		{
+			// The email and name can change. Users are found by their email from the command line.
+			user_collection
+				.update_one(
+					doc! { "_id": user.id },
+					doc! { "$set": { "google": mongodb::bson::to_bson(&google)? } },
+					None,
+				)
+				.await?;
+
+			user.google = Some(google);
+
+			user
+		} else {
+			let new_user = NewUser {
+				twitter: None,
+				passwordless: None,
+				google: Some(google),
+				upload_type: UploadImageType::PrefixAndSuffix,
+				is_banned: false,
+				join_date: mongodb::bson::DateTime::now(),
+				unique_id: gen_uuid(),
+				image_count: 0,
+				deletion_count: 0,
+				tier: None,
+			};
+
+			let inserted = get_collection(CollectionType::Users)
+				.insert_one(mongodb::bson::to_document(&new_user)?, None)
+				.await?;
+
+			new_user.into_user(inserted.inserted_id.as_object_id().ok_or_else(|| Error::from(InternalError::MissingObjectId))?)
+		};
+
+		if user.check_ban().await? {
+			return Ok(HttpResponse::Forbidden().cookie(removal).body(user.ban_message()));
+		}
+
+		remember_identity(&identity, user)?;
+	}
+
+	Ok(HttpResponse::Found()
+		.append_header((header::LOCATION, "/"))
+		.cookie(removal)
+		.finish())
+}
+
+/// Only sent to the callback.
+fn state_cookie(config: &Config, value: String) -> actix_web::cookie::CookieBuilder<'static> {
+	Cookie::build(STATE_COOKIE, value)
+		.path(config.auth.google.callback_path.clone())
+		.http_only(true)
+		.secure(config.website.url_protocol == "https")
+		// Sent when Google redirects back.
+		.same_site(SameSite::Lax)
+}
+
+/// "{state}.{expires_at}.{signature}" Signed with the session secret so it can't be forged.
+fn sign_state(state: &str, expires_at: i64, config: &Config) -> String {
+	format!("{}.{}.{}", state, expires_at, state_signature(state, expires_at, config))
+}
+
+fn state_signature(state: &str, expires_at: i64, config: &Config) -> String {
+	let mut hmac = Hmac::new(Sha256::new(), config.session_secret.as_bytes());
+	hmac.input(b"google-state:");
+	hmac.input(format!("{}.{}", state, expires_at).as_bytes());
+
+	base64::encode_config(hmac.result().code(), base64::URL_SAFE_NO_PAD)
+}
+
+/// Whether the cookie was signed for this state and hasn't expired.
+fn is_signed_state(value: &str, state: &str, now: i64, config: &Config) -> bool {
+	let mut parts = value.rsplitn(3, '.');
+
+	let (signature, expires_at, cookie_state) = match (parts.next(), parts.next().and_then(|v| v.parse::<i64>().ok()), parts.next()) {
+		(Some(signature), Some(expires_at), Some(cookie_state)) => (signature, expires_at, cookie_state),
+		_ => return false,
+	};
+
+	cookie_state == state
+		&& now < expires_at
+		&& fixed_time_eq(state_signature(state, expires_at, config).as_bytes(), signature.as_bytes())
+}
+
+fn redirect_uri(config: &Config) -> String {
+	format!(
+		"{}://{}{}",
+		&config.website.url_protocol,
+		&config.website.http_base_host,
+		&config.auth.google.callback_path
+	)
+}
+
+fn authorization_url(config: &ConfigAuthGoogle, redirect_uri: &str, state: &str, code_verifier: &str) -> Result<Url> {
+	Ok(Url::parse_with_params(
+		&config.authorization_url,
+		&[
+			("response_type", "code"),
+			("client_id", &config.client_id),
+			("redirect_uri", redirect_uri),
+			("scope", "openid email profile"),
+			("state", state),
+			("code_challenge", &code_challenge(code_verifier)),
+			("code_challenge_method", "S256"),
+		],
+	)?)
+}
+
+/// PKCE S256 challenge. https://datatracker.ietf.org/doc/html/rfc7636#section-4.2
+fn code_challenge(code_verifier: &str) -> String {
+	let mut hasher = Sha256::new();
+	hasher.input_str(code_verifier);
+
+	let mut hash = [0; 32];
+	hasher.result(&mut hash);
+
+	base64::encode_config(hash, base64::URL_SAFE_NO_PAD)
+}
+
+
+#[derive(Debug, Deserialize)]
+pub struct TokenResponse {
+	pub access_token: String,
+	pub token_type: String,
+	pub id_token: Option<String>,
+}
+
+async fn exchange_code(config: &ConfigAuthGoogle, redirect_uri: &str, code: &str, code_verifier: &str) -> Result<TokenResponse> {
+	Ok(reqwest::Client::new()
+		.post(&config.token_url)
+		.form(&[
+			("grant_type", "authorization_code"),
+			("code", code),
+			("client_id", &config.client_id),
+			("client_secret", &config.client_secret),
+			("redirect_uri", redirect_uri),
+			("code_verifier", code_verifier),
+		])
+		.send()
+		.await?
+		.error_for_status()?
+		.json()
+		.await?)
+}
+
+
+/// The standard OpenID Connect claims.
+#[derive(Debug, Deserialize)]
+pub struct UserInfo {
+	pub sub: String,
+
+	pub email: Option<String>,
+	#[serde(default)]
+	pub email_verified: bool,
+
+	pub name: Option<String>,
+}
+
+async fn get_user_info(config: &ConfigAuthGoogle, access_token: &str) -> Result<UserInfo> {
+	Ok(reqwest::Client::new()
+		.get(&config.userinfo_url)
+		.bearer_auth(access_token)
+		.send()
+		.await?
+		.error_for_status()?
+		.json()
+		.await?)
+}
+
+
+#[cfg(test)]
+mod tests {
+	#![allow(clippy::unwrap_used)]
+
+	use std::collections::HashMap;
+
+	use actix_web::{App, HttpRequest, HttpServer};
+
+	use super::*;
+
+	const CODE: &str = "mock-code";
+	const ACCESS_TOKEN: &str = "mock-access-token";
+
+	#[test]
+	fn pkce_challenge() {
+		// From RFC 7636 Appendix B.
+		assert_eq!(
+			"E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM",
+			code_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+		);
+	}
+
+	#[test]
+	fn state_cookie_values() {
+		let config = Config::default();
+
+		let value = sign_state("state", 1_000, &config);
+
+		assert!(is_signed_state(&value, "state", 999, &config));
+		// Started by another browser.
+		assert!(!is_signed_state(&value, "other-state", 999, &config));
+		assert!(!is_signed_state(&value, "state", 1_000, &config));
+		assert!(!is_signed_state(&value.replace("1000", "2000"), "state", 999, &config));
+		assert!(!is_signed_state("state", "state", 999, &config));
+
+		let mut other_config = Config::default();
+		other_config.session_secret = String::from("other-secret");
+		assert!(!is_signed_state(&value, "state", 999, &other_config));
+	}
+
+	/// Runs the whole exchange against a local provider which checks what it's sent.
+	#[actix_rt::test]
+	async fn mock_provider() {
+		async fn token(form: web::Form<HashMap<String, String>>) -> HttpResponse {
+			let valid = form.get("grant_type").map(String::as_str) == Some("authorization_code")
+				&& form.get("code").map(String::as_str) == Some(CODE)
+				&& form.get("client_id").map(String::as_str) == Some("client")
+				&& form.get("client_secret").map(String::as_str) == Some("secret")
+				&& form.get("redirect_uri").map(String::as_str) == Some("http://localhost/auth/google/callback")
+				// The challenge of "verifier" which the authorization url was created with.
+				&& form.get("code_verifier").map(|v| code_challenge(v)) == Some(code_challenge("verifier"));
+
+			if valid {
+				HttpResponse::Ok().json(json!({ "access_token": ACCESS_TOKEN, "token_type": "Bearer", "expires_in": 3599 }))
+			} else {
+				HttpResponse::BadRequest().json(json!({ "error": "invalid_grant" }))
+			}
+		}
+
+		async fn userinfo(req: HttpRequest) -> HttpResponse {
+			if req.headers().get(header::AUTHORIZATION).and_then(|v| v.to_str().ok()) == Some(&format!("Bearer {}", ACCESS_TOKEN)) {
+				HttpResponse::Ok().json(json!({ "sub": "1234", "email": "user@example.com", "email_verified": true, "name": "User" }))
+			} else {
+				HttpResponse::Unauthorized().finish()
+			}
+		}
+
+		let server = HttpServer::new(|| {
+			App::new()
+				.route("/token", web::post().to(token))
+				.route("/userinfo", web::get().to(userinfo))
+		})
+		.workers(1)
+		.bind(("127.0.0.1", 0))
+		.unwrap();
+
+		let addr = server.addrs()[0];
+		let handle = server.run();
+		let server_handle = handle.handle();
+		actix_rt::spawn(handle);
+
+		let config = ConfigAuthGoogle {
+			enabled: true,
+			client_id: String::from("client"),
+			client_secret: String::from("secret"),
+			authorization_url: format!("http://{}/authorize", addr),
+			token_url: format!("http://{}/token", addr),
+			userinfo_url: format!("http://{}/userinfo", addr),
+			..ConfigAuthGoogle::default()
+		};
+
+		let redirect = "http://localhost/auth/google/callback";
+
+		let url = authorization_url(&config, redirect, "state", "verifier").unwrap();
+		let query = url.query_pairs().into_owned().collect::<HashMap<_, _>>();
+
+		assert_eq!(Some("client"), query.get("client_id").map(String::as_str));
+		assert_eq!(Some(redirect), query.get("redirect_uri").map(String::as_str));
+		assert_eq!(Some("state"), query.get("state").map(String::as_str));
+		assert_eq!(Some(code_challenge("verifier")), query.get("code_challenge").cloned());
+
+		assert!(exchange_code(&config, redirect, "wrong-code", "verifier").await.is_err());
+		assert!(exchange_code(&config, redirect, CODE, "wrong-verifier").await.is_err());
+
+		let token = exchange_code(&config, redirect, CODE, "verifier").await.unwrap();
+		assert_eq!(ACCESS_TOKEN, token.access_token);
+
+		assert!(get_user_info(&config, "wrong-token").await.is_err());
+
+		let info = get_user_info(&config, &token.access_token).await.unwrap();
+		assert_eq!(("1234", Some("user@example.com"), true), (info.sub.as_str(), info.email.as_deref(), info.email_verified));
+
+		server_handle.stop(true).await;
+	}
+}
@@ -1,2 +1,3 @@
+pub mod google;
 pub mod twitter;
 pub mod passwordless;
\ No newline at end of file
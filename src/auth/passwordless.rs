@@ -144,7 +144,7 @@ pub async fn get_passwordless_oauth_callback(
 
 	if let Some(_auth_verify) = find_and_remove_auth_verify(&oauth_token, &auth_collection).await? {
 		// Create or Update User.
-		let user = if let Some(user) = user_collection
+		let mut user = if let Some(user) = user_collection
 			.find_one(
 				doc! { "passwordless.email": email.to_lowercase() },
 				FindOneOptions::builder()
@@ -165,12 +165,14 @@ pub async fn get_passwordless_oauth_callback(
 				passwordless: Some(UserPasswordless {
 					email
 				}),
+				google: None,
 				upload_type: UploadImageType::PrefixAndSuffix,
 				is_banned: false,
 				join_date: mongodb::bson::DateTime::now(),
 				unique_id: gen_uuid(),
 				image_count: 0,
 				deletion_count: 0,
+				tier: None,
 			};
 
 			let inserted = get_collection(CollectionType::Users)
@@ -182,6 +184,10 @@ pub async fn get_passwordless_oauth_callback(
 			)
 		};
 
+		if user.check_ban().await? {
+			return Ok(HttpResponse::Forbidden().body(user.ban_message()));
+		}
+
 		remember_identity(&identity, user)?;
 	}
 
@@ -117,7 +117,7 @@ pub async fn get_twitter_oauth_callback(
 
 			// Create or Update User.
 
-			let user = if let Some(user) = user_collection
+			let mut user = if let Some(user) = user_collection
 				.find_one(doc! { "twitter.id": profile.id }, None)
 				.await?
 			{
@@ -130,12 +130,14 @@ pub async fn get_twitter_oauth_callback(
 						display_name: profile.name,
 					}),
 					passwordless: None,
+					google: None,
 					upload_type: UploadImageType::PrefixAndSuffix,
 					is_banned: false,
 					join_date: mongodb::bson::DateTime::now(),
 					unique_id: gen_uuid(),
 					image_count: 0,
 					deletion_count: 0,
+					tier: None,
 				};
 
 				let inserted = get_collection(CollectionType::Users)
@@ -145,6 +147,10 @@ pub async fn get_twitter_oauth_callback(
 				new_user.into_user(inserted.inserted_id.as_object_id().ok_or_else(|| Error::from(InternalError::MissingObjectId))?)
 			};
 
+			if user.check_ban().await? {
+				return Ok(HttpResponse::Forbidden().body(user.ban_message()));
+			}
+
 			remember_identity(&identity, user)?;
 		} else {
 			println!("{:#?}", resp);
@@ -0,0 +1,246 @@
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+use futures::TryStreamExt;
+use mongodb::bson::{doc, oid::ObjectId};
+
+use crate::{
+	Result,
+	config::Config,
+	db::{self, get_gallery_collection, get_images_collection, get_jobs_collection, get_users_collection, model::{self, UserBan, UserRole}},
+	error::InternalError,
+	upload::{reprocess::ReprocessOptions, service::Service},
+};
+
+
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+	/// The config file. Read as TOML if it ends with ".toml", otherwise JSON.
+	/// Values can be overridden with environment variables. eg. "IMAGEHOST__SERVICES__B2__KEY"
+	#[arg(long, global = true, env = "IMAGEHOST_CONFIG", default_value = "./app/config.json")]
+	pub config: PathBuf,
+
+	/// Starts the website if no command is given.
+	#[command(subcommand)]
+	pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+	/// Start the website.
+	Serve,
+
+	/// Create any missing database indexes.
+	Migrate,
+
+	/// Rerun compression and thumbnailing on existing images.
+	Reprocess(ReprocessOptions),
+
+	/// Manage users. They're found by their id, unique id or email.
+	#[command(subcommand)]
+	User(UserCommand),
+
+	/// Manage images.
+	#[command(subcommand)]
+	Image(ImageCommand),
+
+	/// Manage galleries.
+	#[command(subcommand)]
+	Gallery(GalleryCommand),
+
+	/// Check the config.
+	#[command(subcommand)]
+	Config(ConfigCommand),
+}
+
+#[derive(Debug, Subcommand)]
+pub enum UserCommand {
+	/// Stop the user from uploading or logging in. Their images are hidden if enabled.
+	Ban {
+		user: String,
+		/// Shown to the user.
+		#[arg(long)]
+		reason: Option<String>,
+		/// Permanent if unset.
+		#[arg(long)]
+		days: Option<u32>,
+	},
+	/// Allow the user to upload again.
+	Unban { user: String },
+	/// Print the users' account.
+	Show { user: String },
+	/// Moderators and admins can use the admin area.
+	Role {
+		user: String,
+		#[arg(value_enum)]
+		role: UserRole,
+	},
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ImageCommand {
+	/// Permanently delete the image from storage and the database.
+	Purge {
+		/// The name without the format. eg. "Name123"
+		name: String,
+	},
+}
+
+#[derive(Debug, Subcommand)]
+pub enum GalleryCommand {
+	/// List galleries. Only the users' if one is given.
+	List {
+		#[arg(long)]
+		user: Option<String>,
+	},
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommand {
+	/// Load the config and check it for problems.
+	Check,
+}
+
+
+pub async fn migrate() -> Result<()> {
+	let created = db::create_indexes().await?;
+
+	if created.is_empty() {
+		println!("Indexes are up to date.");
+	} else {
+		println!("Created Indexes: {}", created.join(", "));
+	}
+
+	Ok(())
+}
+
+pub async fn user_command(command: UserCommand) -> Result<()> {
+	match command {
+		UserCommand::Ban { user, reason, days } => set_banned(&user, Some(UserBan::new(reason, days, None))).await,
+		UserCommand::Unban { user } => set_banned(&user, None).await,
+
+		UserCommand::Role { user, role } => {
+			let user = find_user(&user).await?;
+
+			model::set_user_role(user.id, role, &get_users_collection()).await?;
+
+			println!("{} is now a {}", user.unique_id, role.name());
+
+			Ok(())
+		}
+
+		UserCommand::Show { user } => {
+			let user = find_user(&user).await?;
+
+			let gallery_count = model::gallery_count(&user.id, &get_gallery_collection()).await?;
+
+			println!("{}", serde_json::to_string_pretty(&user)?);
+			println!("Galleries: {}", gallery_count);
+
+			Ok(())
+		}
+	}
+}
+
+pub async fn image_command(command: ImageCommand, config: &Config) -> Result<()> {
+	match command {
+		ImageCommand::Purge { name } => {
+			let collection = get_images_collection();
+
+			let image = model::find_image_by_name(&name, &collection).await?
+				.ok_or(InternalError::ImageDoesNotExist)?;
+
+			let service = Service::pick_service_from_config(&config.services).await?;
+
+			service.remove_files(&image).await?;
+
+			get_jobs_collection().delete_many(doc! { "image_name": &image.name }, None).await?;
+
+			// Deleted images were already taken off the count.
+			let uploader_id = image.uploader_id.filter(|_| image.deleted.is_none());
+
+			image.delete_document(&collection).await?;
+
+			if let Some(uploader_id) = uploader_id {
+				model::change_image_count(uploader_id, -1, &get_users_collection()).await?;
+			}
+
+			println!("Purged {}", name);
+
+			Ok(())
+		}
+	}
+}
+
+pub async fn gallery_command(command: GalleryCommand) -> Result<()> {
+	match command {
+		GalleryCommand::List { user } => {
+			let filter = match user {
+				Some(user) => doc! { "user_id": find_user(&user).await?.id },
+				None => doc! {},
+			};
+
+			let mut cursor = get_gallery_collection().find(filter, None).await?;
+
+			while let Some(gallery) = cursor.try_next().await? {
+				println!(
+					"{}\t{}\t{} images\t{}",
+					gallery.name,
+					gallery.title.as_deref().unwrap_or("-"),
+					gallery.images.len(),
+					gallery.user_id,
+				);
+			}
+
+			Ok(())
+		}
+	}
+}
+
+/// The config was already loaded so only its' values are checked. Every problem is printed.
+pub fn check_config(config: &Config, print_ok: bool) -> bool {
+	match config.validate() {
+		Ok(()) => {
+			if print_ok {
+				println!("Config OK");
+			}
+
+			true
+		}
+
+		Err(e) => {
+			eprintln!("{}", e);
+			false
+		}
+	}
+}
+
+async fn find_user(value: &str) -> Result<model::User> {
+	let collection = get_users_collection();
+
+	let user = if let Ok(id) = ObjectId::parse_str(value) {
+		model::find_user_by_id(id, &collection).await?
+	} else if value.contains('@') {
+		collection.find_one(doc! { "$or": [{ "passwordless.email": value }, { "google.email": value }] }, None).await?
+	} else {
+		model::find_user_by_id(value, &collection).await?
+	};
+
+	Ok(user.ok_or(InternalError::UserMissing)?)
+}
+
+async fn set_banned(value: &str, ban: Option<UserBan>) -> Result<()> {
+	let user = find_user(value).await?;
+
+	let (users, images) = (get_users_collection(), get_images_collection());
+
+	match ban.as_ref() {
+		Some(ban) => model::ban_user(user.id, ban, &users, &images).await?,
+		None => model::unban_user(user.id, &users, &images).await?,
+	}
+
+	println!("{} {}", if ban.is_some() { "Banned" } else { "Unbanned" }, user.unique_id);
+
+	Ok(())
+}
@@ -1,15 +1,22 @@
 use std::{
+	collections::{BTreeMap, HashMap},
 	ops::{Deref, DerefMut},
-	path::PathBuf,
+	path::{Path, PathBuf},
 };
 
+use actix_web::http::header::HeaderValue;
 use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use url::Url;
 
-use crate::Result;
+use crate::{Result, error::ConfigError};
 
 pub type Config = ConfigHelper<ConfigInner>;
 
-#[derive(Default)]
+/// Environment variables starting with this override config values. eg. "IMAGEHOST__SERVICES__B2__KEY"
+pub const ENV_PREFIX: &str = "IMAGEHOST__";
+
+#[derive(Default, Clone)]
 pub struct ConfigHelper<C: DeserializeOwned + Serialize + Default> {
 	path: PathBuf,
 	config: C,
@@ -30,31 +37,107 @@ impl<C: DeserializeOwned + Serialize + Default> ConfigHelper<C> {
 		Ok(this)
 	}
 
+	/// Layers the file then the environment variables over the defaults.
+	///
+	/// The file is read as TOML if it ends with ".toml", otherwise JSON.
 	pub async fn load(&mut self) -> Result<()> {
+		let path = self.path.display().to_string();
+
+		let overrides = std::env::vars()
+			.filter(|(key, _)| key.starts_with(ENV_PREFIX))
+			.collect::<Vec<_>>();
+
+		let mut value = serde_json::to_value(C::default())?;
+
 		// File doesn't exist?
 		if tokio::fs::metadata(&self.path).await.is_err() {
-			tokio::fs::write(&self.path, serde_json::to_string_pretty(&self.config)?).await?;
-			panic!("Config file was missing. I created it.\nEnsure the config is correct. Located in app/config.json");
+			// Everything could be set with environment variables instead.
+			if overrides.is_empty() {
+				self.save().await?;
+				return Err(ConfigError::Created(path).into());
+			}
+		} else {
+			// Error occured while reading the file?
+			let contents = match tokio::fs::read_to_string(&self.path).await {
+				Ok(v) => v,
+				Err(e) => return Err(ConfigError::Open(path, e).into()),
+			};
+
+			let file_value = if self.is_toml() {
+				toml::from_str(&contents).map_err(|e| ConfigError::ParseToml(path.clone(), e))?
+			} else {
+				serde_json::from_str(&contents).map_err(|e| ConfigError::Parse(path.clone(), e))?
+			};
+
+			merge_values(&mut value, file_value);
 		}
 
-		// Error occured while reading the file?
-		let file = match tokio::fs::File::open(&self.path).await {
-			Ok(v) => v,
-			Err(e) => {
-				panic!("{}\n\nAn Error Occured while trying to open the config file!\nPlease ensure you have permissions to access it.", e);
-			}
-		};
+		apply_env_overrides(&mut value, overrides);
 
-		self.config = serde_json::from_reader(file.into_std().await)?;
+		self.config = serde_json::from_value(value).map_err(|e| ConfigError::Parse(path, e))?;
 
 		Ok(())
 	}
 
+	/// Note: Values from environment variables are written to the file too.
 	pub async fn save(&self) -> Result<()> {
-		tokio::fs::write(&self.path, serde_json::to_string_pretty(&self.config)?).await?;
+		let contents = if self.is_toml() {
+			toml::to_string_pretty(&self.config).map_err(|e| ConfigError::SerializeToml(e.to_string()))?
+		} else {
+			serde_json::to_string_pretty(&self.config)?
+		};
+
+		tokio::fs::write(&self.path, contents).await?;
 
 		Ok(())
 	}
+
+	pub fn path(&self) -> &Path {
+		&self.path
+	}
+
+	fn is_toml(&self) -> bool {
+		self.path.extension().is_some_and(|v| v == "toml")
+	}
+}
+
+/// Objects are merged key by key. Anything else is replaced.
+fn merge_values(base: &mut Value, value: Value) {
+	match (base, value) {
+		(Value::Object(base), Value::Object(value)) => {
+			for (key, value) in value {
+				match base.get_mut(&key) {
+					Some(existing) => merge_values(existing, value),
+					None => {
+						base.insert(key, value);
+					}
+				}
+			}
+		}
+
+		(base, value) => *base = value,
+	}
+}
+
+/// "IMAGEHOST__SERVICES__B2__KEY" sets `services.b2.key`. Keys are lowercased.
+///
+/// Values are kept as text if they replace text. Otherwise they're read as JSON. eg. "true", "8080" or "[1, 2]"
+fn apply_env_overrides<I: IntoIterator<Item = (String, String)>>(base: &mut Value, overrides: I) {
+	for (key, raw) in overrides {
+		let keys = match key.strip_prefix(ENV_PREFIX) {
+			Some(v) if !v.is_empty() => v.to_lowercase(),
+			_ => continue,
+		};
+
+		let value = match base.pointer(&format!("/{}", keys.replace("__", "/"))) {
+			Some(Value::String(_)) => Value::String(raw),
+			_ => serde_json::from_str(&raw).unwrap_or(Value::String(raw)),
+		};
+
+		let value = keys.rsplit("__").fold(value, |value, key| json!({ key: value }));
+
+		merge_values(base, value);
+	}
 }
 
 impl<C: DeserializeOwned + Serialize + Default> AsRef<C> for ConfigHelper<C> {
@@ -95,10 +178,202 @@ pub struct ConfigInner {
 	pub website: ConfigWebsite,
 	pub auth: ConfigAuth,
 
+	#[serde(default)]
+	pub upload: ConfigUpload,
+
 	pub services: ConfigServices,
 	pub features: ConfigFeatures,
 }
 
+impl ConfigInner {
+	/// Collects every problem which would stop the website from working.
+	pub fn validate(&self) -> std::result::Result<(), ConfigError> {
+		let mut problems = Vec::new();
+
+		// Session
+		if self.session_secret == ConfigInner::default().session_secret {
+			problems.push(String::from(r#""session_secret" is still the default. Please change it."#));
+		} else if self.session_secret.len() < 32 {
+			problems.push(String::from(r#""session_secret" has to be at least 32 characters long."#));
+		}
+
+		// Database
+		if !(self.database.url.starts_with("mongodb://") || self.database.url.starts_with("mongodb+srv://")) {
+			problems.push(format!(r#""database.url" has to start with "mongodb://" or "mongodb+srv://". Found "{}""#, self.database.url));
+		}
+
+		// Website
+		let website = &self.website;
+
+		if website.url_protocol != "http" && website.url_protocol != "https" {
+			problems.push(format!(r#""website.url_protocol" has to be "http" or "https". Found "{}""#, website.url_protocol));
+		}
+
+		if website.port == 0 {
+			problems.push(String::from(r#""website.port" can't be 0."#));
+		}
+
+		check_host(&mut problems, "website.http_base_host", &website.http_base_host);
+		check_host(&mut problems, "website.http_image_host", &website.http_image_host);
+		check_host(&mut problems, "website.http_icon_host", &website.http_icon_host);
+
+		// Services
+		let services = &self.services;
+
+		let enabled = [
+			("filesystem", services.filesystem.enabled),
+			("b2", services.b2.enabled),
+			("s3", services.s3.enabled),
+			("logging", services.logging.enabled),
+		].iter()
+			.filter(|(_, enabled)| *enabled)
+			.map(|(name, _)| *name)
+			.collect::<Vec<_>>();
+
+		if enabled.is_empty() {
+			problems.push(String::from("No services are enabled. Please enable one in \"services\"."));
+		} else if !services.primary.is_empty() && !enabled.contains(&services.primary.as_str()) {
+			problems.push(format!(r#""services.primary" is "{}" which isn't enabled."#, services.primary));
+		}
+
+		if services.filesystem.enabled && services.filesystem.upload_directory.is_empty() {
+			problems.push(String::from(r#""services.filesystem.upload_directory" is empty."#));
+		}
+
+		if services.b2.enabled {
+			check_filled(&mut problems, "services.b2.id", &services.b2.id);
+			check_filled(&mut problems, "services.b2.key", &services.b2.key);
+			check_filled(&mut problems, "services.b2.bucket_id", &services.b2.bucket_id);
+
+			if check_url(&mut problems, "services.b2.public_url", &services.b2.public_url) && !services.b2.public_url.ends_with('/') {
+				problems.push(String::from(r#""services.b2.public_url" has to end with a "/"."#));
+			}
+		}
+
+		if services.s3.enabled {
+			check_url(&mut problems, "services.s3.endpoint", &services.s3.endpoint);
+			check_filled(&mut problems, "services.s3.bucket", &services.s3.bucket);
+			check_filled(&mut problems, "services.s3.access_key", &services.s3.access_key);
+			check_filled(&mut problems, "services.s3.secret_key", &services.s3.secret_key);
+
+			if !services.s3.public_url.is_empty() {
+				check_url(&mut problems, "services.s3.public_url", &services.s3.public_url);
+			}
+		}
+
+		// Auth
+		let auth = &self.auth;
+
+		for (name, enabled, auth_path, callback_path) in [
+			("google", auth.google.enabled, &auth.google.auth_path, &auth.google.callback_path),
+			("twitter", auth.twitter.enabled, &auth.twitter.auth_path, &auth.twitter.callback_path),
+			("passwordless", auth.passwordless.enabled, &auth.passwordless.auth_path, &auth.passwordless.callback_path),
+		] {
+			if enabled && (!auth_path.starts_with('/') || !callback_path.starts_with('/')) {
+				problems.push(format!(r#""auth.{}" paths have to start with a "/"."#, name));
+			}
+		}
+
+		if self.auth.google.enabled {
+			check_filled(&mut problems, "auth.google.client_id", &self.auth.google.client_id);
+			check_filled(&mut problems, "auth.google.client_secret", &self.auth.google.client_secret);
+			check_url(&mut problems, "auth.google.authorization_url", &self.auth.google.authorization_url);
+			check_url(&mut problems, "auth.google.token_url", &self.auth.google.token_url);
+			check_url(&mut problems, "auth.google.userinfo_url", &self.auth.google.userinfo_url);
+		}
+
+		if self.auth.twitter.enabled {
+			check_filled(&mut problems, "auth.twitter.consumer_key", &self.auth.twitter.consumer_key);
+			check_filled(&mut problems, "auth.twitter.consumer_secret", &self.auth.twitter.consumer_secret);
+		}
+
+		if self.auth.passwordless.enabled {
+			check_filled(&mut problems, "email.smtp_relay", &self.email.smtp_relay);
+			check_filled(&mut problems, "email.sending_email", &self.email.sending_email);
+		}
+
+		// Features
+		if !(0.0..=100.0).contains(&self.features.compression.quality) {
+			problems.push(String::from(r#""features.compression.quality" has to be between 0 and 100."#));
+		}
+
+		if self.features.resize.enabled && (self.features.resize.sizes.is_empty() || self.features.resize.sizes.contains(&0)) {
+			problems.push(String::from(r#""features.resize.sizes" can't be empty or contain 0."#));
+		}
+
+		for (name, size) in &self.features.thumbnails.sizes {
+			if size.width == 0 || size.height == 0 {
+				problems.push(format!(r#"The thumbnail size "{}" needs a width and height."#, name));
+			}
+		}
+
+		for id in &self.features.moderation.admins {
+			if mongodb::bson::oid::ObjectId::parse_str(id).is_err() {
+				problems.push(format!(r#""features.moderation.admins" has to contain user ids. Found "{}""#, id));
+			}
+		}
+
+		if problems.is_empty() {
+			Ok(())
+		} else {
+			Err(ConfigError::Invalid(problems))
+		}
+	}
+}
+
+impl ConfigInner {
+	/// Copies the settings which are only read on start from the running config.
+	///
+	/// Returns the names of the ones which were changed. They need a restart.
+	pub fn keep_startup_settings(&mut self, running: &ConfigInner) -> Vec<&'static str> {
+		let mut changed = Vec::new();
+
+		keep_setting(&mut changed, "session_secret", &mut self.session_secret, &running.session_secret);
+		keep_setting(&mut changed, "database", &mut self.database, &running.database);
+		keep_setting(&mut changed, "website", &mut self.website, &running.website);
+		keep_setting(&mut changed, "auth", &mut self.auth, &running.auth);
+		keep_setting(&mut changed, "services", &mut self.services, &running.services);
+		keep_setting(&mut changed, "upload.processing", &mut self.upload.processing, &running.upload.processing);
+
+		changed
+	}
+}
+
+fn keep_setting<T: Serialize + Clone>(changed: &mut Vec<&'static str>, name: &'static str, value: &mut T, running: &T) {
+	if serde_json::to_value(&*value).ok() != serde_json::to_value(running).ok() {
+		changed.push(name);
+		*value = running.clone();
+	}
+}
+
+/// Hosts are used as header values and without the protocol. eg. "i.example.com"
+fn check_host(problems: &mut Vec<String>, name: &str, host: &str) {
+	if host.is_empty() {
+		problems.push(format!(r#""{}" is empty."#, name));
+	} else if host.contains("://") || host.contains('/') {
+		problems.push(format!(r#""{}" has to be a host without a protocol or path. eg. "example.com". Found "{}""#, name, host));
+	} else if HeaderValue::from_str(host).is_err() || Url::parse(&format!("http://{}", host)).is_err() {
+		problems.push(format!(r#""{}" isn't a valid host. Found "{}""#, name, host));
+	}
+}
+
+/// Returns false if it isn't valid.
+fn check_url(problems: &mut Vec<String>, name: &str, url: &str) -> bool {
+	match Url::parse(url) {
+		Ok(v) if v.scheme() == "http" || v.scheme() == "https" => true,
+		_ => {
+			problems.push(format!(r#""{}" has to be a URL starting with "http://" or "https://". Found "{}""#, name, url));
+			false
+		}
+	}
+}
+
+fn check_filled(problems: &mut Vec<String>, name: &str, value: &str) {
+	if value.trim().is_empty() {
+		problems.push(format!(r#""{}" is empty."#, name));
+	}
+}
+
 impl Default for ConfigInner {
 	fn default() -> Self {
 		Self {
@@ -111,6 +386,7 @@ impl Default for ConfigInner {
 
 			website: ConfigWebsite::default(),
 			auth: ConfigAuth::default(),
+			upload: ConfigUpload::default(),
 			services: ConfigServices::default(),
 			features: ConfigFeatures::default(),
 		}
@@ -199,6 +475,88 @@ impl Default for ConfigWebsite {
 
 
 
+// Upload
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ConfigUpload {
+	/// Where uploads are written to while they're being received and processed.
+	pub spool_directory: String,
+
+	/// In bytes. Used for users without a tier.
+	pub max_file_size: u64,
+
+	/// Tier name -> Tier. A users' tier is stored on their account.
+	#[serde(default)]
+	pub tiers: HashMap<String, ConfigUploadTier>,
+
+	#[serde(default)]
+	pub processing: ConfigUploadProcessing,
+}
+
+impl ConfigUpload {
+	pub fn max_file_size_for(&self, tier: Option<&str>) -> u64 {
+		tier.and_then(|v| self.tiers.get(v))
+			.map_or(self.max_file_size, |v| v.max_file_size)
+	}
+
+	/// The largest file size any user can upload.
+	pub fn largest_max_file_size(&self) -> u64 {
+		self.tiers.values()
+			.map(|v| v.max_file_size)
+			.fold(self.max_file_size, u64::max)
+	}
+}
+
+impl Default for ConfigUpload {
+	fn default() -> Self {
+		Self {
+			spool_directory: String::from("./app/spool"),
+			max_file_size: 10 * 1048576,
+			tiers: HashMap::new(),
+			processing: ConfigUploadProcessing::default(),
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ConfigUploadTier {
+	/// In bytes.
+	pub max_file_size: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ConfigUploadProcessing {
+	/// How many uploads are processed at once. Defaults to the amount of CPU cores.
+	pub concurrency: usize,
+	/// How many uploads can wait for processing before new ones are rejected.
+	pub queue_depth: usize,
+	/// In seconds. Sent to rejected uploads in the Retry-After header.
+	pub retry_after: u64,
+
+	/// Store the original and respond right away. Compression and thumbnails are done afterwards.
+	///
+	/// The metadata policy is still applied before the original is stored.
+	/// Uploads keep their format so the returned link stays valid. Transcoding and converting GIFs are skipped.
+	pub background: bool,
+	/// How many background jobs can be unfinished before new uploads are rejected.
+	pub background_depth: usize,
+}
+
+impl Default for ConfigUploadProcessing {
+	fn default() -> Self {
+		Self {
+			concurrency: std::thread::available_parallelism().map_or(1, |v| v.get()),
+			queue_depth: 32,
+			retry_after: 5,
+			background: false,
+			background_depth: 256,
+		}
+	}
+}
+
+
+
 // Authentication
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
@@ -218,6 +576,11 @@ pub struct ConfigAuthGoogle {
 
 	pub auth_path: String,
 	pub callback_path: String,
+
+	/// The OpenID Connect endpoints. Only changed to use another provider. eg. A local one for testing.
+	pub authorization_url: String,
+	pub token_url: String,
+	pub userinfo_url: String,
 }
 
 impl Default for ConfigAuthGoogle {
@@ -228,6 +591,9 @@ impl Default for ConfigAuthGoogle {
 			callback_path: String::from("/auth/google/callback"),
 			client_id: String::new(),
 			client_secret: String::new(),
+			authorization_url: String::from("https://accounts.google.com/o/oauth2/v2/auth"),
+			token_url: String::from("https://oauth2.googleapis.com/token"),
+			userinfo_url: String::from("https://openidconnect.googleapis.com/v1/userinfo"),
 		}
 	}
 }
@@ -288,6 +654,14 @@ impl Default for ConfigAuthPasswordless {
 pub struct ConfigFeatures {
 	pub compression: ConfigFeatureCompression,
 	pub gallery: ConfigFeatureGallery,
+	#[serde(default)]
+	pub resize: ConfigFeatureResize,
+	#[serde(default)]
+	pub thumbnails: ConfigFeatureThumbnails,
+	#[serde(default)]
+	pub metadata: ConfigMetadataPolicy,
+	#[serde(default)]
+	pub moderation: ConfigFeatureModeration,
 }
 
 
@@ -295,17 +669,62 @@ pub struct ConfigFeatures {
 pub struct ConfigFeatureCompression {
 	pub enabled: bool,
 	pub quality: f32,
+
+	/// Only use lossless compression. JPEGs are left as is.
+	#[serde(default)]
+	pub lossless_only: bool,
+
+	/// Store the untouched original alongside the compressed file. Only the uploader can view it.
+	#[serde(default)]
+	pub keep_original: bool,
+
+	/// Transcode PNG and JPEG uploads into this format if the result is smaller.
+	/// PNGs stay lossless with WebP so ones with more than 8 bits per channel are left as is.
+	#[serde(default)]
+	pub transcode: ConfigTranscodeFormat,
+
+	/// Convert animated GIFs into this format if the result is smaller.
+	#[serde(default)]
+	pub animated: ConfigAnimatedFormat,
+	/// Used to convert animated GIFs into MP4. Defaults to "ffmpeg" in the PATH.
+	#[serde(default)]
+	pub ffmpeg_path: Option<String>,
 }
 
 impl Default for ConfigFeatureCompression {
     fn default() -> Self {
         Self {
 			enabled: false,
-			quality: 80.0
+			quality: 80.0,
+			lossless_only: false,
+			keep_original: false,
+			transcode: ConfigTranscodeFormat::None,
+			animated: ConfigAnimatedFormat::None,
+			ffmpeg_path: None,
 		}
     }
 }
 
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ConfigTranscodeFormat {
+	#[default]
+	None,
+	WebP,
+	/// Requires the "avif" feature.
+	Avif,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ConfigAnimatedFormat {
+	#[default]
+	None,
+	WebP,
+	/// Requires ffmpeg.
+	Mp4,
+}
+
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct ConfigFeatureGallery {
@@ -313,13 +732,158 @@ pub struct ConfigFeatureGallery {
 }
 
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ConfigFeatureModeration {
+	/// Ids of users who are always admins no matter their role. eg. "62a1f0c2e4b0a1b2c3d4e5f6"
+	/// Roles can also be set with `user role <user> <role>`.
+	pub admins: Vec<String>,
+	/// Stop serving the images of banned users. Each image request then looks up the image.
+	pub hide_banned_images: bool,
+	/// Reports from different users or IPs needed to hide an image or gallery until a moderator reviews it. 0 never hides them.
+	/// Each image request then looks up the image.
+	pub report_threshold: u32,
+	/// Reports one user or IP can submit per hour. 0 is unlimited.
+	pub reports_per_hour: u32,
+	/// The header a trusted reverse proxy puts the client's IP in. eg. "X-Forwarded-For" or "X-Real-IP"
+	/// Guests are told apart by their IP. The last value is used since proxies append to it.
+	pub forwarded_for_header: Option<String>,
+}
+
+impl Default for ConfigFeatureModeration {
+	fn default() -> Self {
+		Self {
+			admins: Vec::new(),
+			hide_banned_images: false,
+			report_threshold: 0,
+			reports_per_hour: 10,
+			forwarded_for_header: None,
+		}
+	}
+}
+
+
+/// Resized variants requested via query parameters. eg. `/{name}?w=640&h=480&fit=cover&fmt=webp`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ConfigFeatureResize {
+	pub enabled: bool,
+	/// The widths and heights which can be created. Requested ones are rounded up to the closest size.
+	///
+	/// Every variant is stored so keep this short.
+	pub sizes: Vec<u32>,
+}
+
+impl ConfigFeatureResize {
+	/// The closest configured size which isn't smaller than the requested one.
+	pub fn round_size(&self, size: u32) -> Option<u32> {
+		self.sizes.iter().copied().filter(|v| *v >= size).min()
+	}
+}
+
+impl Default for ConfigFeatureResize {
+	fn default() -> Self {
+		Self {
+			enabled: false,
+			sizes: vec![160, 320, 640, 1280, 2048],
+		}
+	}
+}
+
+
+/// Thumbnails created for every upload.
+///
+/// The "icon" size is stored as the icon. The others are served as `/{size}/{name}` from the icon host.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ConfigFeatureThumbnails {
+	/// Also used for the icon. The frontend reads its' format from the image's thumbnails.
+	pub format: ConfigThumbnailFormat,
+	pub quality: f32,
+	pub sizes: BTreeMap<String, ConfigThumbnailSize>,
+
+	/// Keep the animation of GIFs. They're animated WebPs if the format is WebP, otherwise GIFs.
+	#[serde(default)]
+	pub animated: bool,
+}
+
+impl Default for ConfigFeatureThumbnails {
+	fn default() -> Self {
+		let mut sizes = BTreeMap::new();
+
+		sizes.insert(
+			String::from("icon"),
+			ConfigThumbnailSize {
+				width: 128,
+				height: 128,
+				mode: ConfigThumbnailMode::Exact,
+			},
+		);
+
+		Self {
+			format: ConfigThumbnailFormat::Png,
+			quality: 80.0,
+			sizes,
+			animated: false,
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ConfigThumbnailFormat {
+	#[default]
+	Png,
+	Jpeg,
+	WebP,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ConfigThumbnailSize {
+	pub width: u32,
+	pub height: u32,
+	#[serde(default)]
+	pub mode: ConfigThumbnailMode,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ConfigThumbnailMode {
+	/// Cropped to exactly the width and height.
+	#[default]
+	Exact,
+	/// Fits inside of the width and height keeping the aspect ratio.
+	Fit,
+}
+
+
+/// What happens to the metadata (EXIF, ICC, XMP, text chunks, comments) of JPEG, PNG, WebP and GIF uploads.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ConfigMetadataPolicy {
+	/// Removes everything. The orientation is applied to the pixels instead.
+	/// Lossy images which aren't compressed only keep the orientation since rotating them would lose quality.
+	Strip,
+	/// Only keeps the orientation and color profile.
+	#[default]
+	Essential,
+	/// Keeps everything. Restored after compression.
+	Keep,
+}
+
+
 
 // Services
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct ConfigServices {
+	/// The name of the service uploads are written to first ("filesystem", "b2", "s3" or "logging").
+	/// The other enabled services are replicated to. Defaults to the first enabled one of filesystem, b2, s3 and logging.
+	#[serde(default)]
+	pub primary: String,
+
 	pub logging: ConfigServiceLogging,
 	pub b2: ConfigServiceB2,
+	#[serde(default)]
+	pub s3: ConfigServiceS3,
 	pub filesystem: ConfigServiceFileSystem,
 }
 
@@ -343,6 +907,45 @@ pub struct ConfigServiceB2 {
 	pub public_url: String,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ConfigServiceS3 {
+	pub enabled: bool,
+
+	/// Including the protocol. eg. "http://127.0.0.1:9000" or "https://s3.us-east-1.amazonaws.com"
+	pub endpoint: String,
+	pub region: String,
+	pub bucket: String,
+
+	pub access_key: String,
+	pub secret_key: String,
+
+	/// Use "{endpoint}/{bucket}/{file}" instead of "{bucket}.{endpoint}/{file}". MinIO requires this.
+	pub path_style: bool,
+
+	pub image_sub_directory: String,
+	pub icon_sub_directory: String,
+
+	/// Optional. If empty, files are served through signed requests to the bucket.
+	pub public_url: String,
+}
+
+impl Default for ConfigServiceS3 {
+	fn default() -> Self {
+		Self {
+			enabled: false,
+			endpoint: String::from("http://127.0.0.1:9000"),
+			region: String::from("us-east-1"),
+			bucket: String::new(),
+			access_key: String::new(),
+			secret_key: String::new(),
+			path_style: true,
+			image_sub_directory: String::new(),
+			icon_sub_directory: String::new(),
+			public_url: String::new(),
+		}
+	}
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct ConfigServiceFileSystem {
 	pub enabled: bool,
@@ -352,3 +955,86 @@ pub struct ConfigServiceFileSystem {
 	pub image_sub_directory: String,
 	pub icon_sub_directory: String,
 }
+
+
+#[cfg(test)]
+mod tests {
+	#![allow(clippy::unwrap_used)]
+
+	use super::*;
+
+	#[test]
+	fn validate_collects_problems() {
+		let mut config = ConfigInner::default();
+
+		let problems = match config.validate() {
+			Err(ConfigError::Invalid(v)) => v,
+			v => panic!("Expected problems. Found {:?}", v),
+		};
+
+		assert!(problems.iter().any(|v| v.contains("session_secret")));
+		assert!(problems.iter().any(|v| v.contains("No services are enabled")));
+
+		config.session_secret = "a".repeat(64);
+		config.website.http_image_host = "https://i.local.host/".into();
+		config.services.filesystem.enabled = true;
+		config.services.filesystem.upload_directory = "./app/upload".into();
+		config.services.b2.enabled = true;
+		config.services.b2.public_url = "https://example.com/file".into();
+		config.services.primary = "s3".into();
+
+		let problems = match config.validate() {
+			Err(ConfigError::Invalid(v)) => v,
+			v => panic!("Expected problems. Found {:?}", v),
+		};
+
+		assert!(problems.iter().any(|v| v.contains("http_image_host")));
+		assert!(problems.iter().any(|v| v.contains(r#""services.primary" is "s3""#)));
+		assert!(problems.iter().any(|v| v.contains("services.b2.key")));
+		assert!(problems.iter().any(|v| v.contains(r#"end with a "/""#)));
+
+		config.website.http_image_host = "i.local.host".into();
+		config.services.b2.enabled = false;
+		config.services.primary = String::new();
+
+		assert!(config.validate().is_ok());
+	}
+
+	#[test]
+	fn layered_overrides() {
+		let mut value = serde_json::to_value(ConfigInner::default()).unwrap();
+
+		let file = toml::to_string_pretty(&ConfigInner::default()).unwrap()
+			.replace(r#"database = "image_host""#, r#"database = "from_file""#);
+
+		merge_values(&mut value, toml::from_str(&file).unwrap());
+
+		apply_env_overrides(&mut value, vec![
+			(String::from("IMAGEHOST__SERVICES__B2__KEY"), String::from("1234")),
+			(String::from("IMAGEHOST__WEBSITE__PORT"), String::from("9000")),
+			(String::from("IMAGEHOST__SERVICES__B2__ENABLED"), String::from("true")),
+			(String::from("OTHER__DEBUG"), String::from("true")),
+		]);
+
+		let config: ConfigInner = serde_json::from_value(value).unwrap();
+
+		assert_eq!("from_file", config.database.database);
+		assert_eq!("1234", config.services.b2.key);
+		assert_eq!(9000, config.website.port);
+		assert!(config.services.b2.enabled);
+		assert!(!config.debug);
+	}
+
+	#[test]
+	fn reload_keeps_startup_settings() {
+		let running = ConfigInner::default();
+
+		let mut reloaded = ConfigInner::default();
+		reloaded.website.port = 9000;
+		reloaded.features.gallery.enabled = true;
+
+		assert_eq!(vec!["website"], reloaded.keep_startup_settings(&running));
+		assert_eq!(running.website.port, reloaded.website.port);
+		assert!(reloaded.features.gallery.enabled);
+	}
+}
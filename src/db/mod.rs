@@ -4,7 +4,7 @@ use mongodb::{Client, Collection, Database, IndexModel, bson::doc, options::{Col
 
 use crate::{config::ConfigDatabase, Result};
 
-use self::model::{AuthVerify, Gallery, Image, ImageViews, User};
+use self::model::{AuthVerify, Gallery, Image, ImageViews, ProcessingJob, Report, User};
 
 pub mod model;
 
@@ -13,6 +13,8 @@ pub type ImagesCollection = Collection<Image>;
 pub type UsersCollection = Collection<User>;
 pub type GalleryCollection = Collection<Gallery>;
 pub type AuthCollection = Collection<AuthVerify>;
+pub type JobsCollection = Collection<ProcessingJob>;
+pub type ReportsCollection = Collection<Report>;
 
 
 lazy_static! {
@@ -25,13 +27,13 @@ pub async fn create_mongo_connection(config: &ConfigDatabase) -> Result<Client>
 
 	*DATABASE.write()? = Some(client.database(&config.database));
 
-	create_indexes().await?;
-
 	Ok(client)
 }
 
-// Create Indexes if they don't exist.
-async fn create_indexes() -> Result<()> {
+/// Create Indexes if they don't exist. Returns the names of the created indexes.
+pub async fn create_indexes() -> Result<Vec<&'static str>> {
+	let mut created = Vec::new();
+
 	{ // Users
 		let collection = get_users_collection();
 
@@ -50,6 +52,8 @@ async fn create_indexes() -> Result<()> {
 					.build(),
 				None
 			).await?;
+
+			created.push("email-cs-index");
 		}
 	}
 
@@ -71,10 +75,72 @@ async fn create_indexes() -> Result<()> {
 					.build(),
 				None
 			).await?;
+
+			created.push("created_at-ttl-index");
+		}
+	}
+
+	{ // Jobs
+		let collection = get_jobs_collection();
+
+		let indexes = collection.list_index_names().await?;
+
+		if !indexes.iter().any(|v| v == "image_name-index") {
+			collection.create_index(
+				IndexModel::builder()
+					.keys(doc! { "image_name": 1 })
+					.options(
+						IndexOptions::builder()
+							.name("image_name-index".to_string())
+							.build()
+					)
+					.build(),
+				None
+			).await?;
+
+			created.push("image_name-index");
+		}
+	}
+
+	{ // Reports
+		let collection = get_reports_collection();
+
+		let indexes = collection.list_index_names().await?;
+
+		if !indexes.iter().any(|v| v == "target-index") {
+			collection.create_index(
+				IndexModel::builder()
+					.keys(doc! { "target": 1, "name": 1 })
+					.options(
+						IndexOptions::builder()
+							.name("target-index".to_string())
+							.build()
+					)
+					.build(),
+				None
+			).await?;
+
+			created.push("target-index");
+		}
+
+		if !indexes.iter().any(|v| v == "reporter-index") {
+			collection.create_index(
+				IndexModel::builder()
+					.keys(doc! { "reporter": 1, "created_at": 1 })
+					.options(
+						IndexOptions::builder()
+							.name("reporter-index".to_string())
+							.build()
+					)
+					.build(),
+				None
+			).await?;
+
+			created.push("reporter-index");
 		}
 	}
 
-	Ok(())
+	Ok(created)
 }
 
 
@@ -99,6 +165,14 @@ pub fn get_auth_collection() -> AuthCollection {
 	get_collection(CollectionType::Auths)
 }
 
+pub fn get_jobs_collection() -> JobsCollection {
+	get_collection(CollectionType::Jobs)
+}
+
+pub fn get_reports_collection() -> ReportsCollection {
+	get_collection(CollectionType::Reports)
+}
+
 pub fn get_collection<T>(value: CollectionType) -> Collection<T>
 where
 	T: serde::Serialize + serde::de::DeserializeOwned + Unpin + std::fmt::Debug,
@@ -119,6 +193,8 @@ pub enum CollectionType {
 	Users,
 	Gallery,
 	Auths,
+	Jobs,
+	Reports,
 }
 
 impl CollectionType {
@@ -129,6 +205,8 @@ impl CollectionType {
 			Self::Users => "users",
 			Self::Gallery => "gallery",
 			Self::Auths => "auths",
+			Self::Jobs => "jobs",
+			Self::Reports => "reports",
 		}
 	}
 }
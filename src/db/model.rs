@@ -2,14 +2,15 @@ use chrono::{Datelike, NaiveDate, NaiveDateTime, NaiveTime, Timelike};
 use futures::TryStreamExt;
 use mongodb::{
 	bson::{doc, oid::ObjectId, DateTime, Document},
+	options::FindOptions,
 	results::{DeleteResult, InsertOneResult, UpdateResult},
 	Cursor,
 };
 use rand::prelude::ThreadRng;
 
-use crate::{error::{Result, DateTimeError, InternalError, Error}, upload::image::UploadImageType, words, Filename};
+use crate::{error::{Result, DateTimeError, InternalError, Error}, upload::{ProcessedImage, image::UploadImageType}, words, Filename};
 
-use super::{get_users_collection, AuthCollection, GalleryCollection, ImagesCollection, UsersCollection};
+use super::{get_gallery_collection, get_images_collection, get_users_collection, AuthCollection, GalleryCollection, ImagesCollection, JobsCollection, ReportsCollection, UsersCollection};
 
 
 pub enum UserId {
@@ -45,12 +46,23 @@ pub struct User {
 
 	pub twitter: Option<UserTwitter>,
 	pub passwordless: Option<UserPasswordless>,
+	#[serde(default)]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub google: Option<UserGoogle>,
 
 	#[serde(serialize_with = "bson_unsigned_fix")]
 	pub upload_type: UploadImageType,
 
 	pub is_banned: bool,
 
+	#[serde(default)]
+	pub role: UserRole,
+
+	/// Only set while banned.
+	#[serde(default)]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub ban: Option<UserBan>,
+
 	pub join_date: DateTime,
 
 	pub unique_id: String,
@@ -58,11 +70,57 @@ pub struct User {
 	pub image_count: i32,
 	pub deletion_count: i32,
 
+	/// Used to look up the users' upload limits.
+	#[serde(default)]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub tier: Option<String>,
+
+	/// Uses the site defaults if unset.
+	#[serde(default)]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub compression: Option<UserCompression>,
+
 	#[serde(rename = "__v")]
 	#[serde(skip_serializing_if = "Option::is_none")]
 	pub version_key: Option<i32>,
 }
 
+impl User {
+	/// Expired bans are lifted. Their images are shown again.
+	pub async fn check_ban(&mut self) -> Result<bool> {
+		if !self.is_banned {
+			return Ok(false);
+		}
+
+		if self.ban.as_ref().is_some_and(|v| v.is_expired()) {
+			unban_user(self.id, &get_users_collection(), &get_images_collection()).await?;
+
+			self.is_banned = false;
+			self.ban = None;
+
+			return Ok(false);
+		}
+
+		Ok(true)
+	}
+
+	pub fn ban_message(&self) -> String {
+		let mut message = String::from("You're banned");
+
+		if let Some(ban) = self.ban.as_ref() {
+			if let Some(expires_at) = ban.expires_at {
+				message += &format!(" until {}", expires_at.to_rfc3339_string());
+			}
+
+			if let Some(reason) = ban.reason.as_deref() {
+				message += &format!(". Reason: {}", reason);
+			}
+		}
+
+		message + "."
+	}
+}
+
 impl From<User> for SlimUser {
     fn from(val: User) -> Self {
         SlimUser {
@@ -104,8 +162,13 @@ pub struct NewUser {
 	pub image_count: i32,
 	pub deletion_count: i32,
 
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub tier: Option<String>,
+
 	pub twitter: Option<UserTwitter>,
 	pub passwordless: Option<UserPasswordless>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub google: Option<UserGoogle>,
 }
 
 impl NewUser {
@@ -119,9 +182,14 @@ impl NewUser {
 			unique_id: self.unique_id,
 			image_count: self.image_count,
 			deletion_count: self.deletion_count,
+			tier: self.tier,
+			compression: None,
+			role: UserRole::User,
+			ban: None,
 
 			twitter: self.twitter,
 			passwordless: self.passwordless,
+			google: self.google,
 
 			version_key: None,
 		}
@@ -141,6 +209,81 @@ pub async fn find_user_by_id<I: Into<UserId>>(
 	}
 }
 
+/// Counts the images which aren't deleted. `amount` is negative when removing.
+pub async fn change_image_count(user_id: ObjectId, amount: i32, collection: &UsersCollection) -> Result<UpdateResult> {
+	Ok(collection
+		.update_one(
+			doc! { "_id": user_id },
+			doc! {
+				"$inc": {
+					"image_count": amount
+				}
+			},
+			None,
+		)
+		.await?)
+}
+
+pub async fn ban_user(id: ObjectId, ban: &UserBan, users: &UsersCollection, images: &ImagesCollection) -> Result<()> {
+	users
+		.update_one(
+			doc! { "_id": id },
+			doc! {
+				"$set": {
+					"is_banned": true,
+					"ban": mongodb::bson::to_bson(ban)?,
+				}
+			},
+			None,
+		)
+		.await?;
+
+	images
+		.update_many(
+			doc! {
+				"uploader_id": id,
+				"hidden": { "$exists": false },
+			},
+			doc! {
+				"$set": {
+					"hidden": mongodb::bson::to_bson(&HiddenReason::Banned)?,
+				}
+			},
+			None,
+		)
+		.await?;
+
+	Ok(())
+}
+
+pub async fn unban_user(id: ObjectId, users: &UsersCollection, images: &ImagesCollection) -> Result<()> {
+	users
+		.update_one(
+			doc! { "_id": id },
+			doc! {
+				"$set": { "is_banned": false },
+				"$unset": { "ban": "" },
+			},
+			None,
+		)
+		.await?;
+
+	images
+		.update_many(
+			doc! {
+				"uploader_id": id,
+				"hidden": mongodb::bson::to_bson(&HiddenReason::Banned)?,
+			},
+			doc! {
+				"$unset": { "hidden": "" }
+			},
+			None,
+		)
+		.await?;
+
+	Ok(())
+}
+
 fn bson_unsigned_fix<S>(
 	value: &UploadImageType,
 	serializer: S,
@@ -152,6 +295,77 @@ where
 }
 
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum UserRole {
+	#[default]
+	User,
+	/// Can use the admin area. eg. Deleting images and banning users.
+	Moderator,
+	/// Can also change roles.
+	Admin,
+}
+
+impl UserRole {
+	pub fn name(self) -> &'static str {
+		match self {
+			Self::User => "user",
+			Self::Moderator => "moderator",
+			Self::Admin => "admin",
+		}
+	}
+}
+
+pub async fn set_user_role(id: ObjectId, role: UserRole, collection: &UsersCollection) -> Result<UpdateResult> {
+	Ok(collection
+		.update_one(
+			doc! { "_id": id },
+			doc! { "$set": { "role": role.name() } },
+			None,
+		)
+		.await?)
+}
+
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserBan {
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub reason: Option<String>,
+
+	pub banned_at: DateTime,
+
+	/// Permanent if unset.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub expires_at: Option<DateTime>,
+
+	/// The role of who banned them. Unset if banned from the command line.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub banned_by: Option<UserRole>,
+}
+
+impl UserBan {
+	pub fn new(reason: Option<String>, days: Option<u32>, banned_by: Option<UserRole>) -> Self {
+		let banned_at = DateTime::now();
+
+		Self {
+			reason,
+			banned_at,
+			expires_at: days.map(|days| DateTime::from_millis(banned_at.timestamp_millis() + i64::from(days) * 24 * 60 * 60 * 1000)),
+			banned_by,
+		}
+	}
+
+	/// Bans from the command line can only be lifted by admins.
+	pub fn can_be_lifted_by(&self, role: UserRole) -> bool {
+		role >= self.banned_by.unwrap_or(UserRole::Admin)
+	}
+
+	pub fn is_expired(&self) -> bool {
+		self.expires_at.is_some_and(|v| v <= DateTime::now())
+	}
+}
+
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UserTwitter {
 	pub id: i64,
@@ -166,6 +380,41 @@ pub struct UserPasswordless {
 }
 
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserGoogle {
+	/// The OpenID Connect subject. Unlike the email it never changes.
+	pub id: String,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub email: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub display_name: Option<String>,
+}
+
+
+/// Only applies if compression is enabled for the site.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserCompression {
+	pub enabled: bool,
+	/// JPEG quality. Uses the site default if unset.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub quality: Option<f32>,
+	pub lossless_only: bool,
+	/// Store the untouched original alongside the compressed file.
+	pub keep_original: bool,
+}
+
+impl Default for UserCompression {
+	fn default() -> Self {
+		Self {
+			enabled: true,
+			quality: None,
+			lossless_only: false,
+			keep_original: false,
+		}
+	}
+}
+
+
 // IMAGE VIEWS
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -220,14 +469,54 @@ pub struct Image {
 
 	pub uploader: ImageUploader,
 
+	/// Empty for images uploaded before thumbnails were configurable. Those only have a PNG icon.
+	#[serde(default)]
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub thumbnails: Vec<ImageThumbnail>,
+
+	/// Only set for animated uploads.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub animation: Option<ImageAnimation>,
+
+	/// Set if compressing failed and the original was stored instead.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub compression_error: Option<String>,
+
+	/// The format of the original upload. Only set if it was kept alongside the compressed file.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub original_file_type: Option<String>,
+
+	/// Why it isn't served. Unlike `deleted` the files are kept.
+	#[serde(default)]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub hidden: Option<HiddenReason>,
+
 	pub upload_date: DateTime,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HiddenReason {
+	/// The uploader is banned.
+	Banned,
+	/// Enough people reported it. Shown again if a moderator dismisses the reports.
+	Reported,
+}
+
 impl Image {
 	pub fn get_file_name(&self) -> Result<Filename> {
 		Filename::new(self.name.clone(), Some(self.file_type.clone()))
 	}
 
+	pub fn original_file_name(&self) -> Option<String> {
+		self.original_file_type.as_ref().map(|v| format!("{}.{}", self.name, v))
+	}
+
+	/// The kept original if it can be processed again. It has to have the same format since links are kept.
+	pub fn reprocessable_original(&self) -> Option<String> {
+		self.original_file_name().filter(|_| self.original_file_type.as_deref() == Some(self.file_type.as_str()))
+	}
+
 	pub async fn upload(&self, collection: &ImagesCollection) -> Result<InsertOneResult> {
 		Ok(collection.insert_one(self, None).await?)
 	}
@@ -238,10 +527,14 @@ impl Image {
 			.await?)
 	}
 
+	/// The uploader's image count is decremented unless it was already deleted.
 	pub async fn delete_request(self, collection: &ImagesCollection) -> Result<UpdateResult> {
-		Ok(collection
+		let res = collection
 			.update_one(
-				doc! { "_id": self.id.ok_or_else(|| Error::from(InternalError::MissingObjectId))? },
+				doc! {
+					"_id": self.id.ok_or_else(|| Error::from(InternalError::MissingObjectId))?,
+					"deleted": { "$exists": false }
+				},
 				doc! {
 					"$set": {
 						"deleted": DateTime::now()
@@ -249,13 +542,22 @@ impl Image {
 				},
 				None,
 			)
-			.await?)
+			.await?;
+
+		if let Some(uploader_id) = self.uploader_id.filter(|_| res.modified_count != 0) {
+			change_image_count(uploader_id, -1, &get_users_collection()).await?;
+		}
+
+		Ok(res)
 	}
 
 	pub async fn restore_request(self, collection: &ImagesCollection) -> Result<UpdateResult> {
-		Ok(collection
+		let res = collection
 			.update_one(
-				doc! { "_id": self.id.ok_or_else(|| Error::from(InternalError::MissingObjectId))? },
+				doc! {
+					"_id": self.id.ok_or_else(|| Error::from(InternalError::MissingObjectId))?,
+					"deleted": { "$exists": true }
+				},
 				doc! {
 					"$unset": {
 						"deleted": ""
@@ -263,7 +565,13 @@ impl Image {
 				},
 				None,
 			)
-			.await?)
+			.await?;
+
+		if let Some(uploader_id) = self.uploader_id.filter(|_| res.modified_count != 0) {
+			change_image_count(uploader_id, 1, &get_users_collection()).await?;
+		}
+
+		Ok(res)
 	}
 }
 
@@ -275,6 +583,31 @@ pub struct ImageUploader {
 }
 
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageThumbnail {
+	/// The configured size name. eg. "icon"
+	pub size: String,
+	pub file_type: String,
+
+	pub width: u32,
+	pub height: u32,
+}
+
+impl ImageThumbnail {
+	pub fn file_name(&self, image_name: &str) -> String {
+		format!("{}.{}", image_name, self.file_type)
+	}
+}
+
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageAnimation {
+	pub frame_count: i32,
+	/// The length of a single loop in milliseconds.
+	pub duration: i64,
+}
+
+
 // Image sent to front-end
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SlimImage {
@@ -290,6 +623,9 @@ pub struct SlimImage {
 	pub is_favorite: bool,
 	pub view_count: i32,
 
+	pub thumbnails: Vec<ImageThumbnail>,
+	pub animation: Option<ImageAnimation>,
+
 	pub upload_date: DateTime,
 }
 
@@ -313,6 +649,9 @@ impl From<Image> for SlimImage {
 			is_favorite: img.is_favorite,
 			view_count: img.view_count,
 
+			thumbnails: img.thumbnails,
+			animation: img.animation,
+
 			upload_date: img.upload_date,
 		}
 	}
@@ -335,6 +674,11 @@ pub struct Gallery {
 
 	pub indexed: i64,
 
+	/// Why it isn't shown.
+	#[serde(default)]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub hidden: Option<HiddenReason>,
+
 	pub updated_at: DateTime,
 	pub created_at: DateTime,
 }
@@ -436,6 +780,7 @@ pub async fn create_empty_gallery(
 		title: None,
 		indexed: 0,
 		images: Vec::new(),
+		hidden: None,
 		updated_at: DateTime::now(),
 		created_at: DateTime::now(),
 	};
@@ -473,6 +818,238 @@ pub async fn find_images_from_gallery(
 }
 
 
+// REPORTS
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportTarget {
+	Image,
+	Gallery,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Report {
+	#[serde(rename = "_id")]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub id: Option<ObjectId>,
+
+	pub target: ReportTarget,
+	/// The image or gallery name.
+	pub name: String,
+
+	pub reason: String,
+	/// The id of the user who reported it. Guests are stored by their IP. eg. "user:62a1f0c2e4b0a1b2c3d4e5f6" or "ip:127.0.0.1"
+	pub reporter: String,
+
+	/// Set once a moderator dismissed it or removed the content.
+	#[serde(default)]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub resolved_at: Option<DateTime>,
+
+	pub created_at: DateTime,
+}
+
+/// The open reports of one image or gallery.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReportSummary {
+	pub target: ReportTarget,
+	pub name: String,
+	pub count: i64,
+	pub reasons: Vec<String>,
+	pub last_reported: DateTime,
+}
+
+fn open_reports_filter(target: ReportTarget, name: &str) -> Result<Document> {
+	Ok(doc! {
+		"target": mongodb::bson::to_bson(&target)?,
+		"name": name,
+		"resolved_at": { "$exists": false },
+	})
+}
+
+/// Returns the amount of different reporters with an open report for it. Each reporter is only stored once.
+pub async fn insert_report(report: &Report, collection: &ReportsCollection) -> Result<usize> {
+	let filter = open_reports_filter(report.target, &report.name)?;
+
+	let mut existing = filter.clone();
+	existing.insert("reporter", report.reporter.as_str());
+
+	if collection.count_documents(existing, None).await? == 0 {
+		collection.insert_one(report, None).await?;
+	}
+
+	Ok(collection.distinct("reporter", filter, None).await?.len())
+}
+
+/// The amount of reports the reporter submitted since then.
+pub async fn count_reports_since(reporter: &str, since: DateTime, collection: &ReportsCollection) -> Result<u64> {
+	Ok(collection
+		.count_documents(doc! { "reporter": reporter, "created_at": { "$gte": since } }, None)
+		.await?)
+}
+
+pub async fn resolve_reports(target: ReportTarget, name: &str, collection: &ReportsCollection) -> Result<UpdateResult> {
+	Ok(collection
+		.update_many(
+			open_reports_filter(target, name)?,
+			doc! { "$set": { "resolved_at": DateTime::now() } },
+			None,
+		)
+		.await?)
+}
+
+/// The most recently reported first.
+pub async fn find_open_reports(limit: i64, collection: &ReportsCollection) -> Result<Vec<ReportSummary>> {
+	let cursor = collection
+		.aggregate(
+			[
+				doc! { "$match": { "resolved_at": { "$exists": false } } },
+				doc! {
+					"$group": {
+						"_id": { "target": "$target", "name": "$name" },
+						"count": { "$sum": 1_i64 },
+						"reasons": { "$addToSet": "$reason" },
+						"last_reported": { "$max": "$created_at" },
+					}
+				},
+				doc! { "$sort": { "last_reported": -1 } },
+				doc! { "$limit": limit },
+				doc! {
+					"$project": {
+						"_id": 0,
+						"target": "$_id.target",
+						"name": "$_id.name",
+						"count": 1,
+						"reasons": 1,
+						"last_reported": 1,
+					}
+				},
+			],
+			None,
+		)
+		.await?;
+
+	cursor
+		.map_err(Error::from)
+		.and_then(|v| async move { Ok(mongodb::bson::from_document(v)?) })
+		.try_collect()
+		.await
+}
+
+/// Hides it as reported unless it's already hidden.
+pub async fn hide_reported(target: ReportTarget, name: &str) -> Result<()> {
+	set_reported(target, name, true).await
+}
+
+/// Shows it again if it was only hidden because it was reported.
+pub async fn unhide_reported(target: ReportTarget, name: &str) -> Result<()> {
+	set_reported(target, name, false).await
+}
+
+async fn set_reported(target: ReportTarget, name: &str, hide: bool) -> Result<()> {
+	let (filter, update) = if hide {
+		(
+			doc! { "name": name, "hidden": { "$exists": false } },
+			doc! { "$set": { "hidden": mongodb::bson::to_bson(&HiddenReason::Reported)? } },
+		)
+	} else {
+		(
+			doc! { "name": name, "hidden": mongodb::bson::to_bson(&HiddenReason::Reported)? },
+			doc! { "$unset": { "hidden": "" } },
+		)
+	};
+
+	match target {
+		ReportTarget::Image => get_images_collection().update_one(filter, update, None).await?,
+		ReportTarget::Gallery => get_gallery_collection().update_one(filter, update, None).await?,
+	};
+
+	Ok(())
+}
+
+
+// PROCESSING JOBS
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+	Pending,
+	Processing,
+	Completed,
+	Failed,
+}
+
+/// Compression and thumbnailing of an upload which happens after the upload request is finished.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ProcessingJob {
+	#[serde(rename = "_id")]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub id: Option<ObjectId>,
+
+	pub image_name: String,
+
+	pub status: JobStatus,
+
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub error: Option<String>,
+
+	pub created_at: DateTime,
+	pub updated_at: DateTime,
+}
+
+impl ProcessingJob {
+	pub fn new(image_name: String) -> Self {
+		Self {
+			id: None,
+			image_name,
+			status: JobStatus::Pending,
+			error: None,
+			created_at: DateTime::now(),
+			updated_at: DateTime::now(),
+		}
+	}
+
+	pub async fn insert(&mut self, collection: &JobsCollection) -> Result<()> {
+		self.id = collection.insert_one(&*self, None).await?.inserted_id.as_object_id();
+
+		Ok(())
+	}
+
+	pub async fn set_status(&mut self, status: JobStatus, error: Option<String>, collection: &JobsCollection) -> Result<UpdateResult> {
+		self.status = status;
+		self.error = error;
+		self.updated_at = DateTime::now();
+
+		Ok(collection
+			.update_one(
+				doc! { "_id": self.id.ok_or_else(|| Error::from(InternalError::MissingObjectId))? },
+				doc! {
+					"$set": {
+						"status": mongodb::bson::to_bson(&self.status)?,
+						"error": self.error.as_deref(),
+						"updated_at": self.updated_at,
+					}
+				},
+				None,
+			)
+			.await?)
+	}
+}
+
+/// Pending and processing jobs. eg. Ones which were interrupted by a restart.
+pub async fn find_unfinished_jobs(collection: &JobsCollection) -> Result<Vec<ProcessingJob>> {
+	Ok(collection
+		.find(doc! { "status": { "$in": ["pending", "processing"] } }, None)
+		.await?
+		.try_collect()
+		.await?)
+}
+
+pub async fn find_job_by_image_name(image_name: &str, collection: &JobsCollection) -> Result<Option<ProcessingJob>> {
+	Ok(collection.find_one(doc! { "image_name": image_name }, None).await?)
+}
+
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AuthVerify {
 	#[serde(rename = "_id")]
@@ -528,6 +1105,87 @@ pub async fn find_image_by_name(
 	Ok(collection.find_one(doc! { "name": f_name }, None).await?)
 }
 
+/// The newest first.
+pub async fn find_recent_images(filter: Document, limit: i64, collection: &ImagesCollection) -> Result<Vec<Image>> {
+	Ok(collection
+		.find(filter, FindOptions::builder().sort(doc! { "upload_date": -1 }).limit(limit).build())
+		.await?
+		.try_collect()
+		.await?)
+}
+
+/// Of the images which aren't deleted.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct StorageTotals {
+	pub count: i64,
+	pub size_original: i64,
+	pub size_compressed: i64,
+}
+
+pub async fn storage_totals(collection: &ImagesCollection) -> Result<StorageTotals> {
+	let mut cursor = collection
+		.aggregate(
+			[
+				doc! { "$match": { "deleted": { "$exists": false } } },
+				doc! {
+					"$group": {
+						"_id": null,
+						"count": { "$sum": 1_i64 },
+						"size_original": { "$sum": "$size_original" },
+						"size_compressed": { "$sum": "$size_compressed" },
+					}
+				},
+			],
+			None,
+		)
+		.await?;
+
+	match cursor.try_next().await? {
+		Some(totals) => Ok(mongodb::bson::from_document(totals)?),
+		None => Ok(StorageTotals::default()),
+	}
+}
+
+/// Only if it's hidden for one of the `reasons`.
+pub async fn is_image_hidden(f_name: &str, reasons: &[HiddenReason], collection: &ImagesCollection) -> Result<bool> {
+	Ok(collection
+		.count_documents(doc! { "name": f_name, "hidden": { "$in": mongodb::bson::to_bson(reasons)? } }, None)
+		.await? != 0)
+}
+
+/// Swaps in the results of a processing job.
+pub async fn set_image_processed(name: &str, processed: &ProcessedImage, collection: &ImagesCollection) -> Result<UpdateResult> {
+	let mut set = doc! {
+		"size_compressed": processed.size_compressed,
+		"thumbnails": mongodb::bson::to_bson(&processed.thumbnails)?,
+	};
+
+	let mut unset = Document::new();
+
+	if let Some(animation) = processed.animation.as_ref() {
+		set.insert("animation", mongodb::bson::to_bson(animation)?);
+	}
+
+	match processed.compression_error.as_deref() {
+		Some(error) => set.insert("compression_error", error),
+		None => unset.insert("compression_error", ""),
+	};
+
+	if let Some(file_type) = processed.original_file_type.as_deref() {
+		set.insert("original_file_type", file_type);
+	}
+
+	let mut update = doc! { "$set": set };
+
+	if !unset.is_empty() {
+		update.insert("$unset", unset);
+	}
+
+	Ok(collection
+		.update_one(doc! { "name": name }, update, None)
+		.await?)
+}
+
 pub async fn find_images_by_date<I: Into<UserId>>(
 	f_id: I,
 	f_year: u32,
@@ -626,4 +1284,36 @@ pub fn to_end_of_month(current_month: NaiveDateTime) -> Result<NaiveDateTime> {
 		current_month.with_day(days_in_month)
 			.ok_or(DateTimeError::InvalidDay(days_in_month))?
 	)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn ban_expiry() {
+		assert!(!UserBan::new(None, None, None).is_expired());
+		assert!(!UserBan::new(None, Some(1), None).is_expired());
+
+		let ban = UserBan {
+			reason: None,
+			banned_at: DateTime::from_millis(0),
+			expires_at: Some(DateTime::from_millis(1)),
+			banned_by: None,
+		};
+
+		assert!(ban.is_expired());
+	}
+
+	#[test]
+	fn ban_lifting() {
+		let ban = UserBan::new(None, None, Some(UserRole::Admin));
+		assert!(!ban.can_be_lifted_by(UserRole::Moderator));
+		assert!(ban.can_be_lifted_by(UserRole::Admin));
+
+		let ban = UserBan::new(None, None, Some(UserRole::Moderator));
+		assert!(ban.can_be_lifted_by(UserRole::Moderator));
+
+		assert!(!UserBan::new(None, None, None).can_be_lifted_by(UserRole::Moderator));
+	}
+}
@@ -5,8 +5,10 @@ use thiserror::Error as ThisError;
 use lettre::error::Error as LettreError;
 use lettre::address::AddressError;
 use lettre::transport::smtp::Error as SmtpError;
-use handlebars::RenderError;
+use handlebars::{RenderError, TemplateError};
 use image::ImageError;
+use img_parts::Error as ImagePartsError;
+use exif::Error as ExifError;
 use mongodb::error::Error as MongodbError;
 use reqwest::Error as HttpError;
 use serde_json::Error as JsonError;
@@ -14,11 +16,13 @@ use serde::de::value::Error as SerdeValueError;
 use twapi::TwapiError;
 use std::io::Error as IoError;
 use mongodb::bson::ser::Error as BsonError;
+use mongodb::bson::de::Error as BsonDeError;
+use mongodb::bson::oid::Error as ObjectIdError;
 use url::ParseError as UrlParseError;
 
 use actix_multipart::MultipartError;
 use actix_web::Error as ActixError;
-use actix_web::ResponseError;
+use actix_web::{HttpResponse, ResponseError, http::{StatusCode, header}};
 
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -30,6 +34,9 @@ pub enum Error {
 	#[error("Internal DateTime Error: {0}")]
 	DateTime(#[from] DateTimeError),
 
+	#[error("{0}")]
+	Config(#[from] ConfigError),
+
 	#[error("Poison Error")]
 	Poisoned,
 
@@ -49,18 +56,29 @@ pub enum Error {
 
 	#[error("Actix Invalid Header Error: {0}")]
 	ActixInvalidHeaderValue(String),
-	#[error("ActixWeb Error: {0}")]
-	Actix(#[from] ActixError),
+	/// Stored as text so errors can be sent between threads.
+	#[error("ActixWeb Error: {1}")]
+	Actix(StatusCode, String),
 	#[error("Multipart Error: {0}")]
 	Multipart(#[from] MultipartError),
 	#[error("MongoDB Error: {0}")]
 	Mongodb(#[from] MongodbError),
 	#[error("Bson Error: {0}")]
 	Bson(#[from] BsonError),
+	#[error("Bson Deserialize Error: {0}")]
+	BsonDe(#[from] BsonDeError),
+	#[error("ObjectId Error: {0}")]
+	ObjectId(#[from] ObjectIdError),
 	#[error("Image Error: {0}")]
 	Image(#[from] ImageError),
+	#[error("Image Metadata Error: {0}")]
+	ImageMetadata(#[from] ImagePartsError),
+	#[error("EXIF Error: {0}")]
+	Exif(#[from] ExifError),
 	#[error("Handlebars Error: {0}")]
 	Render(#[from] RenderError),
+	#[error("Handlebars Template Error: {0}")]
+	Template(#[from] Box<TemplateError>),
 	#[error("Lettre Error: {0}")]
 	Lettre(#[from] LettreError),
 	#[error("SMTP Error: {0}")]
@@ -73,8 +91,17 @@ pub enum Error {
 	#[error("Backblaze Error: {0}")]
 	B2(#[from] crate::upload::service::b2::JsonErrorStruct),
 
+	#[error("S3 Error: {0}")]
+	S3(#[from] crate::upload::service::s3::S3ErrorStruct),
+
 	#[error("PNG Optimize Error: {0}")]
 	Oxipng(#[from] oxipng::PngError),
+
+	#[error("{0}")]
+	MozJpeg(#[from] MozJpegError),
+
+	#[error("Too many uploads are being processed. Retry in {0} seconds.")]
+	ProcessingSaturated(u64),
 }
 
 #[derive(Debug, ThisError)]
@@ -118,17 +145,27 @@ pub enum InternalError {
 
 	// JPEG
 
-	#[error("An Error Occured while trying to Optimize JPEG Image")]
-	MozJpegError,
-
 	#[error("An Error Occured while trying retrieve JPEG Data")]
 	MozJpegDataRetrive,
 
 	#[error("An Error Occured while trying read scan lines for JPEG")]
 	MozJpegScanLines,
 
-	#[error("The Specific format is unimplemented for this JPEG")]
-	MozJpegUnimplementedFormat,
+	// Animation
+
+	#[error("An Error Occured while trying to Encode an Animated WebP")]
+	WebPAnimationEncode,
+
+	#[error("An Error Occured while trying to Convert the Animation with ffmpeg")]
+	FfmpegConversion,
+
+	// Processing
+
+	#[error("The Image Processing Pool is Shut Down")]
+	ProcessingPoolClosed,
+
+	#[error("Image Processing Panicked")]
+	ProcessingPanicked,
 
 	// Other
 
@@ -139,7 +176,25 @@ pub enum InternalError {
 	MissingObjectId,
 }
 
-impl ResponseError for Error {}
+impl ResponseError for Error {
+	fn status_code(&self) -> StatusCode {
+		match self {
+			Self::Actix(status, _) => *status,
+			Self::ProcessingSaturated(_) => StatusCode::SERVICE_UNAVAILABLE,
+			_ => StatusCode::INTERNAL_SERVER_ERROR,
+		}
+	}
+
+	fn error_response(&self) -> HttpResponse {
+		let mut resp = HttpResponse::build(self.status_code());
+
+		if let Self::ProcessingSaturated(retry_after) = self {
+			resp.insert_header((header::RETRY_AFTER, retry_after.to_string()));
+		}
+
+		resp.body(self.to_string())
+	}
+}
 
 impl<V> From<PoisonError<V>> for Error {
 	fn from(_: PoisonError<V>) -> Self {
@@ -147,6 +202,12 @@ impl<V> From<PoisonError<V>> for Error {
 	}
 }
 
+impl From<ActixError> for Error {
+	fn from(v: ActixError) -> Self {
+		Self::Actix(v.as_response_error().status_code(), v.to_string())
+	}
+}
+
 impl From<TwapiError> for Error {
 	fn from(v: TwapiError) -> Self {
 		Self::TwApi(format!("{:?}", v))
@@ -154,6 +215,39 @@ impl From<TwapiError> for Error {
 }
 
 
+/// mozjpeg reports most of its errors by panicking.
+#[derive(Debug, ThisError)]
+#[error("MozJPEG Error: {panic}{}", .decode_error.as_ref().map(|v| format!(" (Unable to decode it: {})", v)).unwrap_or_default())]
+pub struct MozJpegError {
+	/// The panic message from re-encoding.
+	pub panic: String,
+	/// Why mozjpeg was unable to decode it. The pixels from the image crate were used instead.
+	pub decode_error: Option<String>,
+}
+
+
+#[derive(Debug, ThisError)]
+pub enum ConfigError {
+	#[error("The config file \"{0}\" was missing so I created it. Please edit it then start again.")]
+	Created(String),
+
+	#[error("Unable to open the config file \"{0}\". Please ensure you have permissions to access it. {1}")]
+	Open(String, IoError),
+
+	#[error("Unable to read the config \"{0}\": {1}")]
+	Parse(String, JsonError),
+
+	#[error("The config file \"{0}\" isn't valid TOML: {1}")]
+	ParseToml(String, toml::de::Error),
+
+	#[error("Unable to write the config as TOML: {0}")]
+	SerializeToml(String),
+
+	#[error("The config has {} problem(s):\n{}", .0.len(), .0.iter().map(|v| format!("  - {}", v)).collect::<Vec<_>>().join("\n"))]
+	Invalid(Vec<String>),
+}
+
+
 #[derive(Debug, ThisError)]
 pub enum DateTimeError {
 	#[error("Invalid Year {0}")]
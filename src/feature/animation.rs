@@ -0,0 +1,209 @@
+use std::{fs::File, io::BufReader, path::Path};
+
+use image::{
+	AnimationDecoder,
+	DynamicImage,
+	Frame,
+	ImageDecoder,
+	codecs::gif::{GifDecoder, GifEncoder, Repeat},
+};
+use tokio::process::Command;
+
+use crate::{Result, error::InternalError};
+
+
+/// GIFs with more frames are handled like still images.
+const MAX_FRAMES: usize = 1_000;
+/// The pixels of every frame together. Each frame is the full canvas and takes 4 bytes per pixel.
+const MAX_TOTAL_PIXELS: u64 = 100_000_000;
+
+
+/// Every frame of an animated GIF composited onto the full canvas.
+pub struct Animation {
+	frames: Vec<Frame>,
+}
+
+impl Animation {
+	/// Returns None if the GIF only has a single frame or is too large to keep every frame in memory.
+	pub fn decode_gif<P: AsRef<Path>>(path: P) -> Result<Option<Self>> {
+		Self::decode_gif_limited(path, MAX_FRAMES, MAX_TOTAL_PIXELS)
+	}
+
+	/// Stops decoding as soon as the next frame would go past one of the limits.
+	fn decode_gif_limited<P: AsRef<Path>>(path: P, max_frames: usize, max_total_pixels: u64) -> Result<Option<Self>> {
+		let decoder = GifDecoder::new(BufReader::new(File::open(path)?))?;
+
+		let (width, height) = decoder.dimensions();
+		let frame_pixels = u64::from(width) * u64::from(height);
+
+		let max_frames = max_frames.min((max_total_pixels / frame_pixels.max(1)) as usize);
+
+		let mut frames = Vec::new();
+
+		for frame in decoder.into_frames() {
+			if frames.len() == max_frames {
+				return Ok(None);
+			}
+
+			frames.push(frame?);
+		}
+
+		if frames.len() > 1 {
+			Ok(Some(Self { frames }))
+		} else {
+			Ok(None)
+		}
+	}
+
+	pub fn frame_count(&self) -> usize {
+		self.frames.len()
+	}
+
+	/// The length of a single loop in milliseconds.
+	pub fn duration(&self) -> u64 {
+		self.frames.iter().map(|v| frame_delay(v) as u64).sum()
+	}
+
+	pub fn width(&self) -> u32 {
+		self.frames[0].buffer().width()
+	}
+
+	pub fn height(&self) -> u32 {
+		self.frames[0].buffer().height()
+	}
+
+	/// Applies the function to every frame. eg. resizing.
+	pub fn map_frames<F: Fn(&DynamicImage) -> DynamicImage>(&self, func: F) -> Self {
+		let frames = self.frames.iter()
+			.map(|frame| {
+				let image = func(&DynamicImage::ImageRgba8(frame.buffer().clone()));
+
+				Frame::from_parts(image.to_rgba8(), 0, 0, frame.delay())
+			})
+			.collect();
+
+		Self { frames }
+	}
+
+	pub fn encode_gif(&self) -> Result<Vec<u8>> {
+		let mut data = Vec::new();
+
+		{
+			let mut encoder = GifEncoder::new(&mut data);
+			encoder.set_repeat(Repeat::Infinite)?;
+			encoder.encode_frames(self.frames.iter().cloned())?;
+		}
+
+		Ok(data)
+	}
+
+	pub fn encode_webp(&self, lossless: bool, quality: f32) -> Result<Vec<u8>> {
+		let mut config = webp::WebPConfig::new().map_err(|_| InternalError::WebPAnimationEncode)?;
+		config.lossless = lossless as i32;
+		config.quality = quality;
+
+		let mut encoder = webp::AnimEncoder::new(self.width(), self.height(), &config);
+
+		let mut timestamp = 0;
+
+		for frame in &self.frames {
+			encoder.add_frame(webp::AnimFrame::from_rgba(
+				frame.buffer().as_raw(),
+				self.width(),
+				self.height(),
+				timestamp,
+			));
+
+			timestamp += frame_delay(frame) as i32;
+		}
+
+		let data = encoder.try_encode().map_err(|_| InternalError::WebPAnimationEncode)?;
+
+		Ok(data.to_vec())
+	}
+}
+
+
+/// In milliseconds. Browsers play very short delays at 100ms so they're stored the same.
+fn frame_delay(frame: &Frame) -> u32 {
+	let (numer, denom) = frame.delay().numer_denom_ms();
+
+	let delay = numer / denom.max(1);
+
+	if delay <= 10 {
+		100
+	} else {
+		delay
+	}
+}
+
+
+/// Converts the GIF into a silent MP4 using ffmpeg.
+pub async fn convert_to_mp4(ffmpeg_path: &str, source: &Path) -> Result<Vec<u8>> {
+	let output = source.with_extension("mp4");
+
+	let status = Command::new(ffmpeg_path)
+		.args(["-loglevel", "error", "-y", "-i"])
+		.arg(source)
+		.args([
+			"-an",
+			"-movflags", "+faststart",
+			"-pix_fmt", "yuv420p",
+			// yuv420p requires even dimensions.
+			"-vf", "scale=trunc(iw/2)*2:trunc(ih/2)*2",
+			"-f", "mp4",
+		])
+		.arg(&output)
+		.kill_on_drop(true)
+		.status()
+		.await;
+
+	let data = match status {
+		Ok(status) if status.success() => tokio::fs::read(&output).await.map_err(Into::into),
+		Ok(_) => Err(InternalError::FfmpegConversion.into()),
+		Err(e) => Err(e.into()),
+	};
+
+	if tokio::fs::metadata(&output).await.is_ok() {
+		tokio::fs::remove_file(&output).await?;
+	}
+
+	data
+}
+
+
+#[cfg(test)]
+mod tests {
+	#![allow(clippy::unwrap_used)]
+
+	use image::{Delay, Rgba, RgbaImage};
+
+	use super::*;
+
+	#[test]
+	fn gif_round_trip() {
+		let frames = [Rgba([255, 0, 0, 255]), Rgba([0, 0, 255, 255])].iter()
+			.map(|color| Frame::from_parts(RgbaImage::from_pixel(8, 4, *color), 0, 0, Delay::from_numer_denom_ms(50, 1)))
+			.collect();
+
+		let animation = Animation { frames };
+
+		let directory = tempfile::tempdir().unwrap();
+		let path = directory.path().join("animation.gif");
+		std::fs::write(&path, animation.encode_gif().unwrap()).unwrap();
+
+		// 2 frames of 8x4.
+		assert!(Animation::decode_gif_limited(&path, 1, 1_000).unwrap().is_none());
+		assert!(Animation::decode_gif_limited(&path, 10, 63).unwrap().is_none());
+
+		let decoded = Animation::decode_gif(&path).unwrap().unwrap();
+
+		assert_eq!(2, decoded.frame_count());
+		assert_eq!(100, decoded.duration());
+
+		let resized = decoded.map_frames(|v| v.thumbnail_exact(4, 2));
+		assert_eq!((4, 2), (resized.width(), resized.height()));
+
+		assert!(!resized.encode_webp(false, 80.0).unwrap().is_empty());
+	}
+}
@@ -1,21 +1,43 @@
-use std::panic::catch_unwind;
+use std::{any::Any, panic::catch_unwind};
 
 use image::{ColorType, DynamicImage};
+use mozjpeg::ColorSpace;
 
-use crate::{Filename, Result, web::ConfigDataService, error::{InternalError, Error}};
+use crate::{
+	Filename,
+	Result,
+	config::{ConfigAnimatedFormat, ConfigFeatureCompression, ConfigTranscodeFormat},
+	db::model::User,
+	error::{InternalError, Error, MozJpegError},
+	upload::spool::SpoolFile,
+};
 
+use super::{animation::{Animation, convert_to_mp4}, metadata};
 
 
-pub fn compress_if_enabled(file_name: &Filename, image_data: Vec<u8>, image: DynamicImage, config: &ConfigDataService) -> Result<Vec<u8>> {
+
+/// The site settings with the users' preferences applied.
+pub fn compression_for_user(config: &ConfigFeatureCompression, user: &User) -> ConfigFeatureCompression {
+	let mut compression = config.clone();
+
+	if let Some(prefs) = user.compression.as_ref() {
+		compression.enabled &= prefs.enabled;
+		compression.quality = prefs.quality.unwrap_or(compression.quality);
+		compression.lossless_only |= prefs.lossless_only;
+		compression.keep_original |= prefs.keep_original;
+	}
+
+	compression
+}
+
+pub fn compress_if_enabled(file_name: &Filename, image_data: Vec<u8>, image: &DynamicImage, compression: &ConfigFeatureCompression) -> Result<Vec<u8>> {
 	{
-		if !config.features.compression.enabled {
+		if !compression.enabled {
 			return Ok(image_data);
 		}
 	}
 
 	if file_name.is_format(mime::IMAGE_PNG) {
-		drop(image);
-
 		let new_data = oxipng::optimize_from_memory(
 			&image_data,
 			&oxipng::Options {
@@ -30,61 +52,368 @@ pub fn compress_if_enabled(file_name: &Filename, image_data: Vec<u8>, image: Dyn
 		} else {
 			Result::Ok(image_data)
 		}
-	} else if file_name.is_format(mime::IMAGE_JPEG)
-		&& (image.color() == ColorType::Rgb8 || image.color() == ColorType::Rgb16)
-	{
-		drop(image);
+	} else if file_name.is_format(mime::IMAGE_JPEG) && !compression.lossless_only {
+		// Decode it. Uses the already decoded image if mozjpeg is unable to.
+		let (decoded, decode_error) = match catch_unwind(|| decode_jpeg(&image_data)) {
+			Ok(Ok(v)) => (v, None),
+			Ok(Err(e)) => (image_pixels(image), Some(e.to_string())),
+			Err(payload) => (image_pixels(image), Some(panic_message(payload))),
+		};
+
+		let (width, height, color_space, pixels) = decoded;
+		let quality = compression.quality;
 
+		// Re-encode it.
 		let res = catch_unwind(|| {
-			// Decode it.
-			let (width, height, pixels) =
-				match mozjpeg::Decompress::new_mem(&image_data)?.image()? {
-					mozjpeg::Format::RGB(mut d) => (
-						d.width(),
-						d.height(),
-						d.read_scanlines::<[u8; 3]>().ok_or_else(|| Error::from(InternalError::MozJpegScanLines))?,
-					),
-					mozjpeg::Format::Gray(_) => return Result::Err(InternalError::MozJpegUnimplementedFormat.into()),
-					mozjpeg::Format::CMYK(_) => return Result::Err(InternalError::MozJpegUnimplementedFormat.into()),
-				};
-
-			// Re-encode it.
-			let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+			let mut comp = mozjpeg::Compress::new(color_space);
 			comp.set_mem_dest();
 			comp.set_size(width, height);
 
-			comp.set_quality(config.features.compression.quality);
+			comp.set_quality(quality);
 
 			// comp.set_scan_optimization_mode(mozjpeg::ScanMode::Auto);
 
 			comp.start_compress();
 
-			comp.write_scanlines(bytemuck::cast_slice(&pixels));
+			comp.write_scanlines(&pixels);
 
 			comp.finish_compress();
 
-			let new_data = comp.data_to_vec().map_err(|_| Error::from(InternalError::MozJpegDataRetrive))?;
-
-			// Pick smallest image data size.
-			if new_data.len() < image_data.len() {
-				Result::Ok(new_data)
-			} else {
-				Result::Ok(image_data)
-			}
+			comp.data_to_vec()
 		});
 
-		match res {
-			Ok(v) => Ok(v?),
-			// TODO: Output Error.
-			Err(_e) => {
-				Err(crate::error::InternalError::MozJpegError.into())
-			}
+		let new_data = match res {
+			Ok(Ok(v)) => v,
+			Ok(Err(_)) => return Err(InternalError::MozJpegDataRetrive.into()),
+			Err(payload) => return Err(MozJpegError { panic: panic_message(payload), decode_error }.into()),
+		};
+
+		// Pick smallest image data size.
+		if new_data.len() < image_data.len() {
+			Result::Ok(new_data)
+		} else {
+			Result::Ok(image_data)
 		}
 	} else {
-		if file_name.is_format(mime::IMAGE_JPEG) {
-			println!("Unknown JPEG Color: {:?}", image.color());
+		Ok(image_data)
+	}
+}
+
+/// The message given to `panic!`.
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+	payload.downcast_ref::<&str>()
+		.map(|v| v.to_string())
+		.or_else(|| payload.downcast_ref::<String>().cloned())
+		.unwrap_or_else(|| String::from("Unknown Panic"))
+}
+
+/// Decodes into RGB or grayscale pixels. CMYK is converted into RGB.
+fn decode_jpeg(image_data: &[u8]) -> Result<(usize, usize, ColorSpace, Vec<u8>)> {
+	let decompress = mozjpeg::Decompress::with_markers(&[mozjpeg::Marker::APP(14)]).from_mem(image_data)?;
+
+	// Adobe stores CMYK inverted.
+	let is_adobe = decompress.markers().any(|v| v.data.starts_with(b"Adobe"));
+
+	let decoded = match decompress.image()? {
+		mozjpeg::Format::RGB(mut d) => (
+			d.width(),
+			d.height(),
+			ColorSpace::JCS_RGB,
+			d.read_scanlines_flat().ok_or_else(|| Error::from(InternalError::MozJpegScanLines))?,
+		),
+		mozjpeg::Format::Gray(mut d) => (
+			d.width(),
+			d.height(),
+			ColorSpace::JCS_GRAYSCALE,
+			d.read_scanlines_flat().ok_or_else(|| Error::from(InternalError::MozJpegScanLines))?,
+		),
+		mozjpeg::Format::CMYK(mut d) => {
+			let cmyk = d.read_scanlines_flat().ok_or_else(|| Error::from(InternalError::MozJpegScanLines))?;
+
+			let icc_profile = metadata::read_icc_profile(image_data);
+
+			(
+				d.width(),
+				d.height(),
+				ColorSpace::JCS_RGB,
+				cmyk_to_rgb(&cmyk, icc_profile.as_deref(), is_adobe),
+			)
+		}
+	};
+
+	Ok(decoded)
+}
+
+/// 16-bit and alpha channels are down-converted.
+fn image_pixels(image: &DynamicImage) -> (usize, usize, ColorSpace, Vec<u8>) {
+	let (width, height) = (image.width() as usize, image.height() as usize);
+
+	match image.color() {
+		ColorType::L8 | ColorType::L16 | ColorType::La8 | ColorType::La16 => {
+			(width, height, ColorSpace::JCS_GRAYSCALE, image.to_luma8().into_raw())
+		}
+		_ => (width, height, ColorSpace::JCS_RGB, image.to_rgb8().into_raw()),
+	}
+}
+
+/// Uses the embedded color profile if there is one. Otherwise it's a naive conversion.
+fn cmyk_to_rgb(cmyk: &[u8], icc_profile: Option<&[u8]>, inverted: bool) -> Vec<u8> {
+	let mut rgb = vec![0; cmyk.len() / 4 * 3];
+
+	if let Some(transform) = icc_profile.and_then(|v| cmyk_transform(v, inverted)) {
+		transform.transform_pixels(bytemuck::cast_slice(cmyk), bytemuck::cast_slice_mut(&mut rgb));
+
+		return rgb;
+	}
+
+	// The amount of light left after each ink.
+	let light = |value: u8| if inverted { value as u32 } else { 255 - value as u32 };
+
+	for (cmyk, rgb) in cmyk.chunks_exact(4).zip(rgb.chunks_exact_mut(3)) {
+		let black = light(cmyk[3]);
+
+		for (rgb, ink) in rgb.iter_mut().zip(cmyk) {
+			*rgb = (light(*ink) * black / 255) as u8;
 		}
+	}
+
+	rgb
+}
+
+fn cmyk_transform(icc_profile: &[u8], inverted: bool) -> Option<lcms2::Transform<[u8; 4], [u8; 3]>> {
+	let profile = lcms2::Profile::new_icc(icc_profile).ok()?;
+
+	if profile.color_space() != lcms2::ColorSpaceSignature::CmykData {
+		return None;
+	}
+
+	lcms2::Transform::new(
+		&profile,
+		if inverted { lcms2::PixelFormat::CMYK_8_REV } else { lcms2::PixelFormat::CMYK_8 },
+		&lcms2::Profile::new_srgb(),
+		lcms2::PixelFormat::RGB_8,
+		lcms2::Intent::Perceptual,
+	).ok()
+}
 
+/// Transcodes PNG and JPEG images into the configured format. Only used if it's smaller.
+///
+/// Changes the format of the file name if it was transcoded.
+/// Only PNGs are transcoded in lossless only mode.
+///
+/// PNGs with more than 8 bits per channel aren't transcoded into WebP since it would lose precision.
+/// AVIFs can't keep the EXIF orientation so it's applied to the pixels instead.
+pub fn transcode_if_smaller(
+	file_name: &mut Filename,
+	image_data: Vec<u8>,
+	image: &DynamicImage,
+	orientation: u32,
+	compression: &ConfigFeatureCompression,
+) -> Result<Vec<u8>> {
+	let is_png = file_name.is_format(mime::IMAGE_PNG);
+
+	if !compression.enabled || !(is_png || (file_name.is_format(mime::IMAGE_JPEG) && !compression.lossless_only)) {
+		return Ok(image_data);
+	}
+
+	let (new_data, format) = match compression.transcode {
+		ConfigTranscodeFormat::None => return Ok(image_data),
+		ConfigTranscodeFormat::WebP if is_png && has_high_bit_depth(image) => return Ok(image_data),
+		// PNGs are kept lossless.
+		ConfigTranscodeFormat::WebP => (encode_webp(image, is_png, compression.quality), "image/webp"),
+		ConfigTranscodeFormat::Avif if compression.lossless_only => return Ok(image_data),
+		ConfigTranscodeFormat::Avif => match encode_avif(&metadata::apply_orientation(image, orientation), compression.quality)? {
+			Some(v) => (v, "image/avif"),
+			None => return Ok(image_data),
+		},
+	};
+
+	if new_data.len() < image_data.len() {
+		*file_name = file_name.clone().set_format(format.to_string())?;
+
+		Ok(new_data)
+	} else {
 		Ok(image_data)
 	}
-}
\ No newline at end of file
+}
+
+/// More than 8 bits per channel.
+fn has_high_bit_depth(image: &DynamicImage) -> bool {
+	let color = image.color();
+
+	color.bytes_per_pixel() > color.channel_count()
+}
+
+/// Converts animated GIFs into the configured format. Only used if it's smaller.
+///
+/// Replaces the spool file and changes the format of the file name if it was converted.
+/// MP4 is skipped in lossless only mode.
+pub async fn convert_animation_if_smaller(
+	file_name: &mut Filename,
+	file: &mut SpoolFile,
+	animation: &Animation,
+	compression: &ConfigFeatureCompression,
+) -> Result<()> {
+	if !compression.enabled {
+		return Ok(());
+	}
+
+	let (new_data, format) = match compression.animated {
+		ConfigAnimatedFormat::None => return Ok(()),
+		ConfigAnimatedFormat::Mp4 if compression.lossless_only => return Ok(()),
+		ConfigAnimatedFormat::WebP => (animation.encode_webp(compression.lossless_only, compression.quality)?, "image/webp"),
+		ConfigAnimatedFormat::Mp4 => (
+			convert_to_mp4(compression.ffmpeg_path.as_deref().unwrap_or("ffmpeg"), file.path()).await?,
+			"video/mp4",
+		),
+	};
+
+	if (new_data.len() as u64) < file.len() {
+		*file_name = file_name.clone().set_format(format.to_string())?;
+		file.replace(&new_data).await?;
+	}
+
+	Ok(())
+}
+
+pub fn encode_webp(image: &DynamicImage, lossless: bool, quality: f32) -> Vec<u8> {
+	let image = image.to_rgba8();
+
+	let encoder = webp::Encoder::from_rgba(image.as_raw(), image.width(), image.height());
+
+	if lossless {
+		encoder.encode_lossless().to_vec()
+	} else {
+		encoder.encode(quality).to_vec()
+	}
+}
+
+#[cfg(feature = "avif")]
+pub fn encode_avif(image: &DynamicImage, quality: f32) -> Result<Option<Vec<u8>>> {
+	let image = image.to_rgba8();
+
+	let mut data = Vec::new();
+
+	image::codecs::avif::AvifEncoder::new_with_speed_quality(&mut data, 6, quality as u8)
+		.write_image(image.as_raw(), image.width(), image.height(), ColorType::Rgba8)?;
+
+	Ok(Some(data))
+}
+
+/// The "avif" feature isn't enabled.
+#[cfg(not(feature = "avif"))]
+pub fn encode_avif(_image: &DynamicImage, _quality: f32) -> Result<Option<Vec<u8>>> {
+	Ok(None)
+}
+
+
+#[cfg(test)]
+mod tests {
+	#![allow(clippy::unwrap_used)]
+
+	use std::io::Cursor;
+
+	use image::ImageOutputFormat;
+
+	use crate::db::model::UserCompression;
+
+	use super::*;
+
+	#[test]
+	fn jpeg_color_spaces() {
+		let mut data = Vec::new();
+		DynamicImage::new_luma8(4, 2).write_to(&mut Cursor::new(&mut data), ImageOutputFormat::Jpeg(90)).unwrap();
+
+		let (width, height, color_space, pixels) = decode_jpeg(&data).unwrap();
+		assert_eq!((4, 2, ColorSpace::JCS_GRAYSCALE, 8), (width, height, color_space, pixels.len()));
+
+		// Cyan and black.
+		assert_eq!(vec![0, 255, 255, 0, 0, 0], cmyk_to_rgb(&[255, 0, 0, 0, 0, 0, 0, 255], None, false));
+		assert_eq!(vec![0, 255, 255, 0, 0, 0], cmyk_to_rgb(&[0, 255, 255, 255, 255, 255, 255, 0], None, true));
+
+		let (_, _, color_space, _) = image_pixels(&DynamicImage::new_rgba16(4, 2));
+		assert_eq!(ColorSpace::JCS_RGB, color_space);
+	}
+
+	#[test]
+	fn user_preferences() {
+		let config = ConfigFeatureCompression { enabled: true, ..Default::default() };
+
+		let user = User {
+			id: mongodb::bson::oid::ObjectId::new(),
+			twitter: None,
+			passwordless: None,
+			google: None,
+			upload_type: crate::upload::image::UploadImageType::Alphabetical32,
+			is_banned: false,
+			role: Default::default(),
+			ban: None,
+			join_date: mongodb::bson::DateTime::now(),
+			unique_id: String::new(),
+			image_count: 0,
+			deletion_count: 0,
+			tier: None,
+			compression: Some(UserCompression {
+				quality: Some(60.0),
+				lossless_only: true,
+				..Default::default()
+			}),
+			version_key: None,
+		};
+
+		let compression = compression_for_user(&config, &user);
+		assert!(compression.enabled && compression.lossless_only);
+		assert_eq!(60.0, compression.quality);
+
+		// JPEGs are left alone.
+		let image = DynamicImage::new_rgb8(4, 2);
+		let mut data = Vec::new();
+		image.write_to(&mut Cursor::new(&mut data), ImageOutputFormat::Jpeg(100)).unwrap();
+
+		let file_name = Filename::new(String::from("Name"), Some(String::from("jpeg"))).unwrap();
+		assert_eq!(data, compress_if_enabled(&file_name, data.clone(), &image, &compression).unwrap());
+
+		// Can't be enabled if it's disabled for the site.
+		let config = ConfigFeatureCompression::default();
+		assert!(!compression_for_user(&config, &user).enabled);
+	}
+
+	#[test]
+	fn high_bit_depth_pngs() {
+		let compression = ConfigFeatureCompression {
+			enabled: true,
+			lossless_only: true,
+			transcode: ConfigTranscodeFormat::WebP,
+			..Default::default()
+		};
+
+		let image = DynamicImage::new_rgb16(64, 64);
+		let mut data = Vec::new();
+		image.write_to(&mut Cursor::new(&mut data), ImageOutputFormat::Png).unwrap();
+
+		let mut file_name = Filename::new(String::from("Name"), Some(String::from("png"))).unwrap();
+		assert_eq!(data, transcode_if_smaller(&mut file_name, data.clone(), &image, 1, &compression).unwrap());
+		assert!(file_name.is_format(mime::IMAGE_PNG));
+
+		// 8 bit PNGs are still transcoded.
+		let image = DynamicImage::new_rgb8(64, 64);
+		let mut data = Vec::new();
+		image.write_to(&mut Cursor::new(&mut data), ImageOutputFormat::Png).unwrap();
+
+		let new_data = transcode_if_smaller(&mut file_name, data.clone(), &image, 1, &compression).unwrap();
+		assert!(new_data.len() < data.len() && file_name.is_format_subtype(crate::words::WEBP));
+	}
+
+	#[test]
+	fn panic_messages() {
+		let payload = catch_unwind(|| panic!("bad scanline {}", 4)).unwrap_err();
+		assert_eq!("bad scanline 4", panic_message(payload));
+
+		let payload = catch_unwind(|| std::panic::panic_any(4)).unwrap_err();
+		assert_eq!("Unknown Panic", panic_message(payload));
+
+		// Both are stored as the compression error.
+		let error = MozJpegError { panic: String::from("bad scanline 4"), decode_error: Some(String::from("Unsupported marker")) };
+		assert_eq!("MozJPEG Error: bad scanline 4 (Unable to decode it: Unsupported marker)", error.to_string());
+	}
+}
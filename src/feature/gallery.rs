@@ -1,21 +1,20 @@
-use actix_web::Scope;
+use actix_web::{guard, web, Scope};
 
-use crate::config::Config;
-use crate::web::gallery;
+use crate::web::{gallery, reload::ConfigStore};
 
 
 
 
-pub fn register(scope: Scope, config: &Config) -> Scope {
-	if config.features.gallery.enabled {
-		scope
+/// The routes are skipped while the feature is disabled so it can be toggled by reloading the config.
+pub fn register(scope: Scope, config: ConfigStore) -> Scope {
+	scope.service(
+		web::scope("")
+		.guard(guard::fn_guard(move |_| config.read().features.gallery.enabled))
 		.service(gallery::home)
 		.service(gallery::item)
 		.service(gallery::gallery_new)
 		.service(gallery::gallery_delete)
 		.service(gallery::gallery_update)
 		.service(gallery::gallery_image_list)
-	} else {
-		scope
-	}
-}
\ No newline at end of file
+	)
+}
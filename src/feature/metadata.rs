@@ -0,0 +1,363 @@
+use std::{borrow::Cow, io::Cursor};
+
+use exif::{experimental::Writer, Field, In, Tag, Value};
+use image::DynamicImage;
+use img_parts::{Bytes, DynImage, ImageEXIF, ImageICC};
+
+use crate::{Result, config::ConfigMetadataPolicy};
+
+
+// Segments and chunks which only contain metadata. EXIF and ICC are handled separately.
+const JPEG_APP1: u8 = 0xE1;
+const JPEG_APP14: u8 = 0xEE;
+const JPEG_APP15: u8 = 0xEF;
+const JPEG_COM: u8 = 0xFE;
+
+const PNG_TEXT_CHUNKS: [[u8; 4]; 4] = [*b"tEXt", *b"zTXt", *b"iTXt", *b"tIME"];
+
+const WEBP_XMP_CHUNK: [u8; 4] = *b"XMP ";
+const WEBP_LOSSLESS_CHUNK: [u8; 4] = *b"VP8L";
+
+const GIF_EXTENSION: u8 = 0x21;
+const GIF_IMAGE: u8 = 0x2C;
+const GIF_TRAILER: u8 = 0x3B;
+const GIF_COMMENT: u8 = 0xFE;
+const GIF_APPLICATION: u8 = 0xFF;
+/// Application extensions which control looping.
+const GIF_LOOP_APPLICATIONS: [&[u8; 11]; 2] = [b"NETSCAPE2.0", b"ANIMEXTS1.0"];
+const GIF_ICC_APPLICATION: &[u8; 11] = b"ICCRGBG1012";
+
+
+/// The EXIF orientation (1-8) of JPEG, PNG and WebP images. 1 is upright.
+pub fn read_orientation(data: &[u8]) -> u32 {
+	(|| {
+		let exif = DynImage::from_bytes(Bytes::copy_from_slice(data)).ok()??.exif()?;
+
+		let exif = exif::Reader::new().read_raw(exif.to_vec()).ok()?;
+
+		exif.get_field(Tag::Orientation, In::PRIMARY)?.value.get_uint(0)
+	})()
+	.filter(|v| (1..=8).contains(v))
+	.unwrap_or(1)
+}
+
+pub fn read_icc_profile(data: &[u8]) -> Option<Bytes> {
+	DynImage::from_bytes(Bytes::copy_from_slice(data)).ok()??.icc_profile()
+}
+
+/// WebPs can be lossy or lossless.
+pub fn is_lossless_webp(data: &[u8]) -> bool {
+	matches!(
+		DynImage::from_bytes(Bytes::copy_from_slice(data)),
+		Ok(Some(DynImage::WebP(webp))) if webp.has_chunk(WEBP_LOSSLESS_CHUNK)
+	)
+}
+
+/// Rotates and flips the pixels so the image is upright.
+pub fn apply_orientation(image: &DynamicImage, orientation: u32) -> Cow<'_, DynamicImage> {
+	Cow::Owned(match orientation {
+		2 => image.fliph(),
+		3 => image.rotate180(),
+		4 => image.flipv(),
+		5 => image.rotate90().fliph(),
+		6 => image.rotate90(),
+		7 => image.rotate270().fliph(),
+		8 => image.rotate270(),
+		_ => return Cow::Borrowed(image),
+	})
+}
+
+/// Applies the policy to JPEG, PNG, WebP and GIF images.
+///
+/// AVIFs are only created by transcoding and never contain metadata. Other formats are returned unchanged.
+///
+/// The metadata is taken from the original upload since compressing and transcoding removes it.
+/// `orientation` is the one the image still needs. 1 if it was applied to the pixels. Only strip can apply it.
+pub fn apply_policy(data: Vec<u8>, original: &[u8], policy: ConfigMetadataPolicy, orientation: u32) -> Result<Vec<u8>> {
+	if data.starts_with(b"GIF8") {
+		return Ok(match policy {
+			ConfigMetadataPolicy::Keep => data,
+			ConfigMetadataPolicy::Essential => remove_gif_metadata(&data, true).unwrap_or(data),
+			ConfigMetadataPolicy::Strip => remove_gif_metadata(&data, false).unwrap_or(data),
+		});
+	}
+
+	let data = Bytes::from(data);
+
+	let mut image = match DynImage::from_bytes(data.clone())? {
+		Some(v) => v,
+		None => return Ok(data.to_vec()),
+	};
+
+	let original_image = DynImage::from_bytes(Bytes::copy_from_slice(original))?;
+
+	let (exif, icc) = match policy {
+		ConfigMetadataPolicy::Keep => {
+			// Nothing was removed.
+			if image.exif().is_some() || image.icc_profile().is_some() {
+				return Ok(data.to_vec());
+			}
+
+			(
+				original_image.as_ref().and_then(|v| v.exif()),
+				original_image.as_ref().and_then(|v| v.icc_profile()),
+			)
+		}
+
+		ConfigMetadataPolicy::Essential => (
+			match orientation {
+				1 => None,
+				orientation => Some(orientation_exif(orientation)?),
+			},
+			original_image.as_ref().and_then(|v| v.icc_profile()),
+		),
+
+		// Only kept if the image couldn't be rotated without losing quality.
+		ConfigMetadataPolicy::Strip => (
+			match orientation {
+				1 => None,
+				orientation => Some(orientation_exif(orientation)?),
+			},
+			None,
+		),
+	};
+
+	// CMYK JPEGs are converted into RGB when compressed so their profile no longer applies.
+	let icc = icc.filter(|v| data.as_ref() == original || !is_cmyk_profile(v));
+
+	if policy != ConfigMetadataPolicy::Keep {
+		remove_extra_metadata(&mut image);
+	}
+
+	image.set_icc_profile(icc);
+	image.set_exif(exif);
+
+	let mut new_data = Vec::with_capacity(image.len());
+	image.encoder().write_to(&mut new_data)?;
+
+	Ok(new_data)
+}
+
+fn is_cmyk_profile(profile: &[u8]) -> bool {
+	profile.get(16..20) == Some(b"CMYK")
+}
+
+/// Removes XMP, comments and text chunks.
+fn remove_extra_metadata(image: &mut DynImage) {
+	match image {
+		DynImage::Jpeg(jpeg) => jpeg.segments_mut().retain(|segment| {
+			let marker = segment.marker();
+
+			// APP0 (JFIF) and APP14 (Adobe color transform) are needed for decoding.
+			// EXIF (APP1) and ICC (APP2) are added back if kept.
+			let is_metadata = (JPEG_APP1..=JPEG_APP15).contains(&marker) && marker != JPEG_APP14;
+
+			!(is_metadata || marker == JPEG_COM)
+		}),
+
+		DynImage::Png(png) => {
+			for kind in PNG_TEXT_CHUNKS {
+				png.remove_chunks_by_type(kind);
+			}
+		}
+
+		DynImage::WebP(webp) => webp.remove_chunks_by_id(WEBP_XMP_CHUNK),
+	}
+}
+
+/// Removes comments and application extensions other than looping and optionally the color profile.
+///
+/// Returns None if the GIF couldn't be read.
+fn remove_gif_metadata(data: &[u8], keep_icc: bool) -> Option<Vec<u8>> {
+	// The size of a color table from the packed flags. Unset if there isn't one.
+	let color_table_size = |flags: u8| if flags & 0x80 == 0 { 0 } else { 3 << ((flags & 0x07) + 1) };
+
+	// Skips a chain of data sub-blocks and returns the position after it.
+	let skip_sub_blocks = |mut position: usize| -> Option<usize> {
+		loop {
+			let length = *data.get(position)? as usize;
+			position += 1 + length;
+
+			if length == 0 {
+				return Some(position);
+			}
+		}
+	};
+
+	// The header, logical screen descriptor and global color table.
+	let mut position = 13 + color_table_size(*data.get(10)?);
+	let mut new_data = data.get(..position)?.to_vec();
+
+	loop {
+		let start = position;
+
+		match *data.get(position)? {
+			GIF_EXTENSION => {
+				let label = *data.get(position + 1)?;
+				position = skip_sub_blocks(position + 2)?;
+
+				let is_kept = match label {
+					GIF_COMMENT => false,
+					GIF_APPLICATION => {
+						let application = data.get(start + 3..start + 14)?;
+
+						GIF_LOOP_APPLICATIONS.iter().any(|v| v.as_slice() == application)
+							|| (keep_icc && application == GIF_ICC_APPLICATION)
+					}
+					_ => true,
+				};
+
+				if is_kept {
+					new_data.extend_from_slice(data.get(start..position)?);
+				}
+			}
+
+			GIF_IMAGE => {
+				// The descriptor, local color table and LZW minimum code size.
+				position += 10 + color_table_size(*data.get(position + 9)?) + 1;
+				position = skip_sub_blocks(position)?;
+
+				new_data.extend_from_slice(data.get(start..position)?);
+			}
+
+			GIF_TRAILER => {
+				new_data.push(GIF_TRAILER);
+
+				return Some(new_data);
+			}
+
+			_ => return None,
+		}
+	}
+}
+
+/// An EXIF block only containing the orientation.
+fn orientation_exif(orientation: u32) -> Result<Bytes> {
+	let field = Field {
+		tag: Tag::Orientation,
+		ifd_num: In::PRIMARY,
+		value: Value::Short(vec![orientation as u16]),
+	};
+
+	let mut writer = Writer::new();
+	writer.push_field(&field);
+
+	let mut data = Cursor::new(Vec::new());
+	writer.write(&mut data, false)?;
+
+	Ok(Bytes::from(data.into_inner()))
+}
+
+
+#[cfg(test)]
+mod tests {
+	#![allow(clippy::unwrap_used)]
+
+	use image::ImageOutputFormat;
+
+	use super::*;
+
+	fn jpeg_with_exif(exif: Bytes) -> Vec<u8> {
+		let mut data = Vec::new();
+		DynamicImage::new_rgb8(4, 2).write_to(&mut Cursor::new(&mut data), ImageOutputFormat::Jpeg(90)).unwrap();
+
+		let mut image = DynImage::from_bytes(Bytes::from(data)).unwrap().unwrap();
+		image.set_exif(Some(exif));
+
+		let mut data = Vec::new();
+		image.encoder().write_to(&mut data).unwrap();
+		data
+	}
+
+	#[test]
+	fn metadata_policies() {
+		let gps = Field {
+			tag: Tag::GPSLatitudeRef,
+			ifd_num: In::PRIMARY,
+			value: Value::Ascii(vec![b"N".to_vec()]),
+		};
+
+		let orientation = Field {
+			tag: Tag::Orientation,
+			ifd_num: In::PRIMARY,
+			value: Value::Short(vec![6]),
+		};
+
+		let mut writer = Writer::new();
+		writer.push_field(&gps);
+		writer.push_field(&orientation);
+
+		let mut exif = Cursor::new(Vec::new());
+		writer.write(&mut exif, false).unwrap();
+
+		let original = jpeg_with_exif(Bytes::from(exif.into_inner()));
+		assert_eq!(6, read_orientation(&original));
+
+		let read_exif = |data: &[u8]| {
+			DynImage::from_bytes(Bytes::copy_from_slice(data)).unwrap().unwrap().exif()
+				.map(|v| exif::Reader::new().read_raw(v.to_vec()).unwrap())
+		};
+
+		let essential = apply_policy(original.clone(), &original, ConfigMetadataPolicy::Essential, 6).unwrap();
+		let exif = read_exif(&essential).unwrap();
+		assert_eq!(6, read_orientation(&essential));
+		assert!(exif.get_field(Tag::GPSLatitudeRef, In::PRIMARY).is_none());
+
+		let stripped = apply_policy(original.clone(), &original, ConfigMetadataPolicy::Strip, 1).unwrap();
+		assert!(read_exif(&stripped).is_none());
+
+		// It couldn't be rotated.
+		let stripped = apply_policy(original.clone(), &original, ConfigMetadataPolicy::Strip, 6).unwrap();
+		assert_eq!(6, read_orientation(&stripped));
+		assert!(read_exif(&stripped).unwrap().get_field(Tag::GPSLatitudeRef, In::PRIMARY).is_none());
+
+		assert_eq!(original, apply_policy(original.clone(), &original, ConfigMetadataPolicy::Keep, 6).unwrap());
+
+		let image = DynamicImage::new_rgb8(4, 2);
+		let upright = apply_orientation(&image, 6);
+		assert_eq!((2, 4), (upright.width(), upright.height()));
+	}
+
+	#[test]
+	fn gif_metadata() {
+		let mut original = Vec::new();
+		DynamicImage::new_rgba8(4, 2).write_to(&mut Cursor::new(&mut original), ImageOutputFormat::Gif).unwrap();
+
+		let extension = |label: u8, blocks: &[&[u8]]| {
+			let mut data = vec![GIF_EXTENSION, label];
+
+			for block in blocks {
+				data.push(block.len() as u8);
+				data.extend_from_slice(block);
+			}
+
+			data.push(0);
+			data
+		};
+
+		let comment = extension(GIF_COMMENT, &[b"Secret"]);
+		let xmp = extension(GIF_APPLICATION, &[b"XMP DataXMP", b"<x:xmpmeta/>"]);
+		let icc = extension(GIF_APPLICATION, &[GIF_ICC_APPLICATION, b"profile"]);
+		let looping = extension(GIF_APPLICATION, &[b"NETSCAPE2.0", &[1, 0, 0]]);
+
+		let header_size = 13 + 3 * 2_usize.pow(u32::from(original[10] & 0x07) + 1) * usize::from(original[10] >> 7);
+
+		let mut data = original[..header_size].to_vec();
+		for block in [&comment, &xmp, &icc, &looping] {
+			data.extend_from_slice(block);
+		}
+		data.extend_from_slice(&original[header_size..]);
+
+		let contains = |data: &[u8], block: &[u8]| data.windows(block.len()).any(|v| v == block);
+
+		let essential = apply_policy(data.clone(), &data, ConfigMetadataPolicy::Essential, 1).unwrap();
+		assert!(!contains(&essential, &comment) && !contains(&essential, &xmp));
+		assert!(contains(&essential, &icc) && contains(&essential, &looping));
+
+		let stripped = apply_policy(data.clone(), &data, ConfigMetadataPolicy::Strip, 1).unwrap();
+		assert!(!contains(&stripped, &icc) && contains(&stripped, &looping));
+
+		assert!(image::load_from_memory(&stripped).is_ok());
+		assert_eq!(data, apply_policy(data.clone(), &data, ConfigMetadataPolicy::Keep, 1).unwrap());
+	}
+}
@@ -1,2 +1,5 @@
+pub mod animation;
 pub mod compress;
-pub mod gallery;
\ No newline at end of file
+pub mod gallery;
+pub mod metadata;
+pub mod resize;
\ No newline at end of file
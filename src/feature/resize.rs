@@ -0,0 +1,443 @@
+use std::io::Cursor;
+
+use actix_web::{HttpRequest, HttpResponse, error::ErrorBadRequest, http::header};
+use image::{DynamicImage, ImageOutputFormat, imageops::FilterType};
+use serde::Deserialize;
+
+use crate::{
+	Filename,
+	Result,
+	config::{ConfigFeatureResize, ConfigFeatureThumbnails, ConfigThumbnailFormat, ConfigThumbnailMode, ConfigThumbnailSize},
+	db::model::ImageThumbnail,
+	upload::{pool::ProcessingPool, service::{Service, StoredFile}},
+	web::ConfigDataService,
+};
+
+use super::{animation::Animation, compress::{encode_avif, encode_webp}};
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Fit {
+	/// Fits inside of the width and height keeping the aspect ratio. Never enlarges.
+	Contain,
+	/// Fills the width and height keeping the aspect ratio. The overflow is cropped.
+	Cover,
+	/// Stretches to exactly the width and height.
+	Fill,
+}
+
+impl Fit {
+	fn name(self) -> &'static str {
+		match self {
+			Self::Contain => "contain",
+			Self::Cover => "cover",
+			Self::Fill => "fill",
+		}
+	}
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VariantFormat {
+	Png,
+	Jpeg,
+	WebP,
+	Avif,
+}
+
+impl VariantFormat {
+	fn from_name(value: &str) -> Option<Self> {
+		match value {
+			"png" => Some(Self::Png),
+			"jpg" | "jpeg" => Some(Self::Jpeg),
+			"webp" => Some(Self::WebP),
+			"avif" if cfg!(feature = "avif") => Some(Self::Avif),
+			_ => None,
+		}
+	}
+
+	/// The format of a stored image. None for GIFs and MP4s.
+	pub fn from_file_name(file_name: &Filename) -> Option<Self> {
+		Self::from_name(file_name.format_name().ok()?)
+	}
+
+	pub fn name(self) -> &'static str {
+		match self {
+			Self::Png => "png",
+			Self::Jpeg => "jpeg",
+			Self::WebP => "webp",
+			Self::Avif => "avif",
+		}
+	}
+
+	pub fn content_type(self) -> &'static str {
+		match self {
+			Self::Png => "image/png",
+			Self::Jpeg => "image/jpeg",
+			Self::WebP => "image/webp",
+			Self::Avif => "image/avif",
+		}
+	}
+
+	pub fn encode(self, image: &DynamicImage, quality: f32) -> Result<Vec<u8>> {
+		let mut data = Vec::new();
+
+		match self {
+			Self::Png => image.write_to(&mut Cursor::new(&mut data), ImageOutputFormat::Png)?,
+			Self::Jpeg => DynamicImage::ImageRgb8(image.to_rgb8())
+				.write_to(&mut Cursor::new(&mut data), ImageOutputFormat::Jpeg(quality as u8))?,
+			Self::WebP => data = encode_webp(image, false, quality),
+			Self::Avif => match encode_avif(image, quality)? {
+				Some(v) => data = v,
+				None => return Err(ErrorBadRequest("Invalid format. Expected png, jpeg or webp.").into()),
+			},
+		}
+
+		Ok(data)
+	}
+
+	/// Picks the best format the client says it accepts. Otherwise keeps the original format.
+	fn negotiate(original: &Filename, accept: Option<&str>) -> Self {
+		let accepts = |media_type: &str| accept.is_some_and(|v| accepts_media_type(v, media_type));
+
+		if cfg!(feature = "avif") && accepts("image/avif") {
+			Self::Avif
+		} else if accepts("image/webp") {
+			Self::WebP
+		} else if original.is_format(mime::IMAGE_JPEG) {
+			Self::Jpeg
+		} else if original.is_format_subtype(crate::words::WEBP) {
+			Self::WebP
+		} else {
+			// GIFs lose their animation so PNG is used instead.
+			Self::Png
+		}
+	}
+}
+
+/// Whether the Accept header lists the media type without `q=0`.
+fn accepts_media_type(accept: &str, media_type: &str) -> bool {
+	accept.split(',').any(|range| {
+		let mut params = range.split(';').map(str::trim);
+
+		params.next().is_some_and(|v| v.eq_ignore_ascii_case(media_type))
+			&& params
+				.filter_map(|param| param.strip_prefix("q="))
+				.all(|q| q.parse::<f32>().is_ok_and(|q| q > 0.0))
+	})
+}
+
+impl From<ConfigThumbnailFormat> for VariantFormat {
+	fn from(value: ConfigThumbnailFormat) -> Self {
+		match value {
+			ConfigThumbnailFormat::Png => Self::Png,
+			ConfigThumbnailFormat::Jpeg => Self::Jpeg,
+			ConfigThumbnailFormat::WebP => Self::WebP,
+		}
+	}
+}
+
+
+/// The query parameters of an image request.
+#[derive(Debug, Default, Deserialize)]
+pub struct VariantQuery {
+	pub w: Option<u32>,
+	pub h: Option<u32>,
+	pub fit: Option<Fit>,
+	pub fmt: Option<String>,
+
+	/// `?original=1` serves the original upload to its' uploader.
+	pub original: Option<u8>,
+}
+
+impl VariantQuery {
+	pub fn is_original(&self) -> bool {
+		self.original == Some(1)
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.w.is_none() && self.h.is_none() && self.fit.is_none() && self.fmt.is_none()
+	}
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Variant {
+	pub width: Option<u32>,
+	pub height: Option<u32>,
+	pub fit: Fit,
+	pub format: VariantFormat,
+}
+
+impl Variant {
+	/// The width and height are rounded up to the configured sizes.
+	pub fn new(query: &VariantQuery, original: &Filename, accept: Option<&str>, config: &ConfigFeatureResize) -> Result<Self> {
+		let round = |size: Option<u32>| -> Result<Option<u32>> {
+			match size {
+				Some(0) => Err(ErrorBadRequest("Width and height can't be 0.").into()),
+				Some(size) => config.round_size(size)
+					.map(Some)
+					.ok_or_else(|| ErrorBadRequest(format!("Width and height can't be larger than {}.", config.sizes.iter().max().copied().unwrap_or_default())).into()),
+				None => Ok(None),
+			}
+		};
+
+		let width = round(query.w)?;
+		let height = round(query.h)?;
+
+		let format = match query.fmt.as_deref() {
+			Some(name) => VariantFormat::from_name(name)
+				.ok_or_else(|| ErrorBadRequest("Invalid format. Expected png, jpeg or webp."))?,
+			None => VariantFormat::negotiate(original, accept),
+		};
+
+		// Cropping and stretching need both dimensions.
+		let fit = match (width, height) {
+			(Some(_), Some(_)) => query.fit.unwrap_or(Fit::Contain),
+			_ => Fit::Contain,
+		};
+
+		Ok(Self {
+			width,
+			height,
+			fit,
+			format,
+		})
+	}
+
+	/// The name the variant is cached under. eg. "640x480_cover.webp" or "640xauto_contain.png"
+	pub fn file_name(&self) -> String {
+		fn dimension(value: Option<u32>) -> String {
+			value.map(|v| v.to_string()).unwrap_or_else(|| String::from("auto"))
+		}
+
+		format!(
+			"{}x{}_{}.{}",
+			dimension(self.width),
+			dimension(self.height),
+			self.fit.name(),
+			self.format.name()
+		)
+	}
+
+	pub fn create(&self, image: &DynamicImage, quality: f32) -> Result<Vec<u8>> {
+		let resized = match (self.width, self.height, self.fit) {
+			(Some(width), Some(height), Fit::Cover) => image.resize_to_fill(width, height, FilterType::Lanczos3),
+			(Some(width), Some(height), Fit::Fill) => image.resize_exact(width, height, FilterType::Lanczos3),
+			(None, None, _) => image.clone(),
+			(width, height, _) => {
+				let width = width.unwrap_or(u32::MAX).min(image.width());
+				let height = height.unwrap_or(u32::MAX).min(image.height());
+
+				if width == image.width() && height == image.height() {
+					image.clone()
+				} else {
+					image.resize(width, height, FilterType::Lanczos3)
+				}
+			}
+		};
+
+		self.format.encode(&resized, quality)
+	}
+}
+
+
+/// Creates every configured thumbnail size.
+pub fn create_thumbnails(image: &DynamicImage, config: &ConfigFeatureThumbnails) -> Result<Vec<(ImageThumbnail, Vec<u8>)>> {
+	let format = VariantFormat::from(config.format);
+
+	config.sizes.iter()
+		.map(|(size, options)| {
+			let thumbnail = resize_thumbnail(image, options);
+
+			Ok((
+				ImageThumbnail {
+					size: size.clone(),
+					file_type: format.name().to_string(),
+					width: thumbnail.width(),
+					height: thumbnail.height(),
+				},
+				format.encode(&thumbnail, config.quality)?,
+			))
+		})
+		.collect()
+}
+
+
+/// Creates every configured thumbnail size keeping the animation.
+pub fn create_animated_thumbnails(animation: &Animation, config: &ConfigFeatureThumbnails) -> Result<Vec<(ImageThumbnail, Vec<u8>)>> {
+	config.sizes.iter()
+		.map(|(size, options)| {
+			let thumbnail = animation.map_frames(|v| resize_thumbnail(v, options));
+
+			let (file_type, data) = if config.format == ConfigThumbnailFormat::WebP {
+				("webp", thumbnail.encode_webp(false, config.quality)?)
+			} else {
+				("gif", thumbnail.encode_gif()?)
+			};
+
+			Ok((
+				ImageThumbnail {
+					size: size.clone(),
+					file_type: file_type.to_string(),
+					width: thumbnail.width(),
+					height: thumbnail.height(),
+				},
+				data,
+			))
+		})
+		.collect()
+}
+
+fn resize_thumbnail(image: &DynamicImage, options: &ConfigThumbnailSize) -> DynamicImage {
+	match options.mode {
+		ConfigThumbnailMode::Exact => image.resize_to_fill(options.width, options.height, FilterType::Triangle),
+		ConfigThumbnailMode::Fit => image.thumbnail(options.width, options.height),
+	}
+}
+
+
+/// Serves a resized variant of an image, creating and caching it in the storage backends if needed.
+///
+/// Creating it goes through the processing pool so it's rejected with a 503 when busy.
+pub async fn serve_variant(
+	name: &str,
+	query: &VariantQuery,
+	service: &Service,
+	pool: &ProcessingPool,
+	config: &ConfigDataService,
+	req: &HttpRequest,
+) -> Result<HttpResponse> {
+	let file_name = match name.rsplit_once('.').and_then(|(name, format)| Filename::new(name.to_string(), Some(format.to_string())).ok()) {
+		Some(v) => v,
+		None => return Ok(HttpResponse::NotFound().finish()),
+	};
+
+	// Converted animations can't be decoded.
+	if file_name.is_format_subtype(crate::words::MP4) {
+		return Ok(HttpResponse::NotFound().finish());
+	}
+
+	let accept = req.headers().get(header::ACCEPT).and_then(|v| v.to_str().ok());
+
+	let variant = Variant::new(query, &file_name, accept, &config.features.resize)?;
+
+	let stored_file = StoredFile::Variant(file_name.name.clone(), variant.file_name());
+
+	let data = match service.fetch(stored_file.clone()).await? {
+		Some(v) => v,
+		None => {
+			let original = match service.fetch(StoredFile::Image(name.to_string())).await? {
+				Some(v) => v,
+				None => return Ok(HttpResponse::NotFound().finish()),
+			};
+
+			let quality = config.features.compression.quality;
+			let creating = variant.clone();
+
+			let data = pool.run(async move {
+				let image = image::load_from_memory(&original)?;
+
+				creating.create(&image, quality)
+			}).await?;
+
+			service.store(stored_file, data.clone()).await?;
+
+			data
+		}
+	};
+
+	Ok(HttpResponse::Ok()
+		.insert_header((header::CONTENT_TYPE, variant.format.content_type()))
+		.insert_header((header::VARY, "Accept"))
+		.body(data))
+}
+
+
+#[cfg(test)]
+mod tests {
+	#![allow(clippy::unwrap_used)]
+
+	use super::*;
+
+	#[test]
+	fn variant_negotiation() {
+		let jpeg = Filename::new("Name123".into(), Some("jpeg".into())).unwrap();
+
+		let query = VariantQuery {
+			w: Some(640),
+			h: Some(480),
+			fit: Some(Fit::Cover),
+			..Default::default()
+		};
+
+		let config = ConfigFeatureResize::default();
+
+		let variant = Variant::new(&query, &jpeg, Some("image/webp,*/*"), &config).unwrap();
+		assert_eq!("640x640_cover.webp", variant.file_name());
+
+		let variant = Variant::new(&query, &jpeg, None, &config).unwrap();
+		assert_eq!("640x640_cover.jpeg", variant.file_name());
+
+		let variant = Variant::new(&query, &jpeg, Some("image/webp;q=0, image/avif;q=0,*/*;q=0.8"), &config).unwrap();
+		assert_eq!("640x640_cover.jpeg", variant.file_name());
+
+		// Cover needs both dimensions.
+		let query = VariantQuery { h: None, fmt: Some("png".into()), ..query };
+		let variant = Variant::new(&query, &jpeg, Some("image/webp"), &config).unwrap();
+		assert_eq!("640xauto_contain.png", variant.file_name());
+
+		let query = VariantQuery { w: Some(641), ..query };
+		assert_eq!(Some(1280), Variant::new(&query, &jpeg, None, &config).unwrap().width);
+
+		assert!(Variant::new(&VariantQuery { w: Some(0), ..Default::default() }, &jpeg, None, &config).is_err());
+		assert!(Variant::new(&VariantQuery { w: Some(2049), ..Default::default() }, &jpeg, None, &config).is_err());
+		assert!(Variant::new(&VariantQuery { fmt: Some("bmp".into()), ..Default::default() }, &jpeg, None, &config).is_err());
+	}
+
+	#[test]
+	fn contain_never_enlarges() {
+		let image = DynamicImage::new_rgb8(100, 50);
+
+		let variant = Variant {
+			width: Some(50),
+			height: None,
+			fit: Fit::Contain,
+			format: VariantFormat::Png,
+		};
+
+		let resized = image::load_from_memory(&variant.create(&image, 80.0).unwrap()).unwrap();
+		assert_eq!((50, 25), (resized.width(), resized.height()));
+
+		let variant = Variant { width: Some(400), ..variant };
+		let resized = image::load_from_memory(&variant.create(&image, 80.0).unwrap()).unwrap();
+		assert_eq!((100, 50), (resized.width(), resized.height()));
+	}
+
+	#[test]
+	fn thumbnail_modes() {
+		let image = DynamicImage::new_rgb8(200, 100);
+
+		let mut config = ConfigFeatureThumbnails {
+			format: ConfigThumbnailFormat::Jpeg,
+			..Default::default()
+		};
+		config.sizes.insert(
+			String::from("medium"),
+			ConfigThumbnailSize {
+				width: 100,
+				height: 100,
+				mode: ConfigThumbnailMode::Fit,
+			},
+		);
+
+		let thumbnails = create_thumbnails(&image, &config).unwrap();
+
+		let sizes = thumbnails.iter()
+			.map(|(v, _)| (v.size.as_str(), v.file_type.as_str(), v.width, v.height))
+			.collect::<Vec<_>>();
+
+		assert_eq!(vec![("icon", "jpeg", 128, 128), ("medium", "jpeg", 100, 50)], sizes);
+	}
+}
@@ -1,30 +1,30 @@
-use std::{cell::UnsafeCell, sync::{Mutex, Arc, MutexGuard}, ops::{Deref, DerefMut}};
+use std::{sync::{Mutex, Arc, MutexGuard, RwLock, PoisonError}, ops::{Deref, DerefMut}};
 
 use crate::Result;
 
 // Allows reading and writing simultaneously.
 pub struct FlipStore<D: Clone> {
 	/// The current one which can be read from.
-	reader: UnsafeCell<Arc<StoreData<D>>>,
+	///
+	/// Only locked long enough to clone or swap the Arc.
+	reader: RwLock<Arc<StoreData<D>>>,
 	/// The current one which is being written to.
 	writer: Mutex<StoreData<D>>,
 }
 
-unsafe impl<D: ?Sized + Send + Clone> Send for FlipStore<D> {}
-unsafe impl<D: ?Sized + Send + Sync + Clone> Sync for FlipStore<D> {}
-
 impl<D: Clone> FlipStore<D> {
 	pub fn new(value: D) -> Self {
 		Self {
-			reader: UnsafeCell::new(Arc::new(StoreData::new(value.clone()))),
+			reader: RwLock::new(Arc::new(StoreData::new(value.clone()))),
 			writer: Mutex::new(StoreData::new(value)),
 		}
 	}
 
 	pub fn read(&self) -> FlipReader<D> {
-		unsafe {
-			FlipReader::new((&*self.reader.get()).clone())
-		}
+		// Nothing can panic while the lock is held so the Arc is always valid.
+		let reader = self.reader.read().unwrap_or_else(PoisonError::into_inner);
+
+		FlipReader::new(Arc::clone(&reader))
 	}
 
 	pub fn write(&self) -> Result<FlipWriter<'_, D>> {
@@ -47,6 +47,7 @@ impl<D: Clone> StoreData<D> {
 }
 
 
+#[derive(Clone)]
 pub struct FlipReader<D: Clone> {
 	store: Arc<StoreData<D>>,
 }
@@ -57,6 +58,11 @@ impl<D: Clone> FlipReader<D> {
 			store
 		}
 	}
+
+	/// Reads a value which is never written to.
+	pub fn from_value(value: D) -> Self {
+		Self::new(Arc::new(StoreData::new(value)))
+	}
 }
 
 impl<D: Clone> Deref for FlipReader<D> {
@@ -105,10 +111,9 @@ impl<'a, D: Clone> Drop for FlipWriter<'a, D> {
 
 		let readable = std::mem::replace(&mut *self.guard, clone);
 
-		unsafe {
-			let v = &mut *self.store.reader.get();
-			*v = Arc::new(readable);
-		}
+		let readable = Arc::new(readable);
+
+		*self.store.reader.write().unwrap_or_else(PoisonError::into_inner) = readable;
 	}
 }
 
@@ -142,4 +147,35 @@ mod tests {
 		let read_new = flipit.read();
 		assert_eq!(6, read_new.len());
 	}
+
+	#[test]
+	fn concurrent_read_write() {
+		let flipit = Arc::new(FlipStore::new(0_usize));
+
+		let readers = (0..4)
+			.map(|_| {
+				let flipit = Arc::clone(&flipit);
+
+				std::thread::spawn(move || {
+					let mut last = 0;
+
+					for _ in 0..10_000 {
+						let current = *flipit.read();
+						assert!(current >= last);
+						last = current;
+					}
+				})
+			})
+			.collect::<Vec<_>>();
+
+		for i in 1..=1_000 {
+			*flipit.write().unwrap() = i;
+		}
+
+		for reader in readers {
+			reader.join().unwrap();
+		}
+
+		assert_eq!(1_000, *flipit.read());
+	}
 }
\ No newline at end of file
@@ -17,7 +17,10 @@ extern crate serde_derive;
 
 use std::env;
 
-use config::ConfigHelper;
+use clap::Parser;
+
+use cli::{Cli, Command, ConfigCommand};
+use config::{Config, ConfigHelper, ConfigTranscodeFormat};
 use db::model;
 use upload::service::Service;
 
@@ -27,6 +30,7 @@ pub use words::{Filename, WordManager};
 
 pub mod flipstore;
 pub mod auth;
+pub mod cli;
 pub mod config;
 pub mod db;
 pub mod error;
@@ -37,17 +41,56 @@ pub mod feature;
 
 #[actix_web::main]
 async fn main() -> Result<()> {
+	let cli = Cli::parse();
+
 	env::set_var("RUST_LOG", "actix_web=debug,actix_server=info");
 	env_logger::init();
 
-	let config = ConfigHelper::<ConfigInner>::create_and_load("./app/config.json").await?;
+	let config = match ConfigHelper::<ConfigInner>::create_and_load(&cli.config).await {
+		Ok(v) => v,
+		Err(e) => {
+			eprintln!("{}", e);
+			std::process::exit(1);
+		}
+	};
+
+	let is_check = matches!(cli.command, Some(Command::Config(ConfigCommand::Check)));
 
-	if config.services.b2.enabled && !config.services.b2.public_url.ends_with('/') {
-		panic!(r#"Please end the b2 "public_url" with a "/""#);
+	if !cli::check_config(&config, is_check) {
+		std::process::exit(1);
 	}
 
+	let command = match cli.command.unwrap_or(Command::Serve) {
+		Command::Config(ConfigCommand::Check) => return Ok(()),
+		command => command,
+	};
+
 	std::mem::forget(db::create_mongo_connection(&config.database).await?);
 
+	match command {
+		Command::Serve => serve(config).await?,
+
+		Command::Migrate => cli::migrate().await?,
+
+		Command::Reprocess(options) => {
+			let service = Service::pick_service_from_config(&config.services).await?.waiting_for_replicas();
+
+			upload::reprocess::reprocess_images(&options, &service, &flipstore::FlipReader::from_value(config)).await?;
+		}
+
+		Command::User(command) => cli::user_command(command).await?,
+		Command::Image(command) => cli::image_command(command, &config).await?,
+		Command::Gallery(command) => cli::gallery_command(command).await?,
+
+		Command::Config(_) => (),
+	}
+
+	Ok(())
+}
+
+async fn serve(config: Config) -> Result<()> {
+	db::create_indexes().await?;
+
 	println!(
 		"Feature Gallery {}",
 		if config.features.gallery.enabled {
@@ -66,10 +109,12 @@ async fn main() -> Result<()> {
 		}
 	);
 
+	if config.features.compression.transcode == ConfigTranscodeFormat::Avif && !cfg!(feature = "avif") {
+		println!("Transcoding to AVIF requires the \"avif\" feature. Uploads won't be transcoded.");
+	}
+
 	// Upload Service
 	let service = Service::pick_service_from_config(&config.services).await?;
 
-	web::init(config, service).await?;
-
-	Ok(())
+	web::init(config, service).await
 }
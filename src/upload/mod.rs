@@ -1,29 +1,93 @@
-use crate::{Filename, Result, db::{ImagesCollection, model::User}, web::WordDataService};
+use mongodb::bson::DateTime;
+
+use crate::{Filename, Result, db::{ImagesCollection, get_users_collection, model::{self, User}}, web::WordDataService};
 
 use self::image::UploadImageType;
+use self::spool::SpoolFile;
 
 pub mod image;
+pub mod pool;
+pub mod reprocess;
 pub mod service;
+pub mod spool;
 
 
 pub struct UploadProcessData {
 	pub user: User,
 	pub file_type: Option<UploadImageType>,
-	pub file_data: Vec<u8>,
+	pub file: SpoolFile,
 	pub content_type: String,
 	pub ip_addr: String
 }
 
+/// The results of compressing and thumbnailing an upload.
+pub struct ProcessedImage {
+	pub size_compressed: i64,
+	pub thumbnails: Vec<model::ImageThumbnail>,
+	pub animation: Option<model::ImageAnimation>,
+	/// Set if compressing failed and the original was kept.
+	pub compression_error: Option<String>,
+	/// Set if the original was stored alongside it.
+	pub original_file_type: Option<String>,
+}
+
 impl UploadProcessData {
 	pub async fn get_file_name(&self, image_icon_same_dir: bool, words: &WordDataService, collection: &ImagesCollection) -> Result<Filename> {
 		let mut words = words.lock().await;
 
 		if let Some(upload_type) = self.file_type {
-			upload_type.get_link_name(&mut *words, image_icon_same_dir, collection).await?
+			upload_type.get_link_name(&mut words, image_icon_same_dir, collection).await?
 		} else {
 			self.user.upload_type
-				.get_link_name(&mut *words, image_icon_same_dir, collection)
+				.get_link_name(&mut words, image_icon_same_dir, collection)
 				.await?
-		}.set_format(self.content_type.clone())
+		}.set_upload_format(self.content_type.clone())
+	}
+
+	/// Creates the image document every storage service stores for an upload.
+	pub async fn create_image(
+		&self,
+		file_name: Filename,
+		size_original: i64,
+		processed: ProcessedImage,
+		collection: &ImagesCollection,
+	) -> Result<model::Image> {
+		let mut image = model::Image {
+			id: None,
+
+			file_type: file_name.format_name()?.to_string(),
+			name: file_name.name,
+
+			size_original,
+			size_compressed: processed.size_compressed,
+
+			deleted: None,
+			is_edited: false,
+			is_favorite: false,
+			view_count: 0,
+
+			uploader: model::ImageUploader {
+				uid: self.user.unique_id.clone(),
+				ip: Some(self.ip_addr.clone()),
+			},
+
+			thumbnails: processed.thumbnails,
+			animation: processed.animation,
+			compression_error: processed.compression_error,
+			original_file_type: processed.original_file_type,
+			hidden: None,
+
+			upload_date: DateTime::now(),
+			uploader_id: Some(self.user.id),
+
+			tags: None,
+			custom_name: None,
+		};
+
+		image.id = image.upload(collection).await?.inserted_id.as_object_id();
+
+		model::change_image_count(self.user.id, 1, &get_users_collection()).await?;
+
+		Ok(image)
 	}
 }
\ No newline at end of file
@@ -0,0 +1,277 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use tokio::runtime::{Builder, Runtime};
+use tokio::sync::Semaphore;
+
+use crate::{Result, config::ConfigUploadProcessing, error::{Error, InternalError}};
+
+
+/// Runs image processing on its own threads so it doesn't block the web workers.
+///
+/// At most `concurrency` uploads are processed at once with up to `queue_depth` waiting for a slot.
+/// Anything past that is rejected.
+///
+/// Background jobs don't wait on a response so they're limited separately by `background_depth`.
+pub struct ProcessingPool {
+	runtime: Option<Runtime>,
+	permits: Arc<Semaphore>,
+
+	queue_depth: usize,
+	background_depth: usize,
+	retry_after: u64,
+
+	queued: AtomicUsize,
+	background: Arc<AtomicUsize>,
+	stats: PoolStats,
+}
+
+impl ProcessingPool {
+	pub fn new(config: &ConfigUploadProcessing) -> Result<Self> {
+		let concurrency = config.concurrency.max(1);
+
+		let runtime = Builder::new_multi_thread()
+			.worker_threads(concurrency)
+			.thread_name("image-processing")
+			.enable_all()
+			.build()?;
+
+		Ok(Self {
+			runtime: Some(runtime),
+			permits: Arc::new(Semaphore::new(concurrency)),
+
+			queue_depth: config.queue_depth,
+			background_depth: config.background_depth,
+			retry_after: config.retry_after,
+
+			queued: AtomicUsize::new(0),
+			background: Arc::new(AtomicUsize::new(0)),
+			stats: PoolStats::default(),
+		})
+	}
+
+	/// Waits for a free slot then runs the future on the processing threads.
+	///
+	/// Rejected if the queue is full.
+	pub async fn run<F, T>(&self, future: F) -> Result<T>
+	where
+		F: Future<Output = Result<T>> + Send + 'static,
+		T: Send + 'static,
+	{
+		self.spawn(future, true).await
+	}
+
+	/// Same as [`Self::run`] but waits no matter how many are queued.
+	///
+	/// Used for background jobs since nobody is waiting on them. They're limited by [`Self::reserve_background`] instead.
+	pub async fn queue<F, T>(&self, future: F) -> Result<T>
+	where
+		F: Future<Output = Result<T>> + Send + 'static,
+		T: Send + 'static,
+	{
+		self.spawn(future, false).await
+	}
+
+	/// Reserves a place for a background job until the slot is dropped.
+	///
+	/// Rejected once `background_depth` jobs are waiting or running.
+	pub fn reserve_background(&self) -> Result<BackgroundSlot> {
+		if self.background.fetch_add(1, Ordering::SeqCst) >= self.background_depth {
+			self.background.fetch_sub(1, Ordering::SeqCst);
+			self.stats.rejected.fetch_add(1, Ordering::Relaxed);
+
+			return Err(Error::ProcessingSaturated(self.retry_after));
+		}
+
+		Ok(BackgroundSlot {
+			background: self.background.clone(),
+		})
+	}
+
+	async fn spawn<F, T>(&self, future: F, reject_when_full: bool) -> Result<T>
+	where
+		F: Future<Output = Result<T>> + Send + 'static,
+		T: Send + 'static,
+	{
+		let queued_at = Instant::now();
+
+		let permit = match self.permits.clone().try_acquire_owned() {
+			Ok(v) => v,
+			Err(_) => {
+				if self.queued.fetch_add(1, Ordering::SeqCst) >= self.queue_depth && reject_when_full {
+					self.queued.fetch_sub(1, Ordering::SeqCst);
+					self.stats.rejected.fetch_add(1, Ordering::Relaxed);
+
+					return Err(Error::ProcessingSaturated(self.retry_after));
+				}
+
+				let permit = self.permits.clone().acquire_owned().await;
+
+				self.queued.fetch_sub(1, Ordering::SeqCst);
+
+				permit.map_err(|_| InternalError::ProcessingPoolClosed)?
+			}
+		};
+
+		self.stats.record_wait(queued_at.elapsed());
+
+		let runtime = self.runtime.as_ref().ok_or(InternalError::ProcessingPoolClosed)?;
+
+		let handle = runtime.spawn(async move {
+			let _permit = permit;
+
+			future.await
+		});
+
+		// Only fails if the processing panicked.
+		handle.await.map_err(|_| InternalError::ProcessingPanicked)?
+	}
+
+	pub fn stats(&self) -> ProcessingStats {
+		let processed = self.stats.processed.load(Ordering::Relaxed);
+		let wait_total = self.stats.wait_total_ms.load(Ordering::Relaxed);
+
+		ProcessingStats {
+			available: self.permits.available_permits(),
+			queued: self.queued.load(Ordering::SeqCst),
+			background: self.background.load(Ordering::SeqCst),
+
+			processed,
+			rejected: self.stats.rejected.load(Ordering::Relaxed),
+
+			wait_average_ms: wait_total.checked_div(processed).unwrap_or_default(),
+			wait_max_ms: self.stats.wait_max_ms.load(Ordering::Relaxed),
+			wait_total_ms: wait_total,
+		}
+	}
+}
+
+impl Drop for ProcessingPool {
+	fn drop(&mut self) {
+		// Dropping a runtime normally blocks which isn't allowed inside of the web runtime.
+		if let Some(runtime) = self.runtime.take() {
+			runtime.shutdown_background();
+		}
+	}
+}
+
+
+/// A reserved place for a background job. Freed when dropped.
+pub struct BackgroundSlot {
+	background: Arc<AtomicUsize>,
+}
+
+impl Drop for BackgroundSlot {
+	fn drop(&mut self) {
+		self.background.fetch_sub(1, Ordering::SeqCst);
+	}
+}
+
+
+#[derive(Default)]
+struct PoolStats {
+	processed: AtomicU64,
+	rejected: AtomicU64,
+
+	wait_total_ms: AtomicU64,
+	wait_max_ms: AtomicU64,
+}
+
+impl PoolStats {
+	fn record_wait(&self, wait: Duration) {
+		let wait = wait.as_millis() as u64;
+
+		self.processed.fetch_add(1, Ordering::Relaxed);
+		self.wait_total_ms.fetch_add(wait, Ordering::Relaxed);
+		self.wait_max_ms.fetch_max(wait, Ordering::Relaxed);
+	}
+}
+
+
+/// Time spent waiting in the queue for a processing slot.
+#[derive(Debug, Serialize)]
+pub struct ProcessingStats {
+	/// Free processing slots.
+	pub available: usize,
+	pub queued: usize,
+	/// Background jobs which haven't finished.
+	pub background: usize,
+
+	pub processed: u64,
+	pub rejected: u64,
+
+	pub wait_average_ms: u64,
+	pub wait_max_ms: u64,
+	pub wait_total_ms: u64,
+}
+
+
+#[cfg(test)]
+mod tests {
+	#![allow(clippy::unwrap_used)]
+
+	use super::*;
+
+	#[tokio::test]
+	async fn rejects_when_saturated() {
+		let pool = Arc::new(ProcessingPool::new(&ConfigUploadProcessing {
+			concurrency: 1,
+			queue_depth: 1,
+			retry_after: 7,
+			..Default::default()
+		}).unwrap());
+
+		let (sender, receiver) = tokio::sync::oneshot::channel::<()>();
+
+		// Occupies the only slot until told to finish.
+		let running = tokio::spawn({
+			let pool = pool.clone();
+			async move { pool.run(async move { receiver.await.ok(); Ok(()) }).await }
+		});
+
+		while pool.stats().available != 0 {
+			tokio::task::yield_now().await;
+		}
+
+		let queued = tokio::spawn({
+			let pool = pool.clone();
+			async move { pool.run(async { Ok(1) }).await }
+		});
+
+		while pool.stats().queued != 1 {
+			tokio::task::yield_now().await;
+		}
+
+		assert!(matches!(pool.run(async { Ok(2) }).await, Err(Error::ProcessingSaturated(7))));
+
+		sender.send(()).unwrap();
+
+		running.await.unwrap().unwrap();
+		assert_eq!(1, queued.await.unwrap().unwrap());
+
+		let stats = pool.stats();
+		assert_eq!((2, 1), (stats.processed, stats.rejected));
+	}
+
+	#[tokio::test]
+	async fn limits_background_jobs() {
+		let pool = ProcessingPool::new(&ConfigUploadProcessing {
+			concurrency: 1,
+			background_depth: 2,
+			retry_after: 7,
+			..Default::default()
+		}).unwrap();
+
+		let first = pool.reserve_background().unwrap();
+		let _second = pool.reserve_background().unwrap();
+
+		assert!(matches!(pool.reserve_background(), Err(Error::ProcessingSaturated(7))));
+
+		drop(first);
+
+		assert!(pool.reserve_background().is_ok());
+		assert_eq!(1, pool.stats().background);
+	}
+}
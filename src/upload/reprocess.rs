@@ -0,0 +1,207 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use futures::TryStreamExt;
+use mongodb::{bson::{doc, oid::ObjectId, Document}, options::FindOptions};
+
+use crate::{
+	Result,
+	db::{get_images_collection, get_users_collection, model::{self, Image}},
+	feature::compress::compression_for_user,
+	web::ConfigDataService,
+};
+
+use super::service::Service;
+
+
+/// Images are loaded in pages so a slow run can't outlive the database cursor.
+const PAGE_SIZE: i64 = 100;
+
+
+/// Reruns compression and thumbnailing on existing images. eg. after changing the quality or adding thumbnail sizes.
+#[derive(Debug, clap::Args)]
+pub struct ReprocessOptions {
+	/// Print the new sizes without storing anything.
+	#[arg(long)]
+	pub dry_run: bool,
+
+	/// Continue after the last image processed by a previous run.
+	#[arg(long)]
+	pub resume: bool,
+
+	/// Where the last processed image is recorded.
+	#[arg(long, default_value = "./app/reprocess.progress")]
+	pub progress_file: PathBuf,
+
+	/// The most images to process per second.
+	#[arg(long)]
+	pub rate_limit: Option<f64>,
+
+	/// Only images which failed to compress when uploaded.
+	#[arg(long)]
+	pub failed_only: bool,
+
+	/// Also compress images again which don't have their original kept.
+	/// Lossy images lose quality each time. Otherwise only their thumbnails are recreated.
+	#[arg(long)]
+	pub force: bool,
+}
+
+
+pub async fn reprocess_images(options: &ReprocessOptions, service: &Service, config: &ConfigDataService) -> Result<()> {
+	let mut filter = doc! {
+		"deleted": { "$exists": false }
+	};
+
+	if options.failed_only {
+		filter.insert("compression_error", doc! { "$exists": true });
+	}
+
+	let mut last_id = None;
+
+	if options.resume {
+		if let Some(id) = read_progress(&options.progress_file).await? {
+			println!("Resuming after {}", id);
+			last_id = Some(id);
+		}
+	}
+
+	let delay = options.rate_limit
+		.filter(|v| *v > 0.0)
+		.map(|v| Duration::from_secs_f64(1.0 / v));
+
+	let users = get_users_collection();
+
+	let mut page = Vec::new().into_iter();
+
+	let mut processed_count = 0;
+	let mut failed = Vec::new();
+	let mut bytes_saved = 0;
+
+	while let Some(image) = next_image(&filter, &mut last_id, &mut page).await? {
+		let started = Instant::now();
+
+		// Uses the uploaders' preferences.
+		let user = match image.uploader_id {
+			Some(id) => model::find_user_by_id(id, &users).await?,
+			None => None,
+		};
+
+		let mut compression = match user {
+			Some(user) => compression_for_user(&config.features.compression, &user),
+			None => config.features.compression.clone(),
+		};
+
+		if !options.force && !has_uncompressed_source(&image) {
+			compression.enabled = false;
+		}
+
+		match service.reprocess_image(&image, config, &compression, options.dry_run).await {
+			Ok(processed) => {
+				println!("{}.{}: {} -> {} bytes", image.name, image.file_type, image.size_compressed, processed.size_compressed);
+
+				processed_count += 1;
+				bytes_saved += image.size_compressed - processed.size_compressed;
+			}
+
+			Err(e) => {
+				eprintln!("Reprocessing \"{}\" Error: {}", image.name, e);
+
+				failed.push(image.name.clone());
+			}
+		}
+
+		// Stops at the first failure so resuming retries it.
+		if !options.dry_run && failed.is_empty() {
+			if let Some(id) = image.id {
+				write_progress(&options.progress_file, id).await?;
+			}
+		}
+
+		if let Some(delay) = delay {
+			tokio::time::sleep(delay.saturating_sub(started.elapsed())).await;
+		}
+	}
+
+	println!(
+		"{} {} images. {} failed. {} bytes saved.",
+		if options.dry_run { "Checked" } else { "Reprocessed" },
+		processed_count,
+		failed.len(),
+		bytes_saved,
+	);
+
+	if !failed.is_empty() {
+		println!("Failed: {}", failed.join(", "));
+	}
+
+	// Finished so there's nothing left to resume unless some failed.
+	if !options.dry_run && failed.is_empty() && tokio::fs::metadata(&options.progress_file).await.is_ok() {
+		tokio::fs::remove_file(&options.progress_file).await?;
+	}
+
+	Ok(())
+}
+
+/// Loads the next page once the current one is used up.
+async fn next_image(filter: &Document, last_id: &mut Option<ObjectId>, page: &mut std::vec::IntoIter<Image>) -> Result<Option<Image>> {
+	if page.len() == 0 {
+		let mut filter = filter.clone();
+
+		if let Some(id) = *last_id {
+			filter.insert("_id", doc! { "$gt": id });
+		}
+
+		*page = get_images_collection()
+			.find(filter, FindOptions::builder().sort(doc! { "_id": 1 }).limit(PAGE_SIZE).build())
+			.await?
+			.try_collect::<Vec<_>>()
+			.await?
+			.into_iter();
+	}
+
+	let image = page.next();
+
+	if let Some(id) = image.as_ref().and_then(|v| v.id) {
+		*last_id = Some(id);
+	}
+
+	Ok(image)
+}
+
+/// The stored image is already compressed unless its' original was kept or compressing it failed.
+fn has_uncompressed_source(image: &Image) -> bool {
+	image.reprocessable_original().is_some() || image.compression_error.is_some()
+}
+
+async fn read_progress(path: &Path) -> Result<Option<ObjectId>> {
+	match tokio::fs::read_to_string(path).await {
+		Ok(value) => Ok(Some(ObjectId::parse_str(value.trim())?)),
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+		Err(e) => Err(e.into()),
+	}
+}
+
+async fn write_progress(path: &Path, id: ObjectId) -> Result<()> {
+	Ok(tokio::fs::write(path, id.to_hex()).await?)
+}
+
+
+#[cfg(test)]
+mod tests {
+	#![allow(clippy::unwrap_used)]
+
+	use super::*;
+
+	#[tokio::test]
+	async fn progress_round_trip() {
+		let directory = tempfile::tempdir().unwrap();
+		let path = directory.path().join("reprocess.progress");
+
+		assert_eq!(None, read_progress(&path).await.unwrap());
+
+		let id = ObjectId::new();
+		write_progress(&path, id).await.unwrap();
+		assert_eq!(Some(id), read_progress(&path).await.unwrap());
+	}
+}
@@ -1,24 +1,24 @@
-use std::path::PathBuf;
+use std::str::FromStr;
 use std::thread;
 use std::time::{Duration, Instant};
 
+use actix_web::{HttpRequest, HttpResponse};
+use async_trait::async_trait;
 use base64::encode as b64encode;
 use bytes::Bytes;
 use crypto::digest::Digest;
 use crypto::sha1::Sha1;
-use mongodb::bson::DateTime;
+use reqwest::{StatusCode, Url};
+use tokio::io::AsyncReadExt;
 use tokio::runtime::Runtime;
 use tokio::time::sleep;
 
 use crate::config::ConfigServiceB2;
-use crate::db::model::{self, SlimImage};
-use crate::error::{InternalError, Result, Error};
+use crate::error::{ConfigError, InternalError, Result};
 use crate::flipstore::FlipStore;
-use crate::upload::UploadProcessData;
-use crate::web::{ConfigDataService, WordDataService};
-use crate::{db, Filename};
+use crate::upload::spool::SpoolFile;
 
-use super::process_image_and_create_icon;
+use super::{StorageBackend, StorageDirectories};
 
 // const API_URL_V5: &str = "https://api.backblazeb2.com/b2api/v5";
 // const API_URL_V4: &str = "https://api.backblazeb2.com/b2api/v4";
@@ -72,23 +72,17 @@ async fn check_and_update_auth() -> Result<()> {
 
 pub struct Service {
 	bucket_id: String,
+	public_url: Url,
 
-	image_sub_directory: PathBuf,
-	icon_sub_directory: PathBuf,
+	directories: StorageDirectories,
 }
 
 impl Service {
 	pub async fn new(config: &ConfigServiceB2) -> Result<Self> {
-		if config.id.is_empty() {
-			panic!("B2 Service ID is empty.");
-		}
-
-		if config.key.is_empty() {
-			panic!("B2 Service Key is empty.");
-		}
-
-		if config.bucket_id.is_empty() {
-			panic!("B2 Service Bucked ID is empty.");
+		for (name, value) in [("id", &config.id), ("key", &config.key), ("bucket_id", &config.bucket_id)] {
+			if value.is_empty() {
+				return Err(ConfigError::Invalid(vec![format!(r#""services.b2.{}" is empty."#, name)]).into());
+			}
 		}
 
 		// Spawn Authentication Thread.
@@ -118,117 +112,102 @@ impl Service {
 
 		Ok(Self {
 			bucket_id: config.bucket_id.clone(),
+			public_url: Url::from_str(&config.public_url)?,
 
-			image_sub_directory: PathBuf::from(&config.image_sub_directory),
-			icon_sub_directory: PathBuf::from(&config.icon_sub_directory),
+			directories: StorageDirectories::new(&config.image_sub_directory, &config.icon_sub_directory),
 		})
 	}
 
-	pub async fn process_files(
-		&self,
-		upload_data: UploadProcessData,
-		config: &ConfigDataService,
-		words: &WordDataService,
-	) -> Result<SlimImage> {
-		let collection = db::get_images_collection();
-
-		let file_name = upload_data.get_file_name(
-			self.icon_sub_directory == self.image_sub_directory,
-			words,
-			&collection
-		).await?;
+	fn public_file_url(&self, path: &str) -> Result<Url> {
+		Ok(self.public_url.join(&encode_file_name(path))?)
+	}
+}
 
-		let size_original = upload_data.file_data.len() as i64;
+#[async_trait(?Send)]
+impl StorageBackend for Service {
+	fn name(&self) -> &'static str {
+		"b2"
+	}
 
-		let file_data = process_image_and_create_icon(&file_name, upload_data.file_data, config).await?;
+	fn directories(&self) -> &StorageDirectories {
+		&self.directories
+	}
 
-		let size_compressed = file_data.image_data.len() as i64;
+	async fn store(&self, path: &str, data: Vec<u8>) -> Result<()> {
+		upload_file_multi_try(path, data, &get_auth()?, &self.bucket_id).await
+	}
 
+	/// Files larger than the recommended part size are uploaded with the large file API.
+	async fn store_file(&self, path: &str, file: &SpoolFile) -> Result<()> {
 		let auth = get_auth()?;
 
-		{
-			// Image Upload
-			let mut path = self.image_sub_directory.clone();
-			path.push(file_data.image_name);
-
-			upload_file_multi_try(
-				path.to_str().ok_or_else(|| Error::from(InternalError::ConvertPathBufToString))?,
-				file_data.image_data,
-				&auth,
-				&self.bucket_id,
-			)
-			.await?;
-		}
-
-		{
-			// Icon Upload
-			let mut path = self.icon_sub_directory.clone();
-			path.push(if self.icon_sub_directory == self.image_sub_directory {
-				format!("i{}", file_data.icon_name)
-			} else {
-				file_data.icon_name
-			});
-
-			upload_file_multi_try(
-				path.to_str().ok_or_else(|| Error::from(InternalError::ConvertPathBufToString))?,
-				file_data.icon_data,
-				&auth,
-				&self.bucket_id,
-			)
-			.await?;
+		if file.len() <= auth.recommended_part_size as u64 {
+			upload_file_multi_try(path, file.read().await?, &auth, &self.bucket_id).await
+		} else {
+			upload_large_file(path, file, &auth, &self.bucket_id).await
 		}
+	}
 
-		let new_image = model::Image {
-			id: None,
+	async fn fetch(&self, path: &str) -> Result<Option<Vec<u8>>> {
+		let resp = reqwest::get(self.public_file_url(path)?).await?;
 
-			file_type: file_name.format_name()?.to_string(),
-			name: file_name.name,
+		if resp.status() == StatusCode::NOT_FOUND {
+			Ok(None)
+		} else {
+			Ok(Some(resp.error_for_status()?.bytes().await?.to_vec()))
+		}
+	}
 
-			size_original,
-			size_compressed,
+	/// Files are hidden instead of being removed.
+	async fn delete(&self, path: &str) -> Result<()> {
+		try_hide_file_multi(path, &get_auth()?, &self.bucket_id).await
+	}
 
-			deleted: None,
-			is_edited: false,
-			is_favorite: false,
-			view_count: 0,
+	async fn exists(&self, path: &str) -> Result<bool> {
+		let resp = reqwest::Client::new()
+			.head(self.public_file_url(path)?)
+			.send()
+			.await?;
 
-			uploader: model::ImageUploader {
-				uid: upload_data.user.unique_id,
-				ip: Some(upload_data.ip_addr),
-			},
+		Ok(resp.status().is_success())
+	}
 
-			upload_date: DateTime::now(),
-			uploader_id: Some(upload_data.user.id),
+	async fn list(&self, directory: &str) -> Result<Vec<String>> {
+		let auth = get_auth()?;
 
-			tags: None,
-			custom_name: None,
+		let prefix = if directory.is_empty() {
+			String::new()
+		} else {
+			format!("{}/", encode_file_name(directory.trim_end_matches('/')))
 		};
 
-		new_image.upload(&collection).await?;
+		let mut found = Vec::new();
+		let mut start_file_name = None;
 
-		Ok(new_image.into())
-	}
+		loop {
+			let resp = auth.list_file_names(&self.bucket_id, &prefix, start_file_name.take()).await??;
 
-	pub async fn hide_file(&self, file_name: Filename) -> Result<()> {
-		let auth = get_auth()?;
-
-		{
-			// Image Upload
-			let mut path = self.image_sub_directory.clone();
-			path.push(file_name.as_filename()?);
+			// Sub-directories are returned as entries ending with "/".
+			found.extend(
+				resp.files.into_iter()
+					.map(|v| v.file_name)
+					.filter(|v| !v.ends_with('/'))
+			);
 
-			try_hide_file_multi(path.to_str().ok_or_else(|| Error::from(InternalError::ConvertPathBufToString))?, &auth, &self.bucket_id).await?;
+			match resp.next_file_name {
+				Some(v) => start_file_name = Some(v),
+				None => break,
+			}
 		}
 
-		{
-			// Icon Upload
-			let mut path = self.icon_sub_directory.clone();
-			path.push(format!("i{}.png", file_name.name));
+		Ok(found)
+	}
 
-			try_hide_file_multi(path.to_str().ok_or_else(|| Error::from(InternalError::ConvertPathBufToString))?, &auth, &self.bucket_id).await?;
+	async fn serve(&self, path: &str, _req: &HttpRequest) -> Result<HttpResponse> {
+		match reqwest::get(self.public_file_url(path)?).await {
+			Ok(v) if v.status().is_success() => Ok(HttpResponse::Ok().streaming(v.bytes_stream())),
+			_ => Ok(HttpResponse::NotFound().finish()),
 		}
-
-		Ok(())
 	}
 }
 
@@ -279,6 +258,92 @@ async fn upload_file_multi_try(
 	Err(prev_error.unwrap())
 }
 
+/// https://www.backblaze.com/b2/docs/large_files.html
+async fn upload_large_file(
+	file_name: &str,
+	file: &SpoolFile,
+	auth: &B2Authorization,
+	bucket_id: &str,
+) -> Result<()> {
+	let large_file = auth.start_large_file(bucket_id, file_name).await??;
+
+	match upload_large_file_parts(&large_file.file_id, file, auth).await {
+		Ok(part_sha1_array) => {
+			auth.finish_large_file(&large_file.file_id, part_sha1_array).await??;
+
+			Ok(())
+		}
+
+		Err(e) => {
+			if let Err(e) = auth.cancel_large_file(&large_file.file_id).await {
+				eprintln!("cancel_large_file: {}", e);
+			}
+
+			Err(e)
+		}
+	}
+}
+
+async fn upload_large_file_parts(
+	file_id: &str,
+	file: &SpoolFile,
+	auth: &B2Authorization,
+) -> Result<Vec<String>> {
+	let part_size = auth.recommended_part_size.max(auth.absolute_minimum_part_size) as u64;
+
+	let mut reader = tokio::fs::File::open(file.path()).await?;
+
+	let mut part_sha1_array = Vec::new();
+
+	loop {
+		let mut part = Vec::new();
+		(&mut reader).take(part_size).read_to_end(&mut part).await?;
+
+		if part.is_empty() {
+			break;
+		}
+
+		let mut sha = Sha1::new();
+		sha.input(&part);
+		let sha = sha.result_str();
+
+		let part = Bytes::from(part);
+		let part_number = part_sha1_array.len() + 1;
+
+		let mut prev_error = None;
+
+		for _ in 0..5 {
+			let upload_url = match auth.get_upload_part_url(file_id).await {
+				Ok(v) => v,
+				Err(e) => {
+					prev_error = Some(e);
+					sleep(Duration::from_millis(1000)).await;
+					continue;
+				}
+			};
+
+			match auth.upload_part(&upload_url, part_number, part.clone(), &sha).await {
+				Ok(Err(error)) => prev_error = Some(error.into()),
+				Err(error) => prev_error = Some(error),
+				Ok(Ok(_)) => {
+					prev_error = None;
+					break;
+				}
+			}
+
+			sleep(Duration::from_millis(1000)).await;
+		}
+
+		if let Some(error) = prev_error {
+			return Err(error);
+		}
+
+		part_sha1_array.push(sha);
+	}
+
+	Ok(part_sha1_array)
+}
+
 async fn try_hide_file_multi(
 	file_path: &str,
 	auth: &B2Authorization,
@@ -338,7 +403,7 @@ impl Credentials {
 	}
 
 	pub fn auth_string(&self) -> String {
-		format!("Basic {}", b64encode(&self.id_key()))
+		format!("Basic {}", b64encode(self.id_key()))
 	}
 
 	pub async fn authorize(&self) -> Result<B2Authorization> {
@@ -470,6 +535,160 @@ impl B2Authorization {
 			Ok(Err(resp.json().await?))
 		}
 	}
+
+	/// https://www.backblaze.com/b2/docs/b2_start_large_file.html
+	pub async fn start_large_file(
+		&self,
+		bucket_id: &str,
+		file_name: &str,
+	) -> Result<std::result::Result<LargeFileResponse, JsonErrorStruct>> {
+		let client = reqwest::Client::new();
+
+		let body = json!({
+			"bucketId": bucket_id,
+			"fileName": encode_file_name(file_name),
+			"contentType": "b2/x-auto"
+		});
+
+		let resp = client
+			.post(format!("{}/b2api/v2/b2_start_large_file", self.api_url).as_str())
+			.header("Authorization", self.authorization_token.as_str())
+			.body(serde_json::to_string(&body)?)
+			.send()
+			.await?;
+
+		if resp.status().is_success() {
+			Ok(Ok(resp.json().await?))
+		} else {
+			Ok(Err(resp.json().await?))
+		}
+	}
+
+	/// https://www.backblaze.com/b2/docs/b2_get_upload_part_url.html
+	pub async fn get_upload_part_url(&self, file_id: &str) -> Result<UploadPartUrlResponse> {
+		let client = reqwest::Client::new();
+
+		let body = json!({ "fileId": file_id });
+
+		let resp = client
+			.post(format!("{}/b2api/v2/b2_get_upload_part_url", self.api_url).as_str())
+			.header("Authorization", self.authorization_token.as_str())
+			.body(serde_json::to_string(&body)?)
+			.send()
+			.await?;
+
+		if resp.status().is_success() {
+			Ok(resp.json().await?)
+		} else {
+			eprintln!("get_upload_part_url: {:?}", resp.text().await?);
+			Err(InternalError::B2GetUploadUrl.into())
+		}
+	}
+
+	/// https://www.backblaze.com/b2/docs/b2_upload_part.html
+	pub async fn upload_part(
+		&self,
+		upload: &UploadPartUrlResponse,
+		part_number: usize,
+		part: Bytes,
+		sha: &str,
+	) -> Result<std::result::Result<serde_json::Value, JsonErrorStruct>> {
+		let client = reqwest::Client::new();
+
+		let resp = client
+			.post(upload.upload_url.as_str())
+			.header("Authorization", upload.authorization_token.as_str())
+			.header("Content-Length", part.len())
+			.header("X-Bz-Part-Number", part_number)
+			.header("X-Bz-Content-Sha1", sha)
+			.body(part)
+			.send()
+			.await?;
+
+		if resp.status().is_success() {
+			Ok(Ok(resp.json().await?))
+		} else {
+			Ok(Err(resp.json().await?))
+		}
+	}
+
+	/// https://www.backblaze.com/b2/docs/b2_finish_large_file.html
+	pub async fn finish_large_file(
+		&self,
+		file_id: &str,
+		part_sha1_array: Vec<String>,
+	) -> Result<std::result::Result<serde_json::Value, JsonErrorStruct>> {
+		let client = reqwest::Client::new();
+
+		let body = json!({
+			"fileId": file_id,
+			"partSha1Array": part_sha1_array
+		});
+
+		let resp = client
+			.post(format!("{}/b2api/v2/b2_finish_large_file", self.api_url).as_str())
+			.header("Authorization", self.authorization_token.as_str())
+			.body(serde_json::to_string(&body)?)
+			.send()
+			.await?;
+
+		if resp.status().is_success() {
+			Ok(Ok(resp.json().await?))
+		} else {
+			Ok(Err(resp.json().await?))
+		}
+	}
+
+	/// https://www.backblaze.com/b2/docs/b2_cancel_large_file.html
+	pub async fn cancel_large_file(&self, file_id: &str) -> Result<()> {
+		let client = reqwest::Client::new();
+
+		let body = json!({ "fileId": file_id });
+
+		let resp = client
+			.post(format!("{}/b2api/v2/b2_cancel_large_file", self.api_url).as_str())
+			.header("Authorization", self.authorization_token.as_str())
+			.body(serde_json::to_string(&body)?)
+			.send()
+			.await?;
+
+		if resp.status().is_success() {
+			Ok(())
+		} else {
+			Err(resp.json::<JsonErrorStruct>().await?.into())
+		}
+	}
+
+	/// https://www.backblaze.com/b2/docs/b2_list_file_names.html
+	pub async fn list_file_names(
+		&self,
+		bucket_id: &str,
+		prefix: &str,
+		start_file_name: Option<String>,
+	) -> Result<std::result::Result<ListFileNamesResponse, JsonErrorStruct>> {
+		let client = reqwest::Client::new();
+
+		let body = json!({
+			"bucketId": bucket_id,
+			"prefix": prefix,
+			"delimiter": "/",
+			"startFileName": start_file_name,
+			"maxFileCount": 1000
+		});
+
+		let resp = client
+			.post(format!("{}/b2api/v2/b2_list_file_names", self.api_url).as_str())
+			.header("Authorization", self.authorization_token.as_str())
+			.body(serde_json::to_string(&body)?)
+			.send()
+			.await?;
+
+		if resp.status().is_success() {
+			Ok(Ok(resp.json().await?))
+		} else {
+			Ok(Err(resp.json().await?))
+		}
+	}
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -480,6 +699,33 @@ pub struct UploadUrlResponse {
 	upload_url: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadPartUrlResponse {
+	authorization_token: String,
+	file_id: String,
+	upload_url: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LargeFileResponse {
+	file_id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListFileNamesResponse {
+	files: Vec<FileNameResponse>,
+	next_file_name: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileNameResponse {
+	file_name: String,
+}
+
 #[derive(Debug, Serialize, Deserialize, thiserror::Error)]
 #[error("Backblaze Error:\nStatus: {status},\nCode: {code},\nMessage: {message}")]
 pub struct JsonErrorStruct {
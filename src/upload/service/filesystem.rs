@@ -1,117 +1,133 @@
+use std::io::ErrorKind;
 use std::path::PathBuf;
 
-use mongodb::bson::DateTime;
+use actix_files::NamedFile;
+use actix_web::{HttpRequest, HttpResponse};
+use async_trait::async_trait;
 
 use crate::config::ConfigServiceFileSystem;
-use crate::db::model::SlimImage;
 use crate::error::Result;
-use crate::upload::UploadProcessData;
-use crate::web::{ConfigDataService, WordDataService};
-use crate::{db, Filename};
+use crate::upload::spool::SpoolFile;
 
-use super::process_image_and_create_icon;
+use super::{StorageBackend, StorageDirectories};
 
 pub struct Service {
-	image_sub_directory: PathBuf,
-	icon_sub_directory: PathBuf,
+	upload_directory: PathBuf,
+	directories: StorageDirectories,
 }
 
 impl Service {
 	pub fn new(config: &ConfigServiceFileSystem) -> Result<Self> {
-		let mut image_sub_directory = PathBuf::from(config.upload_directory.clone());
-		let mut icon_sub_directory = image_sub_directory.clone();
-		icon_sub_directory.push(&config.icon_sub_directory);
-
-		image_sub_directory.push(&config.image_sub_directory);
-
 		Ok(Self {
-			image_sub_directory,
-			icon_sub_directory,
+			upload_directory: PathBuf::from(&config.upload_directory),
+			directories: StorageDirectories::new(&config.image_sub_directory, &config.icon_sub_directory),
 		})
 	}
 
-	pub async fn process_files(
-		&self,
-		upload_data: UploadProcessData,
-		config: &ConfigDataService,
-		words: &WordDataService,
-	) -> Result<SlimImage> {
-		let same_dirs = self.image_sub_directory == self.icon_sub_directory;
+	fn full_path(&self, path: &str) -> PathBuf {
+		let mut full_path = self.upload_directory.clone();
+		full_path.extend(path.split('/').filter(|v| !v.is_empty()));
+		full_path
+	}
+}
 
-		// Directory check
-		if tokio::fs::metadata(&self.image_sub_directory)
-			.await
-			.is_err()
-		{
-			tokio::fs::create_dir_all(&self.image_sub_directory).await?;
-		}
+#[async_trait(?Send)]
+impl StorageBackend for Service {
+	fn name(&self) -> &'static str {
+		"filesystem"
+	}
 
-		if !same_dirs && tokio::fs::metadata(&self.icon_sub_directory).await.is_err() {
-			tokio::fs::create_dir_all(&self.icon_sub_directory).await?;
+	fn directories(&self) -> &StorageDirectories {
+		&self.directories
+	}
+
+	async fn store(&self, path: &str, data: Vec<u8>) -> Result<()> {
+		let path = self.full_path(path);
+
+		// Directory check
+		if let Some(parent) = path.parent() {
+			if tokio::fs::metadata(parent).await.is_err() {
+				tokio::fs::create_dir_all(parent).await?;
+			}
 		}
 
-		let collection = db::get_images_collection();
+		tokio::fs::write(path, data).await?;
 
-		let file_name = upload_data.get_file_name(self.icon_sub_directory == self.image_sub_directory, words, &collection)
-			.await?;
+		Ok(())
+	}
 
+	async fn store_file(&self, path: &str, file: &SpoolFile) -> Result<()> {
+		let path = self.full_path(path);
 
-		let size_original = upload_data.file_data.len() as i64;
+		// Directory check
+		if let Some(parent) = path.parent() {
+			if tokio::fs::metadata(parent).await.is_err() {
+				tokio::fs::create_dir_all(parent).await?;
+			}
+		}
 
-		let data = process_image_and_create_icon(&file_name, upload_data.file_data, config).await?;
+		tokio::fs::copy(file.path(), path).await?;
 
-		let size_compressed = data.image_data.len() as i64;
+		Ok(())
+	}
 
-		{
-			let mut path = self.image_sub_directory.clone();
-			path.push(data.image_name);
+	async fn fetch(&self, path: &str) -> Result<Option<Vec<u8>>> {
+		match tokio::fs::read(self.full_path(path)).await {
+			Ok(v) => Ok(Some(v)),
+			Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+			Err(e) => Err(e.into()),
+		}
+	}
 
-			tokio::fs::write(path, data.image_data).await?;
+	async fn delete(&self, path: &str) -> Result<()> {
+		match tokio::fs::remove_file(self.full_path(path)).await {
+			Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+			_ => Ok(()),
 		}
+	}
 
-		{
-			let mut path = self.icon_sub_directory.clone();
-			path.push(if same_dirs {
-				format!("i{}", data.icon_name)
-			} else {
-				data.icon_name
-			});
+	async fn rename(&self, from: &str, to: &str) -> Result<()> {
+		let to = self.full_path(to);
 
-			tokio::fs::write(path, data.icon_data).await?;
+		// Directory check
+		if let Some(parent) = to.parent() {
+			if tokio::fs::metadata(parent).await.is_err() {
+				tokio::fs::create_dir_all(parent).await?;
+			}
 		}
 
-		Ok(SlimImage {
-			custom_name: None,
-			file_type: file_name.format_name()?.to_string(),
-			name: file_name.name,
-			size_original,
-			size_compressed,
-			is_edited: false,
-			is_favorite: false,
-			view_count: 0,
-			upload_date: DateTime::now(),
-		})
+		match tokio::fs::rename(self.full_path(from), to).await {
+			Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+			_ => Ok(()),
+		}
 	}
 
-	pub async fn hide_file(&self, file_name: Filename) -> Result<()> {
-		{
-			let mut path = self.image_sub_directory.clone();
-			path.push(file_name.as_filename()?);
+	async fn exists(&self, path: &str) -> Result<bool> {
+		Ok(tokio::fs::metadata(self.full_path(path)).await.is_ok())
+	}
 
-			tokio::fs::remove_file(path).await?;
-		}
+	async fn list(&self, directory: &str) -> Result<Vec<String>> {
+		let mut found = Vec::new();
 
-		{
-			let mut path = self.icon_sub_directory.clone();
-			path.push(if self.image_sub_directory == self.icon_sub_directory {
-				format!("i{}", file_name.name)
-			} else {
-				file_name.name
-			});
+		let mut entries = match tokio::fs::read_dir(self.full_path(directory)).await {
+			Ok(v) => v,
+			Err(e) if e.kind() == ErrorKind::NotFound => return Ok(found),
+			Err(e) => return Err(e.into()),
+		};
 
-			tokio::fs::remove_file(path).await?;
+		while let Some(entry) = entries.next_entry().await? {
+			if entry.file_type().await?.is_file() {
+				found.push(super::join_path(directory, &entry.file_name().to_string_lossy()));
+			}
 		}
 
-		Ok(())
+		Ok(found)
+	}
+
+	async fn serve(&self, path: &str, req: &HttpRequest) -> Result<HttpResponse> {
+		match NamedFile::open_async(self.full_path(path)).await {
+			Ok(v) => Ok(v.into_response(req)),
+			Err(_) => Ok(HttpResponse::NotFound().finish()),
+		}
 	}
 }
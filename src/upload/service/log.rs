@@ -1,67 +1,55 @@
-use mongodb::bson::DateTime;
+use async_trait::async_trait;
 
-use crate::db::model::SlimImage;
 use crate::error::Result;
-use crate::upload::UploadProcessData;
-use crate::web::{ConfigDataService, WordDataService};
-use crate::{db, Filename};
 
-use super::process_image_and_create_icon;
+use super::{StorageBackend, StorageDirectories};
 
-#[derive(Default)]
-pub struct Service;
+/// Doesn't store anything. Only outputs what would have been stored.
+pub struct Service {
+	directories: StorageDirectories,
+}
 
-impl Service {
-	pub async fn process_files(
-		&self,
-		upload_data: UploadProcessData,
-		config: &ConfigDataService,
-		words: &WordDataService,
-	) -> Result<SlimImage> {
-		let collection = db::get_images_collection();
+impl Default for Service {
+	fn default() -> Self {
+		Self {
+			directories: StorageDirectories::new("", ""),
+		}
+	}
+}
 
-		let file_name = upload_data.get_file_name(false, words, &collection)
-			.await?;
+#[async_trait(?Send)]
+impl StorageBackend for Service {
+	fn name(&self) -> &'static str {
+		"logging"
+	}
 
-		let size_original = upload_data.file_data.len() as i64;
+	fn directories(&self) -> &StorageDirectories {
+		&self.directories
+	}
 
-		let file_data = process_image_and_create_icon(&file_name, upload_data.file_data, config).await?;
+	async fn store(&self, path: &str, data: Vec<u8>) -> Result<()> {
+		println!("[LOG]: Storing File \"{}\" = {} bytes", path, data.len());
 
-		let size_compressed = file_data.image_data.len() as i64;
+		Ok(())
+	}
 
-		println!(
-			"[LOG]: User Uploaded Image UID: {}, IP: {}",
-			upload_data.user.id, upload_data.ip_addr
-		);
-		println!("[LOG]: \tImage original size: {} bytes", size_original);
-		println!("[LOG]: \tImage compressed size: {} bytes", size_compressed);
-		println!(
-			"[LOG]: \tImage Info: \"{}\" = {} bytes",
-			file_data.image_name,
-			file_data.image_data.len()
-		);
-		println!(
-			"[LOG]: \tIcon Info: \"i{}\" = {} bytes",
-			file_data.icon_name,
-			file_data.icon_data.len()
-		);
+	async fn fetch(&self, path: &str) -> Result<Option<Vec<u8>>> {
+		println!("[LOG]: Fetching File \"{}\"", path);
 
-		Ok(SlimImage {
-			custom_name: None,
-			file_type: file_name.format_name()?.to_string(),
-			name: file_name.name,
-			size_original,
-			size_compressed,
-			is_edited: false,
-			is_favorite: false,
-			view_count: 0,
-			upload_date: DateTime::now(),
-		})
+		Ok(None)
 	}
 
-	pub fn hide_file(&self, file_name: Filename) -> Result<()> {
-		println!("[LOG]: Removing File Name {:?}", file_name);
+	async fn delete(&self, path: &str) -> Result<()> {
+		println!("[LOG]: Removing File \"{}\"", path);
 
 		Ok(())
 	}
+
+	async fn exists(&self, _path: &str) -> Result<bool> {
+		Ok(false)
+	}
+
+	async fn list(&self, _directory: &str) -> Result<Vec<String>> {
+		Ok(Vec::new())
+	}
 }
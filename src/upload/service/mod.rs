@@ -1,139 +1,1096 @@
-use std::io::Cursor;
+use std::future::Future;
+use std::path::Path;
+use std::sync::Arc;
 
-use image::ImageFormat;
+use actix_web::{http::StatusCode, HttpRequest, HttpResponse};
+use async_trait::async_trait;
+use image::DynamicImage;
 
 use crate::{
 	Filename,
 	Result,
 	config::{
+		ConfigFeatureCompression,
 		ConfigServiceB2,
 		ConfigServiceFileSystem,
+		ConfigMetadataPolicy,
+		ConfigServiceS3,
 		ConfigServices
 	},
-	db::model::SlimImage,
-	feature::compress::compress_if_enabled,
+	db::{self, ImagesCollection, model::{Image, ImageAnimation, ImageThumbnail, JobStatus, ProcessingJob, SlimImage}},
+	error::{ConfigError, InternalError},
+	feature::{
+		animation::Animation,
+		compress::{compress_if_enabled, compression_for_user, convert_animation_if_smaller, encode_webp, transcode_if_smaller},
+		metadata,
+		resize::{VariantFormat, create_animated_thumbnails, create_thumbnails},
+	},
 	web::{
 		ConfigDataService,
+		ProcessingDataService,
 		WordDataService
 	}
 };
 
-use super::UploadProcessData;
+use super::{ProcessedImage, UploadProcessData};
+use super::pool::ProcessingPool;
+use super::spool::SpoolFile;
 
 pub mod b2;
 pub mod filesystem;
 pub mod log;
+pub mod s3;
+
+
+/// A place uploaded files can be stored in.
+///
+/// Paths are relative to the backend, use "/" as the separator and are built with [`StorageDirectories`].
+#[async_trait(?Send)]
+pub trait StorageBackend: Send + Sync {
+	/// Used when logging.
+	fn name(&self) -> &'static str;
+
+	/// Where the images and icons are stored inside of the backend.
+	fn directories(&self) -> &StorageDirectories;
+
+	async fn store(&self, path: &str, data: Vec<u8>) -> Result<()>;
+
+	/// Store an upload which was spooled to disk.
+	async fn store_file(&self, path: &str, file: &SpoolFile) -> Result<()> {
+		self.store(path, file.read().await?).await
+	}
+
+	/// Returns `None` if the file doesn't exist.
+	async fn fetch(&self, path: &str) -> Result<Option<Vec<u8>>>;
+
+	/// Deleting a file which doesn't exist isn't an error.
+	async fn delete(&self, path: &str) -> Result<()>;
+
+	/// Moving a file which doesn't exist isn't an error.
+	async fn rename(&self, from: &str, to: &str) -> Result<()> {
+		if let Some(data) = self.fetch(from).await? {
+			self.store(to, data).await?;
+			self.delete(from).await?;
+		}
 
-pub enum Service {
-	B2(b2::Service),
-	Log(log::Service),
-	FileSystem(filesystem::Service),
+		Ok(())
+	}
+
+	async fn exists(&self, path: &str) -> Result<bool>;
+
+	/// Lists the paths of the files inside of the directory.
+	async fn list(&self, directory: &str) -> Result<Vec<String>>;
+
+	/// Respond to a request for a stored file.
+	async fn serve(&self, path: &str, _req: &HttpRequest) -> Result<HttpResponse> {
+		match self.fetch(path).await? {
+			Some(data) => {
+				let content_type = Path::new(path)
+					.extension()
+					.and_then(|v| v.to_str())
+					.map(actix_files::file_extension_to_mime)
+					.unwrap_or(mime::APPLICATION_OCTET_STREAM);
+
+				Ok(HttpResponse::Ok().content_type(content_type).body(data))
+			}
+
+			None => Ok(HttpResponse::NotFound().finish()),
+		}
+	}
 }
 
-impl Service {
-	pub async fn pick_service_from_config(config: &ConfigServices) -> Result<Self> {
-		let enabled_count = [
-			config.logging.enabled,
-			config.filesystem.enabled,
-			config.b2.enabled,
-		]
-		.iter()
-		.filter(|v| **v)
-		.count();
 
-		if enabled_count == 0 {
-			panic!("Please enable a service.");
-		} else if enabled_count > 1 {
-			panic!("Only ONE service can be enabled at once currently.");
+#[derive(Debug, Clone)]
+pub struct StorageDirectories {
+	image_sub_directory: String,
+	icon_sub_directory: String,
+}
+
+impl StorageDirectories {
+	pub fn new<S: Into<String>>(image_sub_directory: S, icon_sub_directory: S) -> Self {
+		Self {
+			image_sub_directory: image_sub_directory.into(),
+			icon_sub_directory: icon_sub_directory.into(),
 		}
+	}
 
-		if config.logging.enabled {
-			println!("Service Logging Enabled");
-			return Ok(Self::new_log());
+	/// If both are the same then icons are prefixed with a LOWERCASE 'i' to differentiate it from its' original.
+	pub fn is_same(&self) -> bool {
+		self.image_sub_directory == self.icon_sub_directory
+	}
+
+	pub fn image_path(&self, image_name: &str) -> String {
+		join_path(&self.image_sub_directory, image_name)
+	}
+
+	pub fn icon_path(&self, icon_name: &str) -> String {
+		if self.is_same() {
+			join_path(&self.icon_sub_directory, &format!("i{}", icon_name))
+		} else {
+			join_path(&self.icon_sub_directory, icon_name)
 		}
+	}
+
+	/// Thumbnails other than the icon are kept in a directory per size.
+	pub fn thumbnail_path(&self, size: &str, file_name: &str) -> String {
+		join_path(&self.icon_sub_directory, &format!("{}/{}", size, file_name))
+	}
+
+	/// Resized variants are kept per image so they can be removed alongside it.
+	pub fn variant_directory(&self, image_name: &str) -> String {
+		join_path(&self.image_sub_directory, &format!("variants/{}", image_name))
+	}
+
+	/// Originals are kept separate so they're never served as the image.
+	pub fn original_path(&self, image_name: &str) -> String {
+		join_path(&self.image_sub_directory, &format!("originals/{}", image_name))
+	}
+
+	/// The files of deleted images are moved here so they aren't served until they're restored.
+	pub fn hidden_path(&self, path: &str) -> String {
+		join_path("hidden", path)
+	}
+
+	pub fn image_sub_directory(&self) -> &str {
+		&self.image_sub_directory
+	}
+
+	pub fn icon_sub_directory(&self) -> &str {
+		&self.icon_sub_directory
+	}
+}
+
+fn join_path(directory: &str, file_name: &str) -> String {
+	let directory = directory.trim_matches('/');
+
+	if directory.is_empty() {
+		file_name.to_string()
+	} else {
+		format!("{}/{}", directory, file_name)
+	}
+}
+
+
+/// The thumbnail size which is stored and served as the icon.
+pub const ICON_THUMBNAIL_SIZE: &str = "icon";
+
+
+/// A file stored inside of every backend.
+#[derive(Debug, Clone)]
+pub enum StoredFile {
+	Image(String),
+	Icon(String),
+	/// (size, file name)
+	Thumbnail(String, String),
+	/// A resized variant of an image. (image name without format, variant file name)
+	Variant(String, String),
+	/// The untouched upload of a compressed image.
+	Original(String),
+	/// A file of a deleted image.
+	Hidden(Box<StoredFile>),
+}
+
+impl StoredFile {
+	/// The "icon" size is stored as the icon.
+	pub fn thumbnail(image_name: &str, thumbnail: &ImageThumbnail) -> Self {
+		if thumbnail.size == ICON_THUMBNAIL_SIZE {
+			Self::Icon(thumbnail.file_name(image_name))
+		} else {
+			Self::Thumbnail(thumbnail.size.clone(), thumbnail.file_name(image_name))
+		}
+	}
+
+	pub fn path(&self, directories: &StorageDirectories) -> String {
+		match self {
+			Self::Image(name) => directories.image_path(name),
+			Self::Icon(name) => directories.icon_path(name),
+			Self::Thumbnail(size, name) => directories.thumbnail_path(size, name),
+			Self::Variant(name, variant) => join_path(&directories.variant_directory(name), variant),
+			Self::Original(name) => directories.original_path(name),
+			Self::Hidden(file) => directories.hidden_path(&file.path(directories)),
+		}
+	}
+
+	pub fn hidden(self) -> Self {
+		Self::Hidden(Box::new(self))
+	}
+
+	/// Every file of the image other than its' variants.
+	pub fn of_image(image: &Image) -> Result<Vec<Self>> {
+		let mut files = vec![Self::Image(image.get_file_name()?.as_filename()?)];
+
+		if let Some(original) = image.original_file_name() {
+			files.push(Self::Original(original));
+		}
+
+		if image.thumbnails.is_empty() {
+			files.push(Self::Icon(format!("{}.png", image.name)));
+		} else {
+			files.extend(image.thumbnails.iter().map(|v| Self::thumbnail(&image.name, v)));
+		}
+
+		Ok(files)
+	}
+}
+
+
+/// Uploads are written to the primary backend and then replicated to the rest in the background.
+#[derive(Clone)]
+pub struct Service {
+	primary: Arc<dyn StorageBackend>,
+	replicas: Vec<Arc<dyn StorageBackend>>,
+	/// Wait for the replicas instead of writing to them in the background.
+	wait_for_replicas: bool,
+}
+
+impl Service {
+	pub fn new<B: StorageBackend + 'static>(backend: B) -> Self {
+		Self {
+			primary: Arc::new(backend),
+			replicas: Vec::new(),
+			wait_for_replicas: false,
+		}
+	}
+
+	pub fn with_replica<B: StorageBackend + 'static>(mut self, backend: B) -> Self {
+		self.replicas.push(Arc::new(backend));
+		self
+	}
+
+	/// Used by commands since they'd exit before the background writes are finished.
+	pub fn waiting_for_replicas(mut self) -> Self {
+		self.wait_for_replicas = true;
+		self
+	}
+
+	pub async fn pick_service_from_config(config: &ConfigServices) -> Result<Self> {
+		let mut backends: Vec<Arc<dyn StorageBackend>> = Vec::new();
 
 		if config.filesystem.enabled {
 			println!("Service Filesystem Enabled");
-			return Self::new_file_system(&config.filesystem);
+			backends.push(Arc::new(filesystem::Service::new(&config.filesystem)?));
 		}
 
 		if config.b2.enabled {
 			println!("Service B2 Enabled");
-			return Self::new_b2(&config.b2).await;
+			backends.push(Arc::new(b2::Service::new(&config.b2).await?));
+		}
+
+		if config.s3.enabled {
+			println!("Service S3 Enabled");
+			backends.push(Arc::new(s3::Service::new(&config.s3)?));
 		}
 
-		unreachable!()
+		if config.logging.enabled {
+			println!("Service Logging Enabled");
+			backends.push(Arc::new(log::Service::default()));
+		}
+
+		if backends.is_empty() {
+			return Err(ConfigError::Invalid(vec![String::from("No services are enabled. Please enable one in \"services\".")]).into());
+		}
+
+		// Defaults to the first enabled service.
+		let primary_index = if config.primary.is_empty() {
+			0
+		} else {
+			backends.iter()
+				.position(|v| v.name() == config.primary)
+				.ok_or_else(|| ConfigError::Invalid(vec![format!(r#""services.primary" is "{}" which isn't enabled."#, config.primary)]))?
+		};
+
+		let primary = backends.remove(primary_index);
+
+		println!("Primary Service: {}", primary.name());
+
+		Ok(Self {
+			primary,
+			replicas: backends,
+			wait_for_replicas: false,
+		})
 	}
 
 	pub async fn new_b2(config: &ConfigServiceB2) -> Result<Self> {
-		Ok(Self::B2(b2::Service::new(config).await?))
+		Ok(Self::new(b2::Service::new(config).await?))
 	}
 
 	pub fn new_log() -> Self {
-		Self::Log(log::Service)
+		Self::new(log::Service::default())
+	}
+
+	pub fn new_s3(config: &ConfigServiceS3) -> Result<Self> {
+		Ok(Self::new(s3::Service::new(config)?))
 	}
 
 	pub fn new_file_system(config: &ConfigServiceFileSystem) -> Result<Self> {
-		Ok(Self::FileSystem(filesystem::Service::new(config)?))
+		Ok(Self::new(filesystem::Service::new(config)?))
+	}
+
+	pub fn primary(&self) -> &dyn StorageBackend {
+		&*self.primary
+	}
+
+	pub fn replicas(&self) -> impl Iterator<Item = &dyn StorageBackend> {
+		self.replicas.iter().map(|v| &**v)
+	}
+
+	/// Image names have to be corrected if any of the backends store images and icons in the same directory.
+	pub fn image_icon_same_dir(&self) -> bool {
+		self.primary.directories().is_same() || self.replicas.iter().any(|v| v.directories().is_same())
+	}
+
+	/// Stores the file in the primary backend and replicates it in the background.
+	pub async fn store(&self, file: StoredFile, data: Vec<u8>) -> Result<()> {
+		self.primary.store(&file.path(self.primary.directories()), data.clone()).await?;
+
+		for replica in &self.replicas {
+			let replica = replica.clone();
+			let path = file.path(replica.directories());
+			let data = data.clone();
+
+			self.replicate(async move {
+				if let Err(e) = replica.store(&path, data).await {
+					eprintln!("Replicating \"{}\" to {} Error: {}", path, replica.name(), e);
+				}
+			}).await;
+		}
+
+		Ok(())
+	}
+
+	/// Stores the spooled file in the primary backend and replicates copies of it in the background.
+	pub async fn store_file(&self, file: StoredFile, spool: &SpoolFile) -> Result<()> {
+		self.primary.store_file(&file.path(self.primary.directories()), spool).await?;
+
+		for replica in &self.replicas {
+			let replica = replica.clone();
+			let path = file.path(replica.directories());
+
+			let spool = match spool.duplicate().await {
+				Ok(v) => v,
+				Err(e) => {
+					eprintln!("Replicating \"{}\" to {} Error: {}", path, replica.name(), e);
+					continue;
+				}
+			};
+
+			self.replicate(async move {
+				if let Err(e) = replica.store_file(&path, &spool).await {
+					eprintln!("Replicating \"{}\" to {} Error: {}", path, replica.name(), e);
+				}
+			}).await;
+		}
+
+		Ok(())
+	}
+
+	async fn replicate<F: Future<Output = ()> + 'static>(&self, write: F) {
+		if self.wait_for_replicas {
+			write.await;
+		} else {
+			actix_web::rt::spawn(write);
+		}
+	}
+
+	/// Fetches the file from the primary backend. Falls back to the replicas if it fails.
+	pub async fn fetch(&self, file: StoredFile) -> Result<Option<Vec<u8>>> {
+		let primary_error = match self.primary.fetch(&file.path(self.primary.directories())).await {
+			Ok(Some(v)) => return Ok(Some(v)),
+			Ok(None) => None,
+			Err(e) => Some(e),
+		};
+
+		for replica in &self.replicas {
+			match replica.fetch(&file.path(replica.directories())).await {
+				Ok(Some(v)) => return Ok(Some(v)),
+				Ok(None) => (),
+				Err(e) => eprintln!("Fetching from {} Error: {}", replica.name(), e),
+			}
+		}
+
+		match primary_error {
+			Some(e) => Err(e),
+			None => Ok(None),
+		}
+	}
+
+	/// Deletes the file from every backend. Only errors from the primary backend are returned.
+	pub async fn delete(&self, file: StoredFile) -> Result<()> {
+		for replica in &self.replicas {
+			if let Err(e) = replica.delete(&file.path(replica.directories())).await {
+				eprintln!("Deleting from {} Error: {}", replica.name(), e);
+			}
+		}
+
+		self.primary.delete(&file.path(self.primary.directories())).await
+	}
+
+	/// Moves the file in every backend. Only errors from the primary backend are returned.
+	pub async fn rename(&self, from: StoredFile, to: StoredFile) -> Result<()> {
+		for replica in &self.replicas {
+			let path = from.path(replica.directories());
+
+			if let Err(e) = replica.rename(&path, &to.path(replica.directories())).await {
+				eprintln!("Moving \"{}\" in {} Error: {}", path, replica.name(), e);
+			}
+		}
+
+		self.primary.rename(&from.path(self.primary.directories()), &to.path(self.primary.directories())).await
+	}
+
+	/// Serves the file from the primary backend. Falls back to the replicas if it can't.
+	pub async fn serve(&self, file: StoredFile, req: &HttpRequest) -> Result<HttpResponse> {
+		let primary_resp = self.primary.serve(&file.path(self.primary.directories()), req).await;
+
+		match &primary_resp {
+			Ok(resp) if resp.status() != StatusCode::NOT_FOUND => return primary_resp,
+			Ok(_) => (),
+			Err(e) => eprintln!("Serving from {} Error: {}", self.primary.name(), e),
+		}
+
+		for replica in &self.replicas {
+			match replica.serve(&file.path(replica.directories()), req).await {
+				Ok(resp) if resp.status() != StatusCode::NOT_FOUND => return Ok(resp),
+				Ok(_) => (),
+				Err(e) => eprintln!("Serving from {} Error: {}", replica.name(), e),
+			}
+		}
+
+		primary_resp
 	}
 
 	pub async fn process_files(
 		&self,
-		upload_data: UploadProcessData,
+		mut upload_data: UploadProcessData,
 		config: &ConfigDataService,
 		words: &WordDataService,
+		pool: &ProcessingDataService,
 	) -> Result<SlimImage> {
-		match self {
-			Self::Log(v) => {
-				v.process_files(upload_data, config, words)
-					.await
+		let collection = db::get_images_collection();
+
+		let mut file_name = upload_data.get_file_name(self.image_icon_same_dir(), words, &collection)
+			.await?;
+
+		if config.upload.processing.background {
+			return self.queue_files(upload_data, file_name, config, pool, &collection).await;
+		}
+
+		let size_original = upload_data.file.len() as i64;
+
+		let compression = compression_for_user(&config.features.compression, &upload_data.user);
+
+		// Copied before it's replaced by the compressed file.
+		let original = if compression.keep_original {
+			Some((file_name.clone(), upload_data.file.duplicate().await?))
+		} else {
+			None
+		};
+
+		let (file_name, file, file_data) = {
+			let config = config.clone();
+			let mut file = upload_data.file;
+
+			pool.run(async move {
+				let file_data = process_image_and_create_thumbnails(&mut file_name, &mut file, &config, &compression, false).await?;
+
+				Ok((file_name, file, file_data))
+			}).await?
+		};
+
+		upload_data.file = file;
+
+		let size_compressed = upload_data.file.len() as i64;
+
+		self.store_file(StoredFile::Image(file_data.image_name), &upload_data.file).await?;
+
+		let mut thumbnails = Vec::new();
+
+		for (thumbnail, data) in file_data.thumbnails {
+			self.store(StoredFile::thumbnail(&file_name.name, &thumbnail), data).await?;
+			thumbnails.push(thumbnail);
+		}
+
+		let original_file_type = match original {
+			Some((original_name, original)) => self.store_original(&original_name, &original, &file_name, &upload_data.file).await?,
+			None => None,
+		};
+
+		let new_image = upload_data.create_image(
+			file_name,
+			size_original,
+			ProcessedImage {
+				size_compressed,
+				thumbnails,
+				animation: file_data.animation,
+				compression_error: file_data.compression_error,
+				original_file_type,
+			},
+			&collection,
+		).await?;
+
+		Ok(new_image.into())
+	}
+
+	/// Stores the upload with the metadata policy applied and processes it in the background.
+	async fn queue_files(
+		&self,
+		mut upload_data: UploadProcessData,
+		file_name: Filename,
+		config: &ConfigDataService,
+		pool: &ProcessingDataService,
+		collection: &ImagesCollection,
+	) -> Result<SlimImage> {
+		let slot = pool.reserve_background()?;
+
+		let size_original = upload_data.file.len() as i64;
+
+		let compression = compression_for_user(&config.features.compression, &upload_data.user);
+
+		// Copied before the metadata is removed.
+		let original = if compression.keep_original {
+			Some(upload_data.file.duplicate().await?)
+		} else {
+			None
+		};
+
+		// The link is returned right away so the metadata can't wait for the job.
+		let (file_name, file) = {
+			let config = config.clone();
+			let compression = compression.clone();
+			let mut file = upload_data.file;
+
+			pool.run(async move {
+				apply_metadata_policy(&file_name, &mut file, &config, &compression).await?;
+
+				Ok((file_name, file))
+			}).await?
+		};
+
+		upload_data.file = file;
+
+		self.store_file(StoredFile::Image(file_name.as_filename()?), &upload_data.file).await?;
+
+		let new_image = upload_data.create_image(
+			file_name.clone(),
+			size_original,
+			ProcessedImage {
+				size_compressed: size_original,
+				thumbnails: Vec::new(),
+				animation: None,
+				compression_error: None,
+				original_file_type: None,
+			},
+			collection,
+		).await?;
+
+		let mut job = ProcessingJob::new(new_image.name.clone());
+		job.insert(&db::get_jobs_collection()).await?;
+
+		let service = self.clone();
+		let config = config.clone();
+		let pool = pool.clone();
+
+		actix_web::rt::spawn(async move {
+			let _slot = slot;
+
+			let upload = JobUpload {
+				file_name,
+				file: upload_data.file,
+				original,
+				compression,
+			};
+
+			if let Err(e) = service.run_job(&mut job, upload, &config, &pool).await {
+				fail_job(&mut job, e).await;
 			}
-			Self::B2(v) => {
-				v.process_files(upload_data, config, words)
-					.await
+		});
+
+		Ok(new_image.into())
+	}
+
+	/// Requeues the jobs which weren't finished when the website stopped. They're run one after another.
+	///
+	/// The stored file is processed again. Originals aren't kept for them since they were never stored.
+	pub async fn resume_jobs(&self, config: &ConfigDataService, pool: &ProcessingDataService) -> Result<usize> {
+		let jobs = db::model::find_unfinished_jobs(&db::get_jobs_collection()).await?;
+		let count = jobs.len();
+
+		let service = self.clone();
+		let config = config.clone();
+		let pool = pool.clone();
+
+		actix_web::rt::spawn(async move {
+			for mut job in jobs {
+				if let Err(e) = service.resume_job(&mut job, &config, &pool).await {
+					fail_job(&mut job, e).await;
+				}
 			}
-			Self::FileSystem(v) => {
-				v.process_files(upload_data, config, words)
-					.await
+		});
+
+		Ok(count)
+	}
+
+	async fn resume_job(&self, job: &mut ProcessingJob, config: &ConfigDataService, pool: &ProcessingPool) -> Result<()> {
+		let image = match db::model::find_image_by_name(&job.image_name, &db::get_images_collection()).await? {
+			Some(v) if v.deleted.is_none() => v,
+			// Removed before it was processed.
+			_ => {
+				job.set_status(JobStatus::Completed, None, &db::get_jobs_collection()).await?;
+
+				return Ok(());
 			}
+		};
+
+		let file_name = image.get_file_name()?;
+
+		let data = self.fetch(StoredFile::Image(file_name.as_filename()?)).await?
+			.ok_or(InternalError::ImageDoesNotExist)?;
+
+		let user = match image.uploader_id {
+			Some(id) => db::model::find_user_by_id(id, &db::get_users_collection()).await?,
+			None => None,
+		};
+
+		let mut compression = match user {
+			Some(user) => compression_for_user(&config.features.compression, &user),
+			None => config.features.compression.clone(),
+		};
+
+		compression.keep_original = false;
+
+		let upload = JobUpload {
+			file_name,
+			file: SpoolFile::from_data(&config.upload.spool_directory, &data).await?,
+			original: None,
+			compression,
+		};
+
+		self.run_job(job, upload, config, pool).await
+	}
+
+	/// Swaps the stored file with the processed one and stores the thumbnails.
+	async fn run_job(
+		&self,
+		job: &mut ProcessingJob,
+		upload: JobUpload,
+		config: &ConfigDataService,
+		pool: &ProcessingPool,
+	) -> Result<()> {
+		let jobs = db::get_jobs_collection();
+
+		job.set_status(JobStatus::Processing, None, &jobs).await?;
+
+		let JobUpload { mut file_name, mut file, original, compression } = upload;
+
+		let (file_name, file, file_data) = {
+			let config = config.clone();
+
+			pool.queue(async move {
+				let file_data = process_image_and_create_thumbnails(&mut file_name, &mut file, &config, &compression, true).await?;
+
+				Ok((file_name, file, file_data))
+			}).await?
+		};
+
+		let collection = db::get_images_collection();
+
+		// Removed while it was being processed.
+		let is_removed = db::model::find_image_by_name(&job.image_name, &collection).await?
+			.is_none_or(|v| v.deleted.is_some());
+
+		if !is_removed {
+			self.store_file(StoredFile::Image(file_data.image_name), &file).await?;
+
+			for (thumbnail, data) in &file_data.thumbnails {
+				self.store(StoredFile::thumbnail(&job.image_name, thumbnail), data.clone()).await?;
+			}
+
+			// The format is kept so only the data changes.
+			let original_file_type = match original {
+				Some(original) => self.store_original(&file_name, &original, &file_name, &file).await?,
+				None => None,
+			};
+
+			db::model::set_image_processed(
+				&job.image_name,
+				&ProcessedImage {
+					size_compressed: file.len() as i64,
+					thumbnails: file_data.thumbnails.into_iter().map(|(v, _)| v).collect(),
+					animation: file_data.animation,
+					compression_error: file_data.compression_error,
+					original_file_type,
+				},
+				&collection,
+			).await?;
 		}
+
+		job.set_status(JobStatus::Completed, None, &jobs).await?;
+
+		Ok(())
 	}
 
-	pub async fn hide_file(&self, file_name: Filename) -> Result<()> {
-		match self {
-			Self::Log(v) => v.hide_file(file_name),
-			Self::B2(v) => v.hide_file(file_name).await,
-			Self::FileSystem(v) => v.hide_file(file_name).await,
+	/// Reruns processing on a stored image and replaces it, its' thumbnails and the document.
+	///
+	/// The kept original is used if it has the same format. Nothing is stored if `dry_run` is set.
+	pub async fn reprocess_image(
+		&self,
+		image: &Image,
+		config: &ConfigDataService,
+		compression: &ConfigFeatureCompression,
+		dry_run: bool,
+	) -> Result<ProcessedImage> {
+		let mut file_name = image.get_file_name()?;
+
+		let source = match image.reprocessable_original() {
+			Some(original) => StoredFile::Original(original),
+			None => StoredFile::Image(file_name.as_filename()?),
+		};
+
+		let data = self.fetch(source).await?.ok_or(InternalError::ImageDoesNotExist)?;
+
+		let mut file = SpoolFile::from_data(&config.upload.spool_directory, &data).await?;
+
+		// The format is kept so existing links stay valid.
+		let file_data = process_image_and_create_thumbnails(&mut file_name, &mut file, config, compression, true).await?;
+
+		let processed = ProcessedImage {
+			size_compressed: file.len() as i64,
+			thumbnails: file_data.thumbnails.iter().map(|(v, _)| v.clone()).collect(),
+			animation: file_data.animation,
+			compression_error: file_data.compression_error,
+			original_file_type: image.original_file_type.clone(),
+		};
+
+		if dry_run {
+			return Ok(processed);
 		}
+
+		self.store_file(StoredFile::Image(file_data.image_name), &file).await?;
+
+		for (thumbnail, data) in file_data.thumbnails {
+			self.store(StoredFile::thumbnail(&image.name, &thumbnail), data).await?;
+		}
+
+		// Sizes or formats which are no longer configured.
+		for thumbnail in &image.thumbnails {
+			if !processed.thumbnails.iter().any(|v| v.size == thumbnail.size && v.file_type == thumbnail.file_type) {
+				self.delete(StoredFile::thumbnail(&image.name, thumbnail)).await?;
+			}
+		}
+
+		self.delete_variants(&image.name).await;
+
+		db::model::set_image_processed(&image.name, &processed, &db::get_images_collection()).await?;
+
+		Ok(processed)
+	}
+
+	/// Only stored if processing changed it. Returns the format of the stored original.
+	async fn store_original(
+		&self,
+		original_name: &Filename,
+		original: &SpoolFile,
+		file_name: &Filename,
+		file: &SpoolFile,
+	) -> Result<Option<String>> {
+		let original_format = original_name.format_name()?;
+
+		if original_format == file_name.format_name()? && original.len() == file.len() {
+			return Ok(None);
+		}
+
+		self.store_file(StoredFile::Original(original_name.as_filename()?), original).await?;
+
+		Ok(Some(original_format.to_string()))
+	}
+
+	/// Moves the files of a deleted image out of the way so they aren't served. Its' variants are removed.
+	pub async fn hide_files(&self, image: &Image) -> Result<()> {
+		for file in StoredFile::of_image(image)? {
+			self.rename(file.clone(), file.hidden()).await?;
+		}
+
+		self.delete_variants(&image.name).await;
+
+		Ok(())
 	}
+
+	/// Moves the files of a deleted image back once it's restored.
+	pub async fn unhide_files(&self, image: &Image) -> Result<()> {
+		for file in StoredFile::of_image(image)? {
+			self.rename(file.clone().hidden(), file).await?;
+		}
+
+		Ok(())
+	}
+
+	/// Removes every file of the image whether it's hidden or not.
+	pub async fn remove_files(&self, image: &Image) -> Result<()> {
+		for file in StoredFile::of_image(image)? {
+			self.delete(file.clone().hidden()).await?;
+			self.delete(file).await?;
+		}
+
+		self.delete_variants(&image.name).await;
+
+		Ok(())
+	}
+
+	/// Removes every cached variant of the image. Failures are only logged.
+	pub async fn delete_variants(&self, name: &str) {
+		for backend in std::iter::once(&self.primary).chain(self.replicas.iter()) {
+			let paths = match backend.list(&backend.directories().variant_directory(name)).await {
+				Ok(v) => v,
+				Err(e) => {
+					eprintln!("Listing variants from {} Error: {}", backend.name(), e);
+					continue;
+				}
+			};
+
+			for path in paths {
+				if let Err(e) = backend.delete(&path).await {
+					eprintln!("Deleting variant from {} Error: {}", backend.name(), e);
+				}
+			}
+		}
+	}
+
+	/// Serve a file requested from the image host.
+	pub async fn serve_image(&self, name: &str, req: &HttpRequest) -> Result<HttpResponse> {
+		self.serve(StoredFile::Image(name.to_string()), req).await
+	}
+
+	/// Serve a file requested from the icon host. Icons are always requested with a prefixed 'i'.
+	pub async fn serve_icon(&self, name: &str, req: &HttpRequest) -> Result<HttpResponse> {
+		let name = name.strip_prefix('i').unwrap_or(name);
+
+		self.serve(StoredFile::Icon(name.to_string()), req).await
+	}
+
+	/// Serve the untouched upload of a compressed image.
+	pub async fn serve_original(&self, image: &Image, req: &HttpRequest) -> Result<HttpResponse> {
+		match image.original_file_name() {
+			Some(name) => self.serve(StoredFile::Original(name), req).await,
+			None => Ok(HttpResponse::NotFound().finish()),
+		}
+	}
+
+	/// Serve a thumbnail other than the icon. eg. `/medium/{name}`
+	pub async fn serve_thumbnail(&self, size: &str, name: &str, req: &HttpRequest) -> Result<HttpResponse> {
+		self.serve(StoredFile::Thumbnail(size.to_string(), name.to_string()), req).await
+	}
+}
+
+/// An upload waiting to be processed by a background job.
+struct JobUpload {
+	file_name: Filename,
+	file: SpoolFile,
+	/// The untouched upload if it's kept.
+	original: Option<SpoolFile>,
+	compression: ConfigFeatureCompression,
+}
+
+async fn fail_job(job: &mut ProcessingJob, error: crate::error::Error) {
+	eprintln!("Processing Job for \"{}\" Error: {}", job.image_name, error);
+
+	if let Err(e) = job.set_status(JobStatus::Failed, Some(error.to_string()), &db::get_jobs_collection()).await {
+		eprintln!("Updating Job for \"{}\" Error: {}", job.image_name, e);
+	}
+}
+
+/// The formats the metadata policy applies to.
+fn has_metadata(file_name: &Filename) -> bool {
+	VariantFormat::from_file_name(file_name).is_some() || file_name.is_format(mime::IMAGE_GIF)
 }
 
-pub async fn process_image_and_create_icon(
+/// Applies the orientation to the pixels since the strip policy removes it. Returns the upright image if it was rotated.
+///
+/// Lossless images stay lossless. Lossy ones are only rotated if they're going to be compressed anyway.
+async fn rotate_for_strip(
 	file_name: &Filename,
-	image_data: Vec<u8>,
+	file: &mut SpoolFile,
+	image: &DynamicImage,
+	orientation: u32,
+	compression: &ConfigFeatureCompression,
+) -> Result<Option<DynamicImage>> {
+	let upright = metadata::apply_orientation(image, orientation).into_owned();
+
+	let data = match VariantFormat::from_file_name(file_name) {
+		Some(VariantFormat::Png) => VariantFormat::Png.encode(&upright, compression.quality)?,
+		Some(VariantFormat::WebP) if metadata::is_lossless_webp(&file.read().await?) => encode_webp(&upright, true, compression.quality),
+		// Compressed right after so close to nothing is lost.
+		Some(VariantFormat::Jpeg) if compression.enabled && !compression.lossless_only => VariantFormat::Jpeg.encode(&upright, 100.0)?,
+		_ => return Ok(None),
+	};
+
+	file.replace(&data).await?;
+
+	Ok(Some(upright))
+}
+
+/// Only applies the metadata policy to the spooled image. Used before an upload is stored unprocessed.
+pub async fn apply_metadata_policy(
+	file_name: &Filename,
+	file: &mut SpoolFile,
+	config: &ConfigDataService,
+	compression: &ConfigFeatureCompression,
+) -> Result<()> {
+	let policy = config.features.metadata;
+
+	if policy == ConfigMetadataPolicy::Keep || !has_metadata(file_name) {
+		return Ok(());
+	}
+
+	let original_data = file.read().await?;
+
+	let mut orientation = metadata::read_orientation(&original_data);
+
+	if policy == ConfigMetadataPolicy::Strip && orientation != 1 {
+		let image = image::load_from_memory(&original_data)?;
+
+		if rotate_for_strip(file_name, file, &image, orientation, compression).await?.is_some() {
+			orientation = 1;
+		}
+	}
+
+	let image_data = metadata::apply_policy(file.read().await?, &original_data, policy, orientation)?;
+
+	file.replace(&image_data).await
+}
+
+/// Creates the icon and compresses the spooled image in place.
+///
+/// The format of the file name is changed if the image was transcoded. Transcoding and converting GIFs are skipped if `keep_format` is set.
+pub async fn process_image_and_create_thumbnails(
+	file_name: &mut Filename,
+	file: &mut SpoolFile,
 	config: &ConfigDataService,
+	compression: &ConfigFeatureCompression,
+	keep_format: bool,
 ) -> Result<FileData> {
-	let image = image::load_from_memory(&image_data)?;
-	let icon = image.thumbnail_exact(128, 128);
+	let mut image = image::io::Reader::open(file.path())?
+		.with_guessed_format()?
+		.decode()?;
 
-	let mut icon_data = Cursor::new(Vec::new());
-	icon.write_to(&mut icon_data, ImageFormat::Png)?;
+	let metadata_policy = config.features.metadata;
 
-	let image_data = compress_if_enabled(file_name, image_data, image, config)?;
+	// Only formats which can contain metadata. The original is kept to restore the metadata after compressing.
+	let original_data = if has_metadata(file_name) {
+		Some(file.read().await?)
+	} else {
+		None
+	};
+
+	let mut orientation = original_data.as_deref().map(metadata::read_orientation).unwrap_or(1);
+
+	// The orientation is removed with the rest of the metadata so it's applied to the pixels.
+	if metadata_policy == ConfigMetadataPolicy::Strip && orientation != 1 {
+		if let Some(upright) = rotate_for_strip(file_name, file, &image, orientation, compression).await? {
+			image = upright;
+			orientation = 1;
+		}
+	}
+
+	let animation = if file_name.is_format(mime::IMAGE_GIF) {
+		Animation::decode_gif(file.path())?
+	} else {
+		None
+	};
+
+	let mut compression_error = None;
+
+	let thumbnails = match animation.as_ref().filter(|_| config.features.thumbnails.animated) {
+		Some(animation) => create_animated_thumbnails(animation, &config.features.thumbnails)?,
+		None => create_thumbnails(&metadata::apply_orientation(&image, orientation), &config.features.thumbnails)?,
+	};
+
+	if let Some(animation) = animation.as_ref() {
+		if !keep_format {
+			convert_animation_if_smaller(file_name, file, animation, compression).await?;
+		}
+	} else if compression.enabled {
+		// Only read the image into memory if it's going to be compressed.
+		let image_data = file.read().await?;
+		let size_original = image_data.len();
+
+		// The original is stored uncompressed if it fails. It's recorded so it can be reprocessed later.
+		let image_data = match compress_if_enabled(file_name, image_data, &image, compression) {
+			Ok(v) => v,
+			Err(e) => {
+				eprintln!("Compressing \"{}\" Error: {:?}", file_name.as_filename()?, e);
+
+				compression_error = Some(e.to_string());
+
+				file.read().await?
+			}
+		};
+
+		let image_data = if keep_format {
+			image_data
+		} else {
+			transcode_if_smaller(file_name, image_data, &image, orientation, compression)?
+		};
+
+		if image_data.len() != size_original {
+			file.replace(&image_data).await?;
+		}
+	}
+
+	if let Some(original_data) = original_data {
+		if metadata_policy != ConfigMetadataPolicy::Keep || compression.enabled {
+			let image_data = metadata::apply_policy(file.read().await?, &original_data, metadata_policy, orientation)?;
+
+			file.replace(&image_data).await?;
+		}
+	}
 
 	Ok(FileData {
 		image_name: file_name.as_filename()?,
-		image_data,
-
-		icon_name: format!("{}.png", file_name.name),
-		icon_data: icon_data.into_inner(),
+		thumbnails,
+		animation: animation.map(|v| ImageAnimation {
+			frame_count: v.frame_count() as i32,
+			duration: v.duration() as i64,
+		}),
+		compression_error,
 	})
 }
 
 pub struct FileData {
 	image_name: String,
-	image_data: Vec<u8>,
+	thumbnails: Vec<(ImageThumbnail, Vec<u8>)>,
+	animation: Option<ImageAnimation>,
+	compression_error: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn directory_paths() {
+		let same = StorageDirectories::new("uploads/", "uploads/");
+
+		assert_eq!("uploads/Name123.png", same.image_path("Name123.png"));
+		assert_eq!("uploads/iName123.png", same.icon_path("Name123.png"));
+
+		let split = StorageDirectories::new("", "icons");
 
-	icon_name: String,
-	icon_data: Vec<u8>,
+		assert_eq!("Name123.png", split.image_path("Name123.png"));
+		assert_eq!("icons/Name123.png", split.icon_path("Name123.png"));
+
+		assert_eq!(
+			"variants/Name123/640x480_cover.webp",
+			StoredFile::Variant("Name123".into(), "640x480_cover.webp".into()).path(&split)
+		);
+
+		assert_eq!(
+			"uploads/medium/Name123.jpeg",
+			StoredFile::Thumbnail("medium".into(), "Name123.jpeg".into()).path(&same)
+		);
+
+		assert_eq!("uploads/originals/Name123.png", StoredFile::Original("Name123.png".into()).path(&same));
+
+		assert_eq!("hidden/uploads/iName123.png", StoredFile::Icon("Name123.png".into()).hidden().path(&same));
+		assert_eq!("hidden/Name123.png", StoredFile::Image("Name123.png".into()).hidden().path(&split));
+	}
 }
@@ -0,0 +1,121 @@
+use std::path::{Path, PathBuf};
+
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+
+use crate::{Result, words::gen_uuid};
+
+/// An upload written to a temporary file instead of being held in memory.
+///
+/// The file is removed once this is dropped.
+pub struct SpoolFile {
+	path: PathBuf,
+	len: u64,
+}
+
+impl SpoolFile {
+	pub async fn create<P: AsRef<Path>>(spool_directory: P) -> Result<(Self, File)> {
+		let spool_directory = spool_directory.as_ref();
+
+		if tokio::fs::metadata(spool_directory).await.is_err() {
+			tokio::fs::create_dir_all(spool_directory).await?;
+		}
+
+		let path = spool_directory.join(gen_uuid());
+
+		let file = File::create(&path).await?;
+
+		Ok((
+			Self {
+				path,
+				len: 0,
+			},
+			file
+		))
+	}
+
+	/// Creates a spool file containing the data.
+	pub async fn from_data<P: AsRef<Path>>(spool_directory: P, data: &[u8]) -> Result<Self> {
+		let (mut this, mut file) = Self::create(spool_directory).await?;
+
+		this.write_chunk(&mut file, data).await?;
+		file.flush().await?;
+
+		Ok(this)
+	}
+
+	/// Copies the file into a new spool file.
+	pub async fn duplicate(&self) -> Result<Self> {
+		let path = self.path.with_file_name(gen_uuid());
+
+		tokio::fs::copy(&self.path, &path).await?;
+
+		Ok(Self {
+			path,
+			len: self.len,
+		})
+	}
+
+	pub async fn write_chunk(&mut self, file: &mut File, chunk: &[u8]) -> Result<()> {
+		file.write_all(chunk).await?;
+		self.len += chunk.len() as u64;
+
+		Ok(())
+	}
+
+	/// Replace the contents of the file.
+	pub async fn replace(&mut self, data: &[u8]) -> Result<()> {
+		tokio::fs::write(&self.path, data).await?;
+		self.len = data.len() as u64;
+
+		Ok(())
+	}
+
+	pub async fn read(&self) -> Result<Vec<u8>> {
+		Ok(tokio::fs::read(&self.path).await?)
+	}
+
+	pub fn path(&self) -> &Path {
+		&self.path
+	}
+
+	pub fn len(&self) -> u64 {
+		self.len
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len == 0
+	}
+}
+
+impl Drop for SpoolFile {
+	fn drop(&mut self) {
+		if let Err(e) = std::fs::remove_file(&self.path) {
+			eprintln!("Unable to remove spool file {:?}: {}", self.path, e);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	#![allow(clippy::unwrap_used)]
+
+	use super::*;
+
+	#[tokio::test]
+	async fn removed_on_drop() {
+		let directory = tempfile::tempdir().unwrap();
+
+		let spool = SpoolFile::from_data(directory.path(), b"image").await.unwrap();
+		let copy = spool.duplicate().await.unwrap();
+
+		assert_eq!(5, copy.len());
+		assert_eq!(b"image".to_vec(), copy.read().await.unwrap());
+
+		let path = spool.path().to_path_buf();
+		drop(spool);
+
+		assert!(!path.exists());
+		assert!(copy.path().exists());
+	}
+}
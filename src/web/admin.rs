@@ -0,0 +1,520 @@
+use actix_identity::Identity;
+use actix_web::{delete, get, http::header, post, web, Either, HttpResponse, Scope};
+use crypto::{hmac::Hmac, mac::Mac, sha2::Sha256, util::fixed_time_eq};
+use futures::TryStreamExt;
+use mongodb::{bson::{doc, oid::ObjectId}, options::FindOptions};
+use serde::{de::Error as _, Deserialize, Deserializer};
+
+use crate::{
+	Result,
+	config::Config,
+	db::{
+		get_gallery_collection, get_images_collection, get_reports_collection, get_users_collection,
+		model::{self, Image, ReportTarget, User, UserBan, UserRole},
+	},
+};
+
+use super::{get_slim_user_identity, media::HiddenCache, ConfigDataService, HandlebarsDataService, HiddenDataService, UploadDataService};
+
+
+/// How many of each are listed on the dashboard.
+const LIST_LIMIT: i64 = 50;
+
+
+pub fn register(scope: Scope) -> Scope {
+	scope
+		.service(dashboard)
+		.service(delete_image)
+		.service(restore_image)
+		.service(ban_user)
+		.service(unban_user)
+		.service(unban_user_form)
+		.service(set_role)
+		.service(dismiss_reports)
+		.service(remove_reported)
+}
+
+/// Users listed in `features.moderation.admins` are always admins.
+pub fn get_role(user: &User, config: &Config) -> UserRole {
+	if config.features.moderation.admins.contains(&user.id.to_hex()) {
+		UserRole::Admin
+	} else {
+		user.role
+	}
+}
+
+/// The user if their role is at least `role`.
+pub async fn get_staff_identity(identity: Identity, config: &Config, role: UserRole) -> Result<Option<User>> {
+	let user = match get_slim_user_identity(identity) {
+		Some(v) => v,
+		None => return Ok(None),
+	};
+
+	let user = model::find_user_by_id(user.id, &get_users_collection()).await?;
+
+	Ok(user.filter(|v| get_role(v, config) >= role))
+}
+
+/// Sent with every dashboard form. Signed with the session secret so it can't be guessed by other websites.
+fn csrf_token(user: &User, config: &Config) -> String {
+	let mut hmac = Hmac::new(Sha256::new(), config.session_secret.as_bytes());
+	hmac.input(b"admin-csrf:");
+	hmac.input(user.unique_id.as_bytes());
+
+	base64::encode_config(hmac.result().code(), base64::URL_SAFE_NO_PAD)
+}
+
+fn is_valid_csrf(user: &User, config: &Config, token: &str) -> bool {
+	fixed_time_eq(csrf_token(user, config).as_bytes(), token.as_bytes())
+}
+
+/// The dashboard forms which only contain the CSRF token.
+#[derive(Deserialize)]
+struct CsrfForm {
+	csrf: String,
+}
+
+/// The staff member if they're allowed and the token is valid.
+async fn get_staff_form_identity(identity: Identity, config: &Config, role: UserRole, csrf: &str) -> Result<Option<User>> {
+	Ok(get_staff_identity(identity, config, role).await?
+		.filter(|v| is_valid_csrf(v, config, csrf)))
+}
+
+/// Redirects to the dashboard which shows the message.
+fn back_to_dashboard(message: &str) -> HttpResponse {
+	let message = url::form_urlencoded::byte_serialize(message.as_bytes()).collect::<String>();
+
+	HttpResponse::Found()
+		.append_header((header::LOCATION, format!("/admin?message={}", message)))
+		.finish()
+}
+
+
+#[derive(Deserialize)]
+struct DashboardQuery {
+	message: Option<String>,
+}
+
+#[get("/admin")]
+async fn dashboard(
+	identity: Identity,
+	query: web::Query<DashboardQuery>,
+	hb: HandlebarsDataService<'_>,
+	config: ConfigDataService,
+) -> Result<HttpResponse> {
+	let user = match get_staff_identity(identity, &config, UserRole::Moderator).await? {
+		Some(v) => v,
+		None => return Ok(HttpResponse::Unauthorized().body("Not Allowed.")),
+	};
+
+	let images = get_images_collection();
+
+	let recent_images = model::find_recent_images(doc! {}, LIST_LIMIT, &images).await?;
+	let hidden_images = model::find_recent_images(doc! { "hidden": { "$exists": true } }, LIST_LIMIT, &images).await?;
+
+	let users = get_users_collection()
+		.find(None, FindOptions::builder().sort(doc! { "join_date": -1 }).limit(LIST_LIMIT).build())
+		.await?
+		.try_collect::<Vec<_>>()
+		.await?;
+
+	let totals = model::storage_totals(&images).await?;
+
+	let reports = model::find_open_reports(LIST_LIMIT, &get_reports_collection()).await?;
+
+	let body = hb.render(
+		"admin/dashboard",
+		&json!({
+			"title": config.website.title,
+			"message": query.message,
+
+			"unique_id": user.unique_id,
+			"csrf": csrf_token(&user, &config),
+			"role": get_role(&user, &config).name(),
+			"is_admin": get_role(&user, &config) == UserRole::Admin,
+
+			"totals": {
+				"count": totals.count,
+				"size_original": format_size(totals.size_original),
+				"size_compressed": format_size(totals.size_compressed),
+				"size_saved": format_size(totals.size_original - totals.size_compressed),
+			},
+
+			"reports": reports.iter().map(|v| json!({
+				"target": v.target,
+				"name": v.name,
+				"url": match v.target {
+					ReportTarget::Image => format!("{}/{}", config.website.image_host_with_proto(), v.name),
+					ReportTarget::Gallery => format!("/g/{}", v.name),
+				},
+				"count": v.count,
+				"reasons": v.reasons.iter().filter(|v| !v.is_empty()).collect::<Vec<_>>(),
+				"last_reported": v.last_reported.to_rfc3339_string(),
+			})).collect::<Vec<_>>(),
+
+			"recent_images": recent_images.iter().map(|v| image_json(v, &config)).collect::<Vec<_>>(),
+			"hidden_images": hidden_images.iter().map(|v| image_json(v, &config)).collect::<Vec<_>>(),
+
+			"users": users.iter().map(|v| json!({
+				"id": v.id.to_hex(),
+				"unique_id": v.unique_id,
+				"name": v.twitter.as_ref().map(|v| v.username.as_str())
+					.or_else(|| v.passwordless.as_ref().map(|v| v.email.as_str()))
+				.or_else(|| v.google.as_ref().and_then(|v| v.email.as_deref().or(v.display_name.as_deref()))),
+				"role": get_role(v, &config).name(),
+				"image_count": v.image_count,
+				"join_date": v.join_date.to_rfc3339_string(),
+				"is_banned": v.is_banned,
+				"ban_reason": v.ban.as_ref().and_then(|v| v.reason.as_deref()),
+			})).collect::<Vec<_>>(),
+		}),
+	)?;
+
+	Ok(HttpResponse::Ok().body(body))
+}
+
+fn image_json(image: &Image, config: &Config) -> serde_json::Value {
+	json!({
+		"name": image.name,
+		"url": format!("{}/{}.{}", config.website.image_host_with_proto(), image.name, image.file_type),
+		"uploader_id": image.uploader_id.map(|v| v.to_hex()),
+		"upload_date": image.upload_date.to_rfc3339_string(),
+		"size": format_size(image.size_compressed),
+		"is_deleted": image.deleted.is_some(),
+		"hidden": image.hidden,
+	})
+}
+
+fn format_size(bytes: i64) -> String {
+	const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+
+	let mut size = bytes as f64;
+	let mut unit = 0;
+
+	while size.abs() >= 1024.0 && unit < UNITS.len() - 1 {
+		size /= 1024.0;
+		unit += 1;
+	}
+
+	format!("{:.1} {}", size, UNITS[unit])
+}
+
+
+/// The files are hidden until the image is restored or purged.
+#[post("/admin/image/{name}/delete")]
+async fn delete_image(
+	identity: Identity,
+	name: web::Path<String>,
+	form: web::Form<CsrfForm>,
+	service: UploadDataService,
+	config: ConfigDataService,
+	hidden: HiddenDataService,
+) -> Result<HttpResponse> {
+	if get_staff_form_identity(identity, &config, UserRole::Moderator, &form.csrf).await?.is_none() {
+		return Ok(HttpResponse::Unauthorized().body("Not Allowed."));
+	}
+
+	let collection = get_images_collection();
+
+	let image = match model::find_image_by_name(&name, &collection).await? {
+		Some(v) if v.deleted.is_none() => v,
+		_ => return Ok(back_to_dashboard("Unable to find Image.")),
+	};
+
+	service.hide_files(&image).await?;
+	image.delete_request(&collection).await?;
+
+	hidden.forget(&name);
+
+	Ok(back_to_dashboard(&format!("Deleted {}.", name)))
+}
+
+#[post("/admin/image/{name}/restore")]
+async fn restore_image(
+	identity: Identity,
+	name: web::Path<String>,
+	form: web::Form<CsrfForm>,
+	service: UploadDataService,
+	config: ConfigDataService,
+	hidden: HiddenDataService,
+) -> Result<HttpResponse> {
+	if get_staff_form_identity(identity, &config, UserRole::Moderator, &form.csrf).await?.is_none() {
+		return Ok(HttpResponse::Unauthorized().body("Not Allowed."));
+	}
+
+	let collection = get_images_collection();
+
+	let image = match model::find_image_by_name(&name, &collection).await? {
+		Some(v) if v.deleted.is_some() => v,
+		_ => return Ok(back_to_dashboard("Unable to find a deleted Image.")),
+	};
+
+	service.unhide_files(&image).await?;
+	image.restore_request(&collection).await?;
+
+	hidden.forget(&name);
+
+	Ok(back_to_dashboard(&format!("Restored {}.", name)))
+}
+
+
+/// Closes the reports and shows it again if it was hidden because of them.
+#[post("/admin/report/{target}/{name}/dismiss")]
+async fn dismiss_reports(
+	identity: Identity,
+	path: web::Path<(ReportTarget, String)>,
+	form: web::Form<CsrfForm>,
+	config: ConfigDataService,
+	hidden: HiddenDataService,
+) -> Result<HttpResponse> {
+	if get_staff_form_identity(identity, &config, UserRole::Moderator, &form.csrf).await?.is_none() {
+		return Ok(HttpResponse::Unauthorized().body("Not Allowed."));
+	}
+
+	let (target, name) = path.into_inner();
+
+	model::resolve_reports(target, &name, &get_reports_collection()).await?;
+	model::unhide_reported(target, &name).await?;
+
+	hidden.forget(&name);
+
+	Ok(back_to_dashboard(&format!("Dismissed the reports of {}.", name)))
+}
+
+/// Deletes the reported image or gallery and closes its' reports.
+#[post("/admin/report/{target}/{name}/remove")]
+async fn remove_reported(
+	identity: Identity,
+	path: web::Path<(ReportTarget, String)>,
+	form: web::Form<CsrfForm>,
+	service: UploadDataService,
+	config: ConfigDataService,
+	hidden: HiddenDataService,
+) -> Result<HttpResponse> {
+	if get_staff_form_identity(identity, &config, UserRole::Moderator, &form.csrf).await?.is_none() {
+		return Ok(HttpResponse::Unauthorized().body("Not Allowed."));
+	}
+
+	let (target, name) = path.into_inner();
+
+	match target {
+		ReportTarget::Image => {
+			let collection = get_images_collection();
+
+			if let Some(image) = model::find_image_by_name(&name, &collection).await?.filter(|v| v.deleted.is_none()) {
+				service.hide_files(&image).await?;
+				image.delete_request(&collection).await?;
+
+				hidden.forget(&name);
+			}
+		}
+
+		ReportTarget::Gallery => {
+			let collection = get_gallery_collection();
+
+			if let Some(gallery) = model::find_gallery_by_name(&name, &collection).await? {
+				gallery.delete(&collection).await?;
+			}
+		}
+	}
+
+	model::resolve_reports(target, &name, &get_reports_collection()).await?;
+
+	Ok(back_to_dashboard(&format!("Removed {}.", name)))
+}
+
+
+#[derive(Deserialize)]
+struct BanUser {
+	reason: Option<String>,
+	/// Permanent if unset.
+	#[serde(default, deserialize_with = "deserialize_days")]
+	days: Option<u32>,
+	/// Only needed for the dashboard form.
+	#[serde(default)]
+	csrf: String,
+}
+
+/// The dashboard form sends an empty string if unset.
+fn deserialize_days<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Option<u32>, D::Error> {
+	#[derive(Deserialize)]
+	#[serde(untagged)]
+	enum Days {
+		Number(u32),
+		Text(String),
+	}
+
+	match Option::<Days>::deserialize(deserializer)? {
+		Some(Days::Number(v)) => Ok(Some(v)),
+		Some(Days::Text(v)) if v.trim().is_empty() => Ok(None),
+		Some(Days::Text(v)) => v.trim().parse().map(Some).map_err(D::Error::custom),
+		None => Ok(None),
+	}
+}
+
+/// Accepts JSON or the dashboard form. Staff can only ban users with a lower role.
+#[post("/admin/user/{id}/ban")]
+async fn ban_user(
+	identity: Identity,
+	id: web::Path<String>,
+	body: Either<web::Json<BanUser>, web::Form<BanUser>>,
+	config: ConfigDataService,
+	hidden: HiddenDataService,
+) -> Result<HttpResponse> {
+	let staff = match get_staff_identity(identity, &config, UserRole::Moderator).await? {
+		Some(v) => v,
+		None => return Ok(HttpResponse::Unauthorized().body("Not Allowed.")),
+	};
+
+	let user = match model::find_user_by_id(ObjectId::parse_str(id.as_str())?, &get_users_collection()).await? {
+		Some(v) => v,
+		None => return Ok(HttpResponse::NotFound().body("Unable to find User.")),
+	};
+
+	if get_role(&user, &config) >= get_role(&staff, &config) {
+		return Ok(HttpResponse::Forbidden().body("Unable to ban a User with the same or higher role."));
+	}
+
+	let (BanUser { reason, days, csrf }, is_form) = match body {
+		Either::Left(v) => (v.into_inner(), false),
+		Either::Right(v) => (v.into_inner(), true),
+	};
+
+	// Other websites can't send JSON without being allowed by CORS.
+	if is_form && !is_valid_csrf(&staff, &config, &csrf) {
+		return Ok(HttpResponse::Unauthorized().body("Not Allowed."));
+	}
+
+	let ban = UserBan::new(reason.filter(|v| !v.trim().is_empty()), days, Some(get_role(&staff, &config)));
+
+	model::ban_user(user.id, &ban, &get_users_collection(), &get_images_collection()).await?;
+
+	hidden.clear();
+
+	if is_form {
+		Ok(back_to_dashboard(&format!("Banned {}.", user.unique_id)))
+	} else {
+		Ok(HttpResponse::Ok().json(ban))
+	}
+}
+
+/// Staff can only lift bans made by the same or a lower role.
+#[delete("/admin/user/{id}/ban")]
+async fn unban_user(
+	identity: Identity,
+	id: web::Path<String>,
+	config: ConfigDataService,
+	hidden: HiddenDataService,
+) -> Result<HttpResponse> {
+	let staff = match get_staff_identity(identity, &config, UserRole::Moderator).await? {
+		Some(v) => v,
+		None => return Ok(HttpResponse::Unauthorized().body("Not Allowed.")),
+	};
+
+	match unban(&id, get_role(&staff, &config), &hidden).await? {
+		Unban::Unbanned(_) => Ok(HttpResponse::Ok().body("Unbanned User.")),
+		Unban::NotAllowed => Ok(HttpResponse::Forbidden().body("Unable to lift a ban made by a higher role.")),
+		Unban::NotFound => Ok(HttpResponse::NotFound().body("Unable to find User.")),
+	}
+}
+
+/// Forms can't send DELETE requests.
+#[post("/admin/user/{id}/unban")]
+async fn unban_user_form(
+	identity: Identity,
+	id: web::Path<String>,
+	form: web::Form<CsrfForm>,
+	config: ConfigDataService,
+	hidden: HiddenDataService,
+) -> Result<HttpResponse> {
+	let staff = match get_staff_form_identity(identity, &config, UserRole::Moderator, &form.csrf).await? {
+		Some(v) => v,
+		None => return Ok(HttpResponse::Unauthorized().body("Not Allowed.")),
+	};
+
+	match unban(&id, get_role(&staff, &config), &hidden).await? {
+		Unban::Unbanned(unique_id) => Ok(back_to_dashboard(&format!("Unbanned {}.", unique_id))),
+		Unban::NotAllowed => Ok(back_to_dashboard("Unable to lift a ban made by a higher role.")),
+		Unban::NotFound => Ok(back_to_dashboard("Unable to find User.")),
+	}
+}
+
+enum Unban {
+	/// The unique id of the user.
+	Unbanned(String),
+	NotAllowed,
+	NotFound,
+}
+
+async fn unban(id: &str, role: UserRole, hidden: &HiddenCache) -> Result<Unban> {
+	let user = match model::find_user_by_id(ObjectId::parse_str(id)?, &get_users_collection()).await? {
+		Some(v) => v,
+		None => return Ok(Unban::NotFound),
+	};
+
+	if user.ban.as_ref().is_some_and(|v| !v.can_be_lifted_by(role)) {
+		return Ok(Unban::NotAllowed);
+	}
+
+	model::unban_user(user.id, &get_users_collection(), &get_images_collection()).await?;
+
+	hidden.clear();
+
+	Ok(Unban::Unbanned(user.unique_id))
+}
+
+
+#[derive(Deserialize)]
+struct SetRole {
+	role: UserRole,
+	csrf: String,
+}
+
+#[post("/admin/user/{id}/role")]
+async fn set_role(
+	identity: Identity,
+	id: web::Path<String>,
+	form: web::Form<SetRole>,
+	config: ConfigDataService,
+) -> Result<HttpResponse> {
+	if get_staff_form_identity(identity, &config, UserRole::Admin, &form.csrf).await?.is_none() {
+		return Ok(HttpResponse::Unauthorized().body("Not Allowed."));
+	}
+
+	let res = model::set_user_role(ObjectId::parse_str(id.as_str())?, form.role, &get_users_collection()).await?;
+
+	if res.matched_count == 0 {
+		Ok(back_to_dashboard("Unable to find User."))
+	} else {
+		Ok(back_to_dashboard(&format!("Changed the role to {}.", form.role.name())))
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	#![allow(clippy::unwrap_used)]
+
+	use super::*;
+
+	#[test]
+	fn ban_form() {
+		let ban: BanUser = serde_urlencoded::from_str("reason=spam&days=").unwrap();
+		assert_eq!((Some("spam"), None), (ban.reason.as_deref(), ban.days));
+
+		let ban: BanUser = serde_urlencoded::from_str("days=7").unwrap();
+		assert_eq!(Some(7), ban.days);
+
+		let ban: BanUser = serde_json::from_str(r#"{ "days": 7 }"#).unwrap();
+		assert_eq!(Some(7), ban.days);
+
+		assert!(serde_urlencoded::from_str::<BanUser>("days=week").is_err());
+	}
+
+	#[test]
+	fn sizes() {
+		assert_eq!("512.0 B", format_size(512));
+		assert_eq!("1.5 KB", format_size(1536));
+		assert_eq!("2.0 GB", format_size(2 * 1024 * 1024 * 1024));
+	}
+}
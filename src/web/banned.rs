@@ -0,0 +1,99 @@
+use std::{future::{ready, Ready}, rc::Rc};
+
+use actix_identity::{Identity, RequestIdentity};
+use actix_web::{
+	body::EitherBody,
+	dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform},
+	Error as ActixError, FromRequest, HttpResponse,
+};
+use futures::future::LocalBoxFuture;
+
+use crate::db::{get_users_collection, model::{self, SlimUser}};
+
+
+/// Logs out banned users and rejects their requests.
+///
+/// Their identity cookie stays valid otherwise so this looks up the user on each request.
+/// Requests for the exempt paths are never looked up.
+pub struct RejectBanned {
+	exempt: Rc<[&'static str]>,
+}
+
+impl RejectBanned {
+	/// Paths starting with any of these are let through. eg. Static files which don't use the identity.
+	pub fn exempting(paths: &[&'static str]) -> Self {
+		Self { exempt: paths.into() }
+	}
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RejectBanned
+where
+	S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = ActixError> + 'static,
+	B: 'static,
+{
+	type Response = ServiceResponse<EitherBody<B>>;
+	type Error = ActixError;
+	type Transform = RejectBannedMiddleware<S>;
+	type InitError = ();
+	type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+	fn new_transform(&self, service: S) -> Self::Future {
+		ready(Ok(RejectBannedMiddleware { service: Rc::new(service), exempt: self.exempt.clone() }))
+	}
+}
+
+
+pub struct RejectBannedMiddleware<S> {
+	service: Rc<S>,
+	exempt: Rc<[&'static str]>,
+}
+
+impl<S, B> Service<ServiceRequest> for RejectBannedMiddleware<S>
+where
+	S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = ActixError> + 'static,
+	B: 'static,
+{
+	type Response = ServiceResponse<EitherBody<B>>;
+	type Error = ActixError;
+	type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+	forward_ready!(service);
+
+	fn call(&self, req: ServiceRequest) -> Self::Future {
+		let service = self.service.clone();
+
+		if self.exempt.iter().any(|v| req.path().starts_with(v)) {
+			return Box::pin(async move { Ok(service.call(req).await?.map_into_left_body()) });
+		}
+
+		Box::pin(async move {
+			if let Some(message) = get_ban_message(&req).await? {
+				let (req, _) = req.into_parts();
+
+				Identity::from_request(&req, &mut Payload::None).await?.forget();
+
+				return Ok(ServiceResponse::new(req, HttpResponse::Forbidden().body(message)).map_into_right_body());
+			}
+
+			Ok(service.call(req).await?.map_into_left_body())
+		})
+	}
+}
+
+async fn get_ban_message(req: &ServiceRequest) -> crate::Result<Option<String>> {
+	let user = match req.get_identity().and_then(|v| serde_json::from_str::<SlimUser>(&v).ok()) {
+		Some(v) => v,
+		None => return Ok(None),
+	};
+
+	let mut user = match model::find_user_by_id(user.id, &get_users_collection()).await? {
+		Some(v) => v,
+		None => return Ok(None),
+	};
+
+	Ok(if user.check_ban().await? {
+		Some(user.ban_message())
+	} else {
+		None
+	})
+}
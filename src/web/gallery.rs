@@ -3,15 +3,23 @@ use actix_identity::Identity;
 use actix_web::{delete, get, http::header, post, web, HttpResponse};
 
 use crate::{
+	config::Config,
 	db::{
 		get_gallery_collection, get_images_collection,
-		model::{self, SlimImage},
+		model::{self, Gallery, SlimImage},
 	},
 	error::InternalError,
 	Result,
 };
 
-use super::{get_slim_user_identity, ConfigDataService, HandlebarsDataService, WordDataService};
+use super::{get_slim_user_identity, report, ConfigDataService, HandlebarsDataService, WordDataService};
+
+
+/// Hidden galleries are still shown to their owner.
+fn is_hidden_from(gallery: &Gallery, identity: Identity, config: &Config) -> bool {
+	gallery.hidden.is_some_and(|v| report::hidden_reasons(config).contains(&v))
+		&& get_slim_user_identity(identity).is_none_or(|v| v.id != gallery.user_id)
+}
 
 #[get("/gallery")]
 async fn home(
@@ -68,7 +76,17 @@ async fn gallery_new(
 }
 
 #[get("/g/{id}")]
-async fn item(hb: HandlebarsDataService<'_>, config: ConfigDataService) -> Result<HttpResponse> {
+async fn item(
+	gallery_id: web::Path<String>,
+	identity: Identity,
+	hb: HandlebarsDataService<'_>,
+	config: ConfigDataService,
+) -> Result<HttpResponse> {
+	if let Some(gallery) = model::find_gallery_by_name(&gallery_id, &get_gallery_collection()).await? {
+		if is_hidden_from(&gallery, identity, &config) {
+			return Ok(HttpResponse::NotFound().finish());
+		}
+	}
 
 	Ok(HttpResponse::Ok().body(hb.render(
 		"gallery/item",
@@ -210,7 +228,11 @@ async fn gallery_delete(
 }
 
 #[get("/g/{id}/list")]
-async fn gallery_image_list(gallery_id: web::Path<String>) -> Result<HttpResponse> {
+async fn gallery_image_list(
+	gallery_id: web::Path<String>,
+	identity: Identity,
+	config: ConfigDataService,
+) -> Result<HttpResponse> {
 	let (gallery_collection, images_collection) =
 		(get_gallery_collection(), get_images_collection());
 
@@ -219,9 +241,16 @@ async fn gallery_image_list(gallery_id: web::Path<String>) -> Result<HttpRespons
 		None => return Err(InternalError::GalleryDoesNotExist.into()),
 	};
 
+	if is_hidden_from(&gallery, identity, &config) {
+		return Ok(HttpResponse::NotFound().finish());
+	}
+
+	let reasons = report::hidden_reasons(&config);
+
 	let images = model::find_images_from_gallery(&gallery.images, &images_collection)
 		.await?
 		.into_iter()
+		.filter(|v| v.hidden.is_none_or(|v| !reasons.contains(&v)))
 		.map(SlimImage::from)
 		.collect::<Vec<_>>();
 
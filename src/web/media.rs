@@ -1,27 +1,186 @@
-use std::{path::PathBuf, str::FromStr};
+use std::{
+	collections::HashMap,
+	sync::{Mutex, PoisonError},
+	time::{Duration, Instant},
+};
 
-use actix_files::NamedFile;
 use actix_http::header;
+use actix_identity::Identity;
 use actix_service::ServiceFactory;
 use actix_web::{
 	dev::ServiceRequest,
 	guard, web, App, HttpRequest, HttpResponse,
 };
-use reqwest::Url;
 
-use crate::{config::Config, Result, error::Error};
+use crate::{
+	Result,
+	db::{get_images_collection, model},
+	error::Error,
+	feature::resize::{self, VariantQuery},
+	upload::service::ICON_THUMBNAIL_SIZE,
+};
+
+use super::{ConfigDataService, HiddenDataService, ProcessingDataService, UploadDataService, get_slim_user_identity, report};
+
+
+/// How long it's remembered whether an image is hidden. Changes made through other instances are seen after this.
+const HIDDEN_CACHE_DURATION: Duration = Duration::from_secs(60);
+/// Everything is forgotten once it's full.
+const HIDDEN_CACHE_SIZE: usize = 10_000;
+
+/// Remembers whether images are hidden so every request doesn't have to look up the image.
+#[derive(Default)]
+pub struct HiddenCache {
+	names: Mutex<HashMap<String, (bool, Instant)>>,
+}
+
+impl HiddenCache {
+	fn get(&self, name: &str) -> Option<bool> {
+		let names = self.names.lock().unwrap_or_else(PoisonError::into_inner);
+
+		names.get(name)
+			.filter(|(_, cached_at)| cached_at.elapsed() < HIDDEN_CACHE_DURATION)
+			.map(|(hidden, _)| *hidden)
+	}
+
+	fn insert(&self, name: String, hidden: bool) {
+		let mut names = self.names.lock().unwrap_or_else(PoisonError::into_inner);
+
+		if names.len() >= HIDDEN_CACHE_SIZE {
+			names.clear();
+		}
+
+		names.insert(name, (hidden, Instant::now()));
+	}
+
+	/// Looks the image up again on the next request. eg. Once it's deleted.
+	pub fn forget(&self, file_name: &str) {
+		let name = file_name.split('.').next().unwrap_or_default();
+
+		self.names.lock().unwrap_or_else(PoisonError::into_inner).remove(name);
+	}
+
+	/// Looks every image up again. eg. Once a user's images are hidden.
+	pub fn clear(&self) {
+		self.names.lock().unwrap_or_else(PoisonError::into_inner).clear();
+	}
+}
+
+
+/// Serves the original image unless a resized variant was requested.
+async fn serve_image_or_variant(
+	name: &str,
+	query: &VariantQuery,
+	identity: Identity,
+	service: &UploadDataService,
+	pool: &ProcessingDataService,
+	config: &ConfigDataService,
+	req: &HttpRequest,
+) -> Result<HttpResponse> {
+	if is_hidden(name, config, req).await? {
+		Ok(HttpResponse::NotFound().finish())
+	} else if query.is_original() {
+		serve_original(name, identity, service, req).await
+	} else if config.features.resize.enabled && !query.is_empty() {
+		resize::serve_variant(name, query, service, pool, config, req).await
+	} else {
+		service.serve_image(name, req).await
+	}
+}
+
+/// Only looked up if moderation hides anything. The files of deleted images are already hidden.
+async fn is_hidden(file_name: &str, config: &ConfigDataService, req: &HttpRequest) -> Result<bool> {
+	let reasons = report::hidden_reasons(config);
+
+	if reasons.is_empty() {
+		return Ok(false);
+	}
+
+	let name = file_name.split('.').next().unwrap_or_default();
+
+	let cache = req.app_data::<HiddenDataService>();
+
+	if let Some(hidden) = cache.and_then(|v| v.get(name)) {
+		return Ok(hidden);
+	}
+
+	let hidden = model::is_image_hidden(name, &reasons, &get_images_collection()).await?;
+
+	if let Some(cache) = cache {
+		cache.insert(name.to_string(), hidden);
+	}
+
+	Ok(hidden)
+}
+
+async fn serve_icon(name: &str, service: &UploadDataService, config: &ConfigDataService, req: &HttpRequest) -> Result<HttpResponse> {
+	if is_hidden(name.strip_prefix('i').unwrap_or(name), config, req).await? {
+		Ok(HttpResponse::NotFound().finish())
+	} else {
+		service.serve_icon(name, req).await
+	}
+}
+
+/// The untouched upload of a compressed image. Only served to its' uploader.
+pub async fn serve_original(name: &str, identity: Identity, service: &UploadDataService, req: &HttpRequest) -> Result<HttpResponse> {
+	let user = match get_slim_user_identity(identity) {
+		Some(v) => v,
+		None => return Ok(HttpResponse::Unauthorized().body("Not Logged in.")),
+	};
+
+	let name = name.split('.').next().unwrap_or_default();
+
+	let image = model::find_image_by_name(name, &get_images_collection()).await?
+		.filter(|v| v.uploader_id == Some(user.id) && v.deleted.is_none());
+
+	match image {
+		Some(image) => service.serve_original(&image, req).await,
+		None => Ok(HttpResponse::NotFound().finish()),
+	}
+}
+
+/// Only configured sizes are served. The icon is served through its own route.
+async fn thumbnail_route(
+	path: web::Path<(String, String)>,
+	service: UploadDataService,
+	config: ConfigDataService,
+	req: HttpRequest,
+) -> Result<HttpResponse> {
+	let (size, name) = path.into_inner();
+
+	if size == ICON_THUMBNAIL_SIZE || !config.features.thumbnails.sizes.contains_key(&size) || name.is_empty() || is_hidden(&name, &config, &req).await? {
+		Ok(HttpResponse::NotFound().finish())
+	} else {
+		service.serve_thumbnail(&size, &name, &req).await
+	}
+}
+
+/// The Host headers of the image and icon hosts. Checked on start so building the routes can't fail.
+#[derive(Clone)]
+pub struct MediaHosts {
+	image: header::HeaderValue,
+	icon: header::HeaderValue,
+}
 
-use super::ConfigDataService;
+impl MediaHosts {
+	pub fn new(image_url: &str, icon_url: &str) -> Result<Self> {
+		Ok(Self {
+			image: header::HeaderValue::from_str(image_url)
+				.map_err(|_| Error::ActixInvalidHeaderValue(image_url.to_string()))?,
+			icon: header::HeaderValue::from_str(icon_url)
+				.map_err(|_| Error::ActixInvalidHeaderValue(icon_url.to_string()))?,
+		})
+	}
+}
 
 // If both urls are the same then icons use LOWERCASE 'i' to differentiate it from its' original.
 pub fn create_services<T: ServiceFactory<ServiceRequest, Config = (), Error = actix_web::Error, InitError = ()>>(
 	app: App<T>,
-	image_url: String,
-	icon_url: String,
-	read: &Config,
-) -> Result<App<T>> {
-	let image_url_header = header::HeaderValue::from_str(&image_url)
-		.map_err(|_| Error::ActixInvalidHeaderValue(icon_url.to_string()))?;
+	hosts: MediaHosts,
+) -> App<T> {
+	let MediaHosts { image: image_url_header, icon: icon_url_header } = hosts;
+
+	let is_same = image_url_header == icon_url_header;
 
 	let image_factory = web::scope("").guard(guard::fn_guard(move |req| {
 		(|| -> Option<bool> {
@@ -31,130 +190,57 @@ pub fn create_services<T: ServiceFactory<ServiceRequest, Config = (), Error = ac
 		.unwrap_or_default()
 	}));
 
-	let apples = if image_url == icon_url {
-		if read.services.b2.enabled {
-			app.service(image_factory.route(
-				"/{name}",
-				web::to(|mut name: web::Path<String>, config: ConfigDataService| async move {
-					let url = {
-						let input = if name.starts_with('i') {
-							// We don't prepend 'i' if the icon dir is different than the image one.
-							if config.services.b2.icon_sub_directory != config.services.b2.image_sub_directory {
-								name.remove(0);
-							}
-
-							&config.services.b2.icon_sub_directory
-						} else {
-							&config.services.b2.image_sub_directory
-						};
-
-						Url::from_str(&config.services.b2.public_url)?
-							.join(&format!("{}/{}", input, &name))?
-					};
-
-					match reqwest::get(url).await {
-						Ok(v) => Result::Ok(HttpResponse::Ok().streaming(v.bytes_stream())),
-						Err(_) => Result::Ok(HttpResponse::NotFound().finish()),
-					}
-				}),
-			))
-		} else if read.services.filesystem.enabled {
-			app.service(image_factory.route(
-				"/{name}",
-				web::get().to(
-					|name: web::Path<String>, config: ConfigDataService, req: HttpRequest| async move {
-						if name.is_empty() {
-							Result::Ok(HttpResponse::NotFound().finish())
-						} else {
-							let mut path = PathBuf::new();
-							path.push(&config.services.filesystem.upload_directory);
-
-							if name.starts_with('i') {
-								path.push(&config.services.filesystem.icon_sub_directory);
-							} else {
-								path.push(&config.services.filesystem.image_sub_directory);
-							}
-
-							path.push(name.into_inner());
-
-							match NamedFile::open_async(path).await {
-								Ok(v) => Result::Ok(v.into_response(&req)),
-								Err(_) => Result::Ok(HttpResponse::NotFound().finish()),
-							}
-						}
-					},
-				),
-			))
-		} else {
-			app
-		}
-	} else {
-		async fn b2_image_route(name: web::Path<String>, config: ConfigDataService) -> Result<HttpResponse> {
-			let url = {
-				Url::from_str(&config.services.b2.public_url)?
-					.join(&format!(
-						"{}/{}",
-						&config.services.b2.image_sub_directory, &name
-					))?
-			};
-
-			match reqwest::get(url).await {
-				Ok(v) => Ok(HttpResponse::Ok().streaming(v.bytes_stream())),
-				Err(_) => Ok(HttpResponse::NotFound().finish()),
-			}
-		}
-
-		async fn b2_icon_route(name: web::Path<String>, config: ConfigDataService) -> Result<HttpResponse> {
-			let url = {
-				Url::from_str(&config.services.b2.public_url)?
-					.join(&format!(
-						"{}/{}",
-						&config.services.b2.icon_sub_directory, &name
-					))?
-			};
-
-			match reqwest::get(url).await {
-				Ok(v) => Ok(HttpResponse::Ok().streaming(v.bytes_stream())),
-				Err(_) => Ok(HttpResponse::NotFound().finish()),
+	if is_same {
+		async fn image_or_icon_route(
+			name: web::Path<String>,
+			query: web::Query<VariantQuery>,
+			identity: Identity,
+			service: UploadDataService,
+			pool: ProcessingDataService,
+			config: ConfigDataService,
+			req: HttpRequest,
+		) -> Result<HttpResponse> {
+			if name.is_empty() {
+				Ok(HttpResponse::NotFound().finish())
+			} else if name.starts_with('i') {
+				serve_icon(&name, &service, &config, &req).await
+			} else {
+				serve_image_or_variant(&name, &query, identity, &service, &pool, &config, &req).await
 			}
 		}
 
-		async fn fs_image_route(name: web::Path<String>, config: ConfigDataService, req: HttpRequest) -> Result<HttpResponse> {
+		app.service(image_factory.route(
+			"/{name}",
+			web::get().to(image_or_icon_route),
+		).route(
+			"/{size}/{name}",
+			web::get().to(thumbnail_route),
+		))
+	} else {
+		async fn image_route(
+			name: web::Path<String>,
+			query: web::Query<VariantQuery>,
+			identity: Identity,
+			service: UploadDataService,
+			pool: ProcessingDataService,
+			config: ConfigDataService,
+			req: HttpRequest,
+		) -> Result<HttpResponse> {
 			if name.is_empty() {
 				Ok(HttpResponse::NotFound().finish())
 			} else {
-				let mut path = PathBuf::new();
-				path.push(&config.services.filesystem.upload_directory);
-				path.push(&config.services.filesystem.image_sub_directory);
-				path.push(name.into_inner());
-
-				match NamedFile::open_async(path).await {
-					Ok(v) => Ok(v.into_response(&req)),
-					Err(_) => Ok(HttpResponse::NotFound().finish()),
-				}
+				serve_image_or_variant(&name, &query, identity, &service, &pool, &config, &req).await
 			}
 		}
 
-		async fn fs_icon_route(name: web::Path<String>, config: ConfigDataService, req: HttpRequest) -> Result<HttpResponse> {
+		async fn icon_route(name: web::Path<String>, service: UploadDataService, config: ConfigDataService, req: HttpRequest) -> Result<HttpResponse> {
 			if name.is_empty() {
 				Ok(HttpResponse::NotFound().finish())
 			} else {
-				let mut path = PathBuf::new();
-				path.push(&config.services.filesystem.upload_directory);
-				path.push(&config.services.filesystem.icon_sub_directory);
-				path.push(name.into_inner());
-
-				match NamedFile::open_async(path).await {
-					Ok(v) => Ok(v.into_response(&req)),
-					Err(_) => Ok(HttpResponse::NotFound().finish()),
-				}
+				serve_icon(&name, &service, &config, &req).await
 			}
 		}
 
-
-		let icon_url_header = header::HeaderValue::from_str(&icon_url)
-			.map_err(|_| Error::ActixInvalidHeaderValue(icon_url.to_string()))?;
-
 		let icon_factory = web::scope("").guard(guard::fn_guard(move |req| {
 			(|| -> Option<bool> {
 				let host = req.head().headers().get(header::HOST)?;
@@ -163,28 +249,38 @@ pub fn create_services<T: ServiceFactory<ServiceRequest, Config = (), Error = ac
 			.unwrap_or_default()
 		}));
 
-		if read.services.b2.enabled {
-			app.service(image_factory.route(
-				"/{name}",
-				web::to(b2_image_route),
-			))
-			.service(icon_factory.route(
-				"/{name}",
-				web::to(b2_icon_route),
-			))
-		} else if read.services.filesystem.enabled {
-			app.service(image_factory.route(
-				"/{name}",
-				web::get().to(fs_image_route),
-			))
-			.service(icon_factory.route(
-				"/{name}",
-				web::get().to(fs_icon_route),
-			))
-		} else {
-			app
-		}
-	};
+		app.service(image_factory.route(
+			"/{name}",
+			web::get().to(image_route),
+		))
+		.service(icon_factory.route(
+			"/{name}",
+			web::get().to(icon_route),
+		).route(
+			"/{size}/{name}",
+			web::get().to(thumbnail_route),
+		))
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn hidden_cache() {
+		let cache = HiddenCache::default();
+		assert_eq!(None, cache.get("Name123"));
+
+		cache.insert(String::from("Name123"), false);
+		assert_eq!(Some(false), cache.get("Name123"));
+
+		cache.forget("Name123.png");
+		assert_eq!(None, cache.get("Name123"));
 
-	Ok(apples)
-}
\ No newline at end of file
+		cache.insert(String::from("Name123"), true);
+		cache.clear();
+		assert_eq!(None, cache.get("Name123"));
+	}
+}
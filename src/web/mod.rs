@@ -3,12 +3,13 @@
 	clippy::unwrap_used,
 )]
 
-use std::convert::TryInto;
-
 use actix_identity::{CookieIdentityPolicy, Identity, IdentityService};
+use actix_web::dev::Payload;
 use actix_web::guard;
+use futures::future::{ready, Ready};
 use actix_web::web::Data;
 use futures::TryStreamExt;
+use tokio::io::AsyncWriteExt;
 use tokio::sync::Mutex;
 
 use handlebars::Handlebars;
@@ -20,32 +21,69 @@ use actix_web::{
 	middleware::Logger,
 	post,
 	web::{self, JsonConfig},
-	App, HttpRequest, HttpResponse, HttpServer,
+	App, FromRequest, HttpRequest, HttpResponse, HttpServer,
 };
 
 use mongodb::bson::{doc, Document};
 
 use crate::config::Config;
 use crate::db::get_users_collection;
-use crate::db::model::{find_user_by_id, SlimUser, UserId};
+use crate::flipstore::{FlipReader, FlipStore};
+use crate::db::model::{find_user_by_id, SlimUser, UserId, UserRole};
 use crate::upload::UploadProcessData;
 use crate::upload::image::UploadImageType;
+use crate::upload::pool::ProcessingPool;
+use crate::upload::spool::SpoolFile;
 use crate::upload::service::Service;
 use crate::{
-	db::{get_images_collection, model},
-	error::InternalError,
+	db::{get_images_collection, get_jobs_collection, model},
+	error::{Error, InternalError},
 	words, Result, WordManager,
 };
 
 pub mod gallery;
 pub mod media;
+pub mod admin;
+pub mod banned;
 pub mod profile;
+pub mod reload;
+pub mod report;
+
+/// The directories and files inside of "./app/frontend/public/www". Banned users aren't checked for them.
+const STATIC_PATHS: &[&str] = &[
+	"/css/",
+	"/gallery/",
+	"/img/",
+	"/js/",
+	"/1-bg-paint.jpg",
+	"/FAVICON.ico",
+	"/brand.png",
+	"/grey_2X.png",
+	"/image_background.jpg",
+	"/robots.txt",
+];
 
 // Services
 pub type UploadDataService = web::Data<Service>;
-pub type ConfigDataService = web::Data<Config>;
+/// A snapshot of the config when the request started.
+pub type ConfigDataService = FlipReader<Config>;
 pub type WordDataService = web::Data<Mutex<WordManager>>;
-pub type HandlebarsDataService<'a> = web::Data<Handlebars<'a>>;
+pub type HandlebarsDataService<'a> = FlipReader<Handlebars<'a>>;
+pub type ProcessingDataService = web::Data<ProcessingPool>;
+pub type HiddenDataService = web::Data<media::HiddenCache>;
+
+/// Reads the latest value from a `web::Data<FlipStore<D>>`.
+impl<D: Clone + 'static> FromRequest for FlipReader<D> {
+	type Error = actix_web::Error;
+	type Future = Ready<std::result::Result<Self, Self::Error>>;
+
+	fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+		ready(match req.app_data::<web::Data<FlipStore<D>>>() {
+			Some(store) => Ok(store.read()),
+			None => Err(actix_web::error::ErrorInternalServerError("Store is not registered.")),
+		})
+	}
+}
 
 pub fn get_slim_user_identity(identity: Identity) -> Option<model::SlimUser> {
 	let id = identity.identity()?;
@@ -72,6 +110,8 @@ async fn index(
 			"title": config.website.title,
 			"is_logged_in": is_logged_in,
 
+			"is_auth_google_enabled": config.auth.google.enabled,
+			"google_auth_path": config.auth.google.auth_path,
 			"is_auth_twitter_enabled": config.auth.twitter.enabled,
 			"twitter_auth_path": config.auth.twitter.auth_path,
 			"is_auth_passwordless_enabled": config.auth.passwordless.enabled,
@@ -117,6 +157,61 @@ async fn get_image_info(identity: Identity, path: web::Path<String>) -> Result<H
 	Ok(HttpResponse::Found().json(image))
 }
 
+/// Whether the upload has been compressed and thumbnailed yet.
+#[get("/image/{name}/status")]
+async fn get_image_status(identity: Identity, path: web::Path<String>) -> Result<HttpResponse> {
+	let image = match model::find_image_by_name(path.as_ref(), &get_images_collection()).await? {
+		Some(v) if v.deleted.is_none() => v,
+		_ => return Ok(HttpResponse::NotFound().body("Unable to find Image.")),
+	};
+
+	// Errors can contain internal details so only the uploader sees them.
+	let is_uploader = get_slim_user_identity(identity).is_some_and(|v| image.uploader_id == Some(v.id));
+
+	match model::find_job_by_image_name(path.as_ref(), &get_jobs_collection()).await? {
+		Some(job) if is_uploader => Ok(HttpResponse::Ok().json(json!({
+			"status": job.status,
+			"error": job.error,
+		}))),
+
+		Some(job) => Ok(HttpResponse::Ok().json(json!({
+			"status": job.status,
+		}))),
+
+		// Processed while uploading.
+		None => Ok(HttpResponse::Ok().json(json!({
+			"status": model::JobStatus::Completed,
+		}))),
+	}
+}
+
+/// Downloads the original upload of a compressed image. eg. `/image/Name123.png/original`
+#[get("/image/{name}/original")]
+async fn download_original(
+	identity: Identity,
+	path: web::Path<String>,
+	service: UploadDataService,
+	req: HttpRequest,
+) -> Result<HttpResponse> {
+	let file_name = path.into_inner();
+
+	let mut resp = media::serve_original(&file_name, identity, &service, &req).await?;
+
+	if resp.status().is_success() {
+		let disposition = header::ContentDisposition {
+			disposition: header::DispositionType::Attachment,
+			parameters: vec![header::DispositionParam::Filename(file_name.clone())],
+		};
+
+		let value = header::TryIntoHeaderValue::try_into_value(disposition)
+			.map_err(|_| Error::ActixInvalidHeaderValue(file_name))?;
+
+		resp.headers_mut().insert(header::CONTENT_DISPOSITION, value);
+	}
+
+	Ok(resp)
+}
+
 #[derive(Serialize, Deserialize)]
 struct UpdateImage {
 	favorite: Option<bool>,
@@ -170,6 +265,7 @@ async fn remove_image(
 	identity: Identity,
 	file_name: web::Path<String>,
 	service: UploadDataService,
+	hidden: HiddenDataService,
 ) -> Result<HttpResponse> {
 	let collection = get_images_collection();
 
@@ -191,12 +287,12 @@ async fn remove_image(
 		.await?;
 
 	if let Some(image) = res {
-		let file_name = image.get_file_name()?;
-
-		service.hide_file(file_name).await?;
+		service.hide_files(&image).await?;
 
 		let res = image.delete_request(&collection).await?;
 
+		hidden.forget(&file_name);
+
 		if res.modified_count == 0 {
 			Ok(HttpResponse::Unauthorized().body("Unable to delete image. Unmodified."))
 		} else {
@@ -214,6 +310,7 @@ async fn upload(
 	service: UploadDataService,
 	words: WordDataService,
 	config: ConfigDataService,
+	pool: ProcessingDataService,
 	identity: Identity,
 ) -> Result<HttpResponse> {
 	let is_gallery_upload = req
@@ -228,12 +325,16 @@ async fn upload(
 		.peer_addr()
 		.map_or_else(String::new, |c| c.to_string());
 
-	// TODO: Properly stream.
-	// Make a class to ensure both fields (image, uid) are there and proper.
+	// Banned session users were already rejected by `RejectBanned`.
+	let mut user = match get_slim_user_identity(identity) {
+		Some(u) => Some(u.upgrade().await?),
+		None => None,
+	};
+
+	// TODO: Make a class to ensure both fields (image, uid) are there and proper.
 
 	let mut content_type = None;
 	let mut image_data = None;
-	let mut uid = None;
 	let mut file_type = None;
 
 	while let Some(field) = multipart.try_next().await? {
@@ -242,12 +343,40 @@ async fn upload(
 		if disp.is_form_data() {
 			match disp.get_name() {
 				Some("image") => {
+					// The uid can be sent after the image. Their own limit is checked once they're known.
+					let max_file_size = match user.as_ref() {
+						Some(v) => config.upload.max_file_size_for(v.tier.as_deref()),
+						None => config.upload.largest_max_file_size(),
+					};
+
 					content_type = Some(field.content_type().to_string());
-					image_data = Some(get_file(field).await?);
+					image_data = Some(get_file(field, &config.upload.spool_directory, max_file_size).await?);
 				}
 
 				Some("uid") => {
-					uid = Some(get_uid(field).await?);
+					let user_id = get_uid(field).await?;
+
+					match find_user_by_id(UserId::UniqueId(user_id.trim().to_string()), &get_users_collection()).await? {
+						Some(mut v) => {
+							if v.check_ban().await? {
+								return Ok(HttpResponse::Forbidden().body(v.ban_message()));
+							}
+
+							user = Some(v);
+						}
+
+						None => {
+							println!("Unable to Find User By Unique ID");
+							let base_url = config.website.http_base_host.clone();
+
+							return Ok(HttpResponse::NotAcceptable()
+								.append_header((
+									header::LOCATION,
+									base_url + "error?type=Incorrect+Unique+ID",
+								))
+								.body("Incorrect Unique ID"));
+						}
+					}
 				}
 
 				Some("type") => {
@@ -281,7 +410,7 @@ async fn upload(
 		}
 	};
 
-	let file_data = match image_data {
+	let file = match image_data {
 		Some(v) => v,
 		None => {
 			println!("Missing Image Data");
@@ -293,46 +422,35 @@ async fn upload(
 		}
 	};
 
-	let user = match uid {
-		Some(user_id) => match find_user_by_id(UserId::UniqueId(user_id.trim().to_string()), &get_users_collection()).await? {
-			Some(v) => v,
-			None => {
-				println!("Unable to Find User By Unique ID");
-				let base_url = config.website.http_base_host.clone();
-
-				return Ok(HttpResponse::NotAcceptable()
-					.append_header((
-						header::LOCATION,
-						base_url + "error?type=Incorrect+Unique+ID",
-					))
-					.body("Incorrect Unique ID"));
-			}
-		},
-
-		None => match get_slim_user_identity(identity) {
-			Some(u) => u.upgrade().await?,
-			None => {
-				println!("Missing Unique ID");
-				let base_url = config.website.http_base_host.clone();
+	let user = match user {
+		Some(v) => v,
+		None => {
+			println!("Missing Unique ID");
+			let base_url = config.website.http_base_host.clone();
 
-				return Ok(HttpResponse::NotAcceptable()
-					.append_header((header::LOCATION, base_url + "error?type=Missing+Unique+ID"))
-					.body("Missing Unique ID"));
-			}
-		},
+			return Ok(HttpResponse::NotAcceptable()
+				.append_header((header::LOCATION, base_url + "error?type=Missing+Unique+ID"))
+				.body("Missing Unique ID"));
+		}
 	};
 
+	// The uid could've been sent after the image.
+	if file.len() > config.upload.max_file_size_for(user.tier.as_deref()) {
+		return Err(InternalError::UploadSizeTooLarge.into());
+	}
+
 	let slim_image = service
 		.process_files(
 			UploadProcessData {
 				user,
 				file_type,
-				file_data,
+				file,
 				content_type,
 				ip_addr
 			},
 			&config,
 			&words,
+			&pool,
 		)
 		.await?;
 
@@ -351,19 +469,34 @@ async fn upload(
 	}
 }
 
-pub async fn get_file(mut field: Field) -> Result<Vec<u8>> {
-	let mut value = Vec::new();
+/// Only admins can see how busy the server is.
+#[get("/metrics/processing")]
+async fn processing_metrics(
+	identity: Identity,
+	pool: ProcessingDataService,
+	config: ConfigDataService,
+) -> Result<HttpResponse> {
+	if admin::get_staff_identity(identity, &config, UserRole::Admin).await?.is_none() {
+		return Ok(HttpResponse::Unauthorized().body("Not Allowed."));
+	}
+
+	Ok(HttpResponse::Ok().json(pool.stats()))
+}
+
+pub async fn get_file(mut field: Field, spool_directory: &str, max_file_size: u64) -> Result<SpoolFile> {
+	let (mut spool, mut file) = SpoolFile::create(spool_directory).await?;
 
 	while let Some(bytes) = field.try_next().await? {
-		value.extend(bytes);
+		spool.write_chunk(&mut file, &bytes).await?;
 
-		// 10 MB MAX
-		if value.len() > 10 * 1048576 {
+		if spool.len() > max_file_size {
 			return Err(InternalError::UploadSizeTooLarge.into());
 		}
 	}
 
-	Ok(value)
+	file.flush().await?;
+
+	Ok(spool)
 }
 
 pub async fn get_uid(mut field: Field) -> Result<String> {
@@ -415,33 +548,44 @@ pub async fn init(config: Config, service: Service) -> Result<()> {
 	);
 
 	// Handlebars
-	let mut handlebars = Handlebars::new();
-	handlebars.set_dev_mode(config.debug);
-	handlebars
-		.register_templates_directory(".hbs", "./app/frontend/views")
-		.expect("Unable to register template directory");
-	let handlebars_ref = web::Data::new(handlebars);
+	let handlebars_ref = web::Data::new(FlipStore::new(reload::create_handlebars(&config)?));
 
+	let pool = web::Data::new(ProcessingPool::new(&config.upload.processing)?);
+	let hidden_cache = web::Data::new(media::HiddenCache::default());
 	let service = web::Data::new(service);
-	let config = web::Data::new(config);
+	let config_store = web::Data::new(FlipStore::new(config));
+
+	reload::watch(config_store.clone(), handlebars_ref.clone());
+
+	let config = config_store.read();
+
+	let resumed = service.resume_jobs(&config, &pool).await?;
+
+	if resumed != 0 {
+		println!("Resuming {} processing jobs.", resumed);
+	}
+
+	let base_host = config.website.http_base_host.clone();
+
+	let base_url_with_www = header::HeaderValue::from_str(&format!("www.{}", base_host))
+		.map_err(|_| Error::ActixInvalidHeaderValue(format!("www.{}", base_host)))?;
+	let base_url_non_www = header::HeaderValue::from_str(&base_host)
+		.map_err(|_| Error::ActixInvalidHeaderValue(base_host))?;
+
+	let media_hosts = media::MediaHosts::new(&config.website.http_image_host, &config.website.http_icon_host)?;
 
 	println!("Starting website.");
 
 	HttpServer::new(move || {
-		let config = config.clone();
+		// Only used to build the routes. Handlers read the latest config.
+		let config = config_store.read();
 
 		let session_key = config.session_secret.clone();
 
-		let base_url_with_www =
-			header::HeaderValue::from_str(&format!("www.{}", config.website.http_base_host))
-			.expect("WWW: Invalid Header Value");
-		let base_url_non_www = header::HeaderValue::from_str(&config.website.http_base_host)
-			.expect("Base Host Invalid Header Value");
+		let base_url_with_www = base_url_with_www.clone();
+		let base_url_non_www = base_url_non_www.clone();
 		let base_url_non_www_2 = base_url_non_www.clone(); // TODO: Remove.
 
-		let image_url = config.website.http_image_host.clone();
-		let icon_url = config.website.http_icon_host.clone();
-
 		let app = App::new()
 			// enable logger
 			.wrap(Logger::default())
@@ -449,23 +593,19 @@ pub async fn init(config: Config, service: Service) -> Result<()> {
 			.wrap(IdentityService::new(
 				CookieIdentityPolicy::new(session_key.as_bytes())
 					.name("auth")
-					.max_age(
-						chrono::Duration::days(365)
-							.to_std()
-							.unwrap()
-							.try_into()
-							.unwrap(),
-					)
+					.same_site(actix_web::cookie::SameSite::Lax)
+					.max_age(actix_web::cookie::time::Duration::days(365))
 					.secure(false),
 			))
 			.app_data(Data::new(Mutex::new(WordManager::default())))
 			.app_data(Data::new(JsonConfig::default().limit(4096)))
 			.app_data(service.clone())
-			.app_data(config.clone())
+			.app_data(pool.clone())
+			.app_data(hidden_cache.clone())
+			.app_data(config_store.clone())
 			.app_data(handlebars_ref.clone());
 
-		let app = media::create_services(app, image_url, icon_url, &*config)
-			.expect("Create Services Error");
+		let app = media::create_services(app, media_hosts.clone());
 
 		// Redirect off www
 		app.service(
@@ -500,6 +640,7 @@ pub async fn init(config: Config, service: Service) -> Result<()> {
 					.unwrap_or_default()
 				}))
 				.service(upload)
+				.service(processing_metrics)
 				.service(index)
 				.service(logout)
 				.service(profile::profile)
@@ -507,14 +648,21 @@ pub async fn init(config: Config, service: Service) -> Result<()> {
 				.service(profile::get_images)
 				.service(profile::get_settings)
 				.service(get_image_info)
+				.service(get_image_status)
+				.service(download_original)
 				.service(update_image)
 				.service(remove_image);
 
-			let scope = crate::feature::gallery::register(scope, &*config);
-			let scope = crate::auth::twitter::register(scope, &*config);
-			let scope = crate::auth::passwordless::register(scope, &*config);
+			let scope = admin::register(scope);
+			let scope = report::register(scope);
+			let scope = crate::feature::gallery::register(scope, config_store.clone());
+			let scope = crate::auth::google::register(scope, &config);
+			let scope = crate::auth::twitter::register(scope, &config);
+			let scope = crate::auth::passwordless::register(scope, &config);
 
-			scope.service(actix_files::Files::new("/", "./app/frontend/public/www"))
+			scope
+				.service(actix_files::Files::new("/", "./app/frontend/public/www"))
+				.wrap(banned::RejectBanned::exempting(STATIC_PATHS))
 		})
 	})
 	.bind(addr)?
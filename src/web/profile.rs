@@ -46,6 +46,12 @@ pub struct Settings {
 
 	icon_host: Option<String>,
 	image_host: Option<String>,
+
+	// Compression
+	compress: Option<bool>,
+	quality: Option<f32>,
+	lossless_only: Option<bool>,
+	keep_original: Option<bool>,
 }
 
 #[post("/user/settings")]
@@ -61,7 +67,7 @@ async fn update_settings(identity: Identity, data: web::Form<Settings>) -> Resul
 		get_users_collection()
 			.update_one(
 				doc! {
-					"data.unique_id": user.unique_id
+					"data.unique_id": &user.unique_id
 				},
 				doc! {
 					"$set": {
@@ -73,6 +79,46 @@ async fn update_settings(identity: Identity, data: web::Form<Settings>) -> Resul
 			.await?;
 	}
 
+	if data.compress.is_some() || data.quality.is_some() || data.lossless_only.is_some() || data.keep_original.is_some() {
+		if let Some(quality) = data.quality {
+			if !(1.0..=100.0).contains(&quality) {
+				return Ok(HttpResponse::BadRequest().body("Quality has to be between 1 and 100."));
+			}
+		}
+
+		let mut compression = user.upgrade().await?.compression.unwrap_or_default();
+
+		if let Some(value) = data.compress {
+			compression.enabled = value;
+		}
+
+		if let Some(value) = data.quality {
+			compression.quality = Some(value);
+		}
+
+		if let Some(value) = data.lossless_only {
+			compression.lossless_only = value;
+		}
+
+		if let Some(value) = data.keep_original {
+			compression.keep_original = value;
+		}
+
+		get_users_collection()
+			.update_one(
+				doc! {
+					"_id": user.id
+				},
+				doc! {
+					"$set": {
+						"compression": mongodb::bson::to_bson(&compression)?
+					}
+				},
+				None,
+			)
+			.await?;
+	}
+
 	Ok(HttpResponse::Ok().json("{}".to_string()))
 }
 
@@ -93,6 +139,8 @@ async fn get_settings(identity: Identity, _hb: HandlebarsDataService<'_>, config
 	};
 
 
+	let compression = user.compression.unwrap_or_default();
+
 	Ok(HttpResponse::Ok().json(Settings {
 		upload_type: Some(user.upload_type.to_num()),
 		unique_id: Some(user.unique_id),
@@ -100,6 +148,11 @@ async fn get_settings(identity: Identity, _hb: HandlebarsDataService<'_>, config
 
 		icon_host: Some(config.website.http_icon_host.clone()),
 		image_host: Some(config.website.http_image_host.clone()),
+
+		compress: Some(compression.enabled),
+		quality: Some(compression.quality.unwrap_or(config.features.compression.quality)),
+		lossless_only: Some(compression.lossless_only),
+		keep_original: Some(compression.keep_original),
 	}))
 }
 
@@ -0,0 +1,112 @@
+use std::time::{Duration, SystemTime};
+
+use actix_web::web;
+use handlebars::Handlebars;
+
+use crate::{Result, config::Config, flipstore::FlipStore};
+
+
+/// How often the config file is checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+pub type ConfigStore = web::Data<FlipStore<Config>>;
+pub type HandlebarsStore = web::Data<FlipStore<Handlebars<'static>>>;
+
+
+pub fn create_handlebars(config: &Config) -> Result<Handlebars<'static>> {
+	let mut handlebars = Handlebars::new();
+	handlebars.set_dev_mode(config.debug);
+	handlebars
+		.register_templates_directory(".hbs", "./app/frontend/views")
+		.map_err(Box::new)?;
+
+	Ok(handlebars)
+}
+
+/// Reloads the config and templates when the config file changes or on SIGHUP.
+///
+/// Requests which already started keep using the previous config.
+pub fn watch(config: ConfigStore, handlebars: HandlebarsStore) {
+	actix_web::rt::spawn(async move {
+		let path = config.read().path().to_path_buf();
+
+		let mut last_modified = modified_time(&path).await;
+		let mut interval = tokio::time::interval(WATCH_INTERVAL);
+		let mut hangup = Hangup::new();
+
+		loop {
+			tokio::select! {
+				_ = interval.tick() => {
+					let modified = modified_time(&path).await;
+
+					if modified == last_modified {
+						continue;
+					}
+
+					last_modified = modified;
+				}
+
+				_ = hangup.recv() => (),
+			}
+
+			match reload(&config, &handlebars).await {
+				Ok(()) => println!("Reloaded the config."),
+				Err(e) => eprintln!("Config Reload Error: {}\nStill using the previous config.", e),
+			}
+		}
+	});
+}
+
+async fn reload(config: &ConfigStore, handlebars: &HandlebarsStore) -> Result<()> {
+	let running = config.read();
+
+	let mut new_config = Config::create_with_defaults(running.path());
+	new_config.load().await?;
+	new_config.validate()?;
+
+	let changed = new_config.keep_startup_settings(&running);
+
+	if !changed.is_empty() {
+		println!("Restart to apply the changes to: {}", changed.join(", "));
+	}
+
+	let new_handlebars = create_handlebars(&new_config)?;
+
+	*config.write()? = new_config;
+	*handlebars.write()? = new_handlebars;
+
+	Ok(())
+}
+
+async fn modified_time(path: &std::path::Path) -> Option<SystemTime> {
+	tokio::fs::metadata(path).await.ok()?.modified().ok()
+}
+
+
+/// Resolves on SIGHUP. Never resolves on other platforms.
+struct Hangup {
+	#[cfg(unix)]
+	signal: Option<tokio::signal::unix::Signal>,
+}
+
+impl Hangup {
+	fn new() -> Self {
+		Self {
+			#[cfg(unix)]
+			signal: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).ok(),
+		}
+	}
+
+	async fn recv(&mut self) {
+		#[cfg(unix)]
+		if let Some(signal) = self.signal.as_mut() {
+			if signal.recv().await.is_some() {
+				return;
+			}
+
+			self.signal = None;
+		}
+
+		std::future::pending::<()>().await
+	}
+}
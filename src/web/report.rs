@@ -0,0 +1,188 @@
+use actix_identity::Identity;
+use actix_web::{post, web, Either, HttpRequest, HttpResponse, Scope};
+use mongodb::bson::DateTime;
+
+use crate::{
+	Result,
+	config::Config,
+	db::{
+		get_gallery_collection, get_images_collection, get_reports_collection,
+		model::{self, HiddenReason, Report, ReportTarget},
+	},
+};
+
+use super::{ConfigDataService, HiddenDataService, get_slim_user_identity};
+
+
+/// Longer reasons are cut off.
+const MAX_REASON_LENGTH: usize = 500;
+
+const HOUR_MILLIS: i64 = 60 * 60 * 1000;
+
+
+pub fn register(scope: Scope) -> Scope {
+	scope.service(report)
+}
+
+/// The reasons content is currently hidden for. Content hidden for other reasons is served again.
+pub fn hidden_reasons(config: &Config) -> Vec<HiddenReason> {
+	let mut reasons = Vec::new();
+
+	if config.features.moderation.hide_banned_images {
+		reasons.push(HiddenReason::Banned);
+	}
+
+	if config.features.moderation.report_threshold != 0 {
+		reasons.push(HiddenReason::Reported);
+	}
+
+	reasons
+}
+
+
+#[derive(Deserialize)]
+struct NewReport {
+	target: ReportTarget,
+	/// The image or gallery name. eg. "Name123" or "Name123.png"
+	name: String,
+	#[serde(default)]
+	reason: String,
+}
+
+/// Tells reporters apart. Logged in users by their id, guests by their IP.
+fn reporter(req: &HttpRequest, identity: Identity, config: &Config) -> String {
+	match get_slim_user_identity(identity) {
+		Some(user) => format!("user:{}", user.id),
+		None => format!("ip:{}", client_ip(req, config)),
+	}
+}
+
+/// The IP from the configured header if it's set. Otherwise the IP of the connection.
+fn client_ip(req: &HttpRequest, config: &Config) -> String {
+	let forwarded = config.features.moderation.forwarded_for_header.as_deref()
+		.and_then(|name| req.headers().get(name))
+		.and_then(|v| v.to_str().ok())
+		.and_then(|v| v.rsplit(',').next())
+		.map(str::trim)
+		.filter(|v| !v.is_empty());
+
+	match forwarded {
+		Some(v) => v.to_string(),
+		None => req.peer_addr().map_or_else(String::new, |v| v.ip().to_string()),
+	}
+}
+
+/// Anyone can report an image or gallery. Accepts JSON or a form.
+///
+/// Once enough different users or IPs reported it, it's hidden until a moderator reviews it.
+#[post("/report")]
+async fn report(
+	req: HttpRequest,
+	identity: Identity,
+	body: Either<web::Json<NewReport>, web::Form<NewReport>>,
+	config: ConfigDataService,
+	hidden: HiddenDataService,
+) -> Result<HttpResponse> {
+	let NewReport { target, name, reason } = match body {
+		Either::Left(v) => v.into_inner(),
+		Either::Right(v) => v.into_inner(),
+	};
+
+	let reporter = reporter(&req, identity, &config);
+
+	let limit = config.features.moderation.reports_per_hour;
+
+	if limit != 0 {
+		let since = DateTime::from_millis(DateTime::now().timestamp_millis() - HOUR_MILLIS);
+
+		if model::count_reports_since(&reporter, since, &get_reports_collection()).await? >= u64::from(limit) {
+			return Ok(HttpResponse::TooManyRequests().body("Too many reports. Try again later."));
+		}
+	}
+
+	let name = name.split('.').next().unwrap_or_default().to_string();
+
+	let exists = match target {
+		ReportTarget::Image => model::find_image_by_name(&name, &get_images_collection()).await?
+			.is_some_and(|v| v.deleted.is_none()),
+		ReportTarget::Gallery => model::does_gallery_exist(&name, &get_gallery_collection()).await?,
+	};
+
+	if !exists {
+		return Ok(HttpResponse::NotFound().body("Unable to find it."));
+	}
+
+	let report = Report {
+		id: None,
+		target,
+		name,
+		reason: reason.trim().chars().take(MAX_REASON_LENGTH).collect(),
+		reporter,
+		resolved_at: None,
+		created_at: DateTime::now(),
+	};
+
+	let reporters = model::insert_report(&report, &get_reports_collection()).await?;
+
+	let threshold = config.features.moderation.report_threshold as usize;
+
+	if threshold != 0 && reporters >= threshold {
+		model::hide_reported(report.target, &report.name).await?;
+
+		hidden.forget(&report.name);
+	}
+
+	Ok(HttpResponse::Ok().body("Reported. Thank you."))
+}
+
+
+#[cfg(test)]
+mod tests {
+	#![allow(clippy::unwrap_used)]
+
+	use actix_web::test::TestRequest;
+
+	use super::*;
+
+	#[test]
+	fn report_form() {
+		let value: NewReport = serde_urlencoded::from_str("target=gallery&name=Abc123&reason=spam").unwrap();
+		assert_eq!((ReportTarget::Gallery, "Abc123", "spam"), (value.target, value.name.as_str(), value.reason.as_str()));
+
+		let value: NewReport = serde_json::from_str(r#"{ "target": "image", "name": "Abc123.png" }"#).unwrap();
+		assert_eq!((ReportTarget::Image, ""), (value.target, value.reason.as_str()));
+
+		assert!(serde_urlencoded::from_str::<NewReport>("target=user&name=Abc123").is_err());
+	}
+
+	#[test]
+	fn reasons() {
+		let mut config = Config::default();
+		assert!(hidden_reasons(&config).is_empty());
+
+		config.features.moderation.report_threshold = 3;
+		assert_eq!(vec![HiddenReason::Reported], hidden_reasons(&config));
+	}
+
+	#[test]
+	fn client_ips() {
+		let mut config = Config::default();
+
+		let req = TestRequest::default()
+			.peer_addr("10.0.0.1:4000".parse().unwrap())
+			.insert_header(("X-Forwarded-For", "1.1.1.1, 2.2.2.2"))
+			.to_http_request();
+
+		// Not trusted unless it's configured.
+		assert_eq!("10.0.0.1", client_ip(&req, &config));
+
+		config.features.moderation.forwarded_for_header = Some(String::from("X-Forwarded-For"));
+		assert_eq!("2.2.2.2", client_ip(&req, &config));
+
+		let req = TestRequest::default()
+			.peer_addr("10.0.0.1:4000".parse().unwrap())
+			.to_http_request();
+
+		assert_eq!("10.0.0.1", client_ip(&req, &config));
+	}
+}
@@ -15,6 +15,12 @@ use crate::Result;
 
 pub static APP_PATH: &str = "./app";
 
+// Not included in the mime crate.
+pub static WEBP: &str = "webp";
+pub static AVIF: &str = "avif";
+/// Converted animated GIFs. Stored as "video/mp4".
+pub static MP4: &str = "mp4";
+
 fn correct_line(value: String) -> String {
 	// TODO: Replace ALL invalid charizards.
 	value.trim().replace(' ', "")
@@ -80,19 +86,46 @@ impl Filename {
 			Some(GIF) => Ok("gif"),
 			Some(PNG) => Ok("png"),
 			Some(JPEG) => Ok("jpeg"),
-			_ => Err(ErrorNotAcceptable("Invalid file format. Expected gif, png, or jpeg.").into())
+			Some(v) if v == WEBP => Ok("webp"),
+			Some(v) if v == AVIF => Ok("avif"),
+			Some(v) if v == MP4 => Ok("mp4"),
+			_ => Err(ErrorNotAcceptable("Invalid file format. Expected gif, png, jpeg or webp.").into())
 		}
 	}
 
+	/// Accepts either a mime type or a stored format name. eg. "image/png" or "png".
 	pub fn set_format(mut self, format: String) -> Result<Self> {
+		let format = if format.contains('/') {
+			format
+		} else if format == MP4 {
+			format!("video/{}", format)
+		} else {
+			format!("image/{}", format)
+		};
+
 		if let Some(format) = format.parse().ok().filter(Self::is_valid_format) {
 			self.format = Some(format);
 			Ok(self)
 		} else {
-			Err(ErrorNotAcceptable("Invalid file format. Expected gif, png, or jpeg.").into())
+			Err(ErrorNotAcceptable("Invalid file format. Expected gif, png, jpeg or webp.").into())
+		}
+	}
+
+	/// AVIF and MP4 can be stored (converted) but there isn't a decoder for uploads.
+	pub fn set_upload_format(self, content_type: String) -> Result<Self> {
+		let this = self.set_format(content_type)?;
+
+		if this.is_format_subtype(AVIF) || this.is_format_subtype(MP4) {
+			Err(ErrorNotAcceptable("Invalid file format. Expected gif, png, jpeg or webp.").into())
+		} else {
+			Ok(this)
 		}
 	}
 
+	pub fn is_format_subtype(&self, subtype: &str) -> bool {
+		self.format.as_ref().map(|v| v.subtype() == subtype).unwrap_or_default()
+	}
+
 	pub fn as_filename(&self) -> Result<String> {
 		Ok(format!("{}.{}", self.name, self.format_name()?))
 	}
@@ -106,7 +139,11 @@ impl Filename {
 	}
 
 	fn is_valid_format(value: &Mime) -> bool {
-		matches!(value.subtype(), GIF | PNG | JPEG)
+		if value.type_() == mime::VIDEO {
+			value.subtype() == MP4
+		} else {
+			matches!(value.subtype(), GIF | PNG | JPEG) || value.subtype() == WEBP || value.subtype() == AVIF
+		}
 	}
 }
 
@@ -206,3 +243,26 @@ pub fn gen_sample_alphanumeric(amount: usize, rng: &mut ThreadRng) -> String {
 pub fn gen_uuid() -> String {
 	uuid::Uuid::new_v4().to_hyphenated().to_string()
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn file_name_formats() {
+		let webp = Filename::new(String::from("Name123"), Some(String::from("image/webp"))).unwrap();
+		assert_eq!("Name123.webp", webp.as_filename().unwrap());
+
+		// Stored format names.
+		let jpeg = Filename::new(String::from("Name123"), Some(String::from("jpeg"))).unwrap();
+		assert!(jpeg.is_format(mime::IMAGE_JPEG));
+
+		assert!(Filename::new(String::from("Name123"), Some(String::from("avif"))).is_ok());
+		assert!(Filename::new(String::from("Name123"), None).unwrap().set_upload_format(String::from("image/avif")).is_err());
+		assert!(Filename::new(String::from("Name123"), None).unwrap().set_upload_format(String::from("image/bmp")).is_err());
+
+		let mp4 = Filename::new(String::from("Name123"), Some(String::from("mp4"))).unwrap();
+		assert_eq!(Some("video/mp4"), mp4.format.as_ref().map(|v| v.essence_str()));
+		assert!(Filename::new(String::from("Name123"), None).unwrap().set_upload_format(String::from("video/mp4")).is_err());
+	}
+}